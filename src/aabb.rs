@@ -0,0 +1,102 @@
+use crate::interval::Interval;
+use crate::ray::Ray;
+use crate::vec3::Point3;
+
+// Axis-aligned bounding box, one interval per axis
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aabb {
+    pub x: Interval,
+    pub y: Interval,
+    pub z: Interval,
+}
+
+impl Aabb {
+    pub fn new(x: Interval, y: Interval, z: Interval) -> Self {
+        Self { x, y, z }.pad_to_minimums()
+    }
+
+    // Box with `a` and `b` as opposite corners, in any order
+    pub fn from_points(a: Point3, b: Point3) -> Self {
+        Self::new(
+            Interval::new(a.x().min(b.x()), a.x().max(b.x())),
+            Interval::new(a.y().min(b.y()), a.y().max(b.y())),
+            Interval::new(a.z().min(b.z()), a.z().max(b.z())),
+        )
+    }
+
+    pub fn enclosing(a: &Aabb, b: &Aabb) -> Self {
+        Self {
+            x: Interval::enclosing(a.x, b.x),
+            y: Interval::enclosing(a.y, b.y),
+            z: Interval::enclosing(a.z, b.z),
+        }
+    }
+
+    pub fn axis_interval(&self, axis: usize) -> Interval {
+        match axis {
+            1 => self.y,
+            2 => self.z,
+            _ => self.x,
+        }
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        if self.x.size() > self.y.size() {
+            if self.x.size() > self.z.size() { 0 } else { 2 }
+        } else if self.y.size() > self.z.size() {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn centroid(&self, axis: usize) -> f64 {
+        let interval = self.axis_interval(axis);
+        (interval.min + interval.max) / 2.0
+    }
+
+    pub fn corners(&self) -> [Point3; 8] {
+        let mut corners = [Point3::new(0.0, 0.0, 0.0); 8];
+        for (index, corner) in corners.iter_mut().enumerate() {
+            let x = if index & 1 == 0 { self.x.min } else { self.x.max };
+            let y = if index & 2 == 0 { self.y.min } else { self.y.max };
+            let z = if index & 4 == 0 { self.z.min } else { self.z.max };
+            *corner = Point3::new(x, y, z);
+        }
+        corners
+    }
+
+    // Slab test against the ray segment in `ray_t`
+    pub fn hit(&self, r: &Ray, mut ray_t: Interval) -> bool {
+        let origin = r.origin();
+        let direction = r.direction();
+        for axis in 0..3 {
+            let interval = self.axis_interval(axis);
+            let inverse = 1.0 / direction.e[axis];
+            let t0 = (interval.min - origin.e[axis]) * inverse;
+            let t1 = (interval.max - origin.e[axis]) * inverse;
+            let (near, far) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+            ray_t.min = ray_t.min.max(near);
+            ray_t.max = ray_t.max.min(far);
+            if ray_t.max <= ray_t.min {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Flat boxes get a little thickness so the slab test doesn't miss them
+    fn pad_to_minimums(mut self) -> Self {
+        let delta = 0.0001;
+        if self.x.size() < delta {
+            self.x = self.x.expand(delta);
+        }
+        if self.y.size() < delta {
+            self.y = self.y.expand(delta);
+        }
+        if self.z.size() < delta {
+            self.z = self.z.expand(delta);
+        }
+        self
+    }
+}
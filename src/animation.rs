@@ -0,0 +1,350 @@
+use std::ops::{Add, Mul, RangeInclusive, Sub};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::aov::AovOutput;
+use crate::bvh::Bvh;
+use crate::camera::Camera;
+use crate::hittable::Hittable;
+use crate::hittable_list::HittableList;
+use crate::interrupt;
+use crate::stats::RenderStats;
+use crate::transform::Transform;
+use crate::vec3::{Point3, Vec3};
+
+// How a track moves between its keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    // Cubic Bezier through each pair of keys, shaped by their handles (see
+    // `Track::with_bezier_key`). Keys without handles get flat ones, so motion eases out of
+    // one key and into the next.
+    Bezier,
+    // Smooth curve through every key, with tangents from the neighbouring keys
+    CatmullRom,
+}
+
+// Anything that can be blended by weighted sums
+pub trait Animatable: Copy + Add<Output = Self> + Sub<Output = Self> + Mul<f64, Output = Self> {}
+
+impl<T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f64, Output = T>> Animatable for T {}
+
+#[derive(Debug, Clone, Copy)]
+struct Key<T> {
+    time: f64,
+    value: T,
+    // Bezier control points as offsets from `value`, towards the previous and next key
+    in_handle: T,
+    out_handle: T,
+}
+
+// Keyframed value, times in seconds. Before the first and after the last key the value
+// holds still.
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    keys: Vec<Key<T>>,
+    pub interpolation: Interpolation,
+}
+
+impl<T: Animatable> Track<T> {
+    pub fn new(interpolation: Interpolation) -> Self {
+        Self { keys: Vec::new(), interpolation }
+    }
+
+    // A track that never changes
+    pub fn constant(value: T) -> Self {
+        Self::new(Interpolation::Linear).with_key(0.0, value)
+    }
+
+    pub fn with_key(self, time: f64, value: T) -> Self {
+        self.with_bezier_key(time, value, value * 0.0, value * 0.0)
+    }
+
+    // A key with Bezier handles, offsets from `value` that the curve leaves towards along
+    // `out_handle` and arrives from along `in_handle`. Handles sit a third of the way into
+    // their span in time. Other interpolations ignore them.
+    pub fn with_bezier_key(mut self, time: f64, value: T, in_handle: T, out_handle: T) -> Self {
+        let index = self.keys.partition_point(|key| key.time <= time);
+        self.keys.insert(index, Key { time, value, in_handle, out_handle });
+        self
+    }
+
+    pub fn sample(&self, time: f64) -> Option<T> {
+        let (first, last) = (self.keys.first()?, self.keys.last()?);
+        if time <= first.time {
+            return Some(first.value);
+        }
+        if time >= last.time {
+            return Some(last.value);
+        }
+
+        let k = self.keys.partition_point(|key| key.time <= time) - 1;
+        let (start, end) = (self.keys[k], self.keys[k + 1]);
+        let (t0, p0, t1, p1) = (start.time, start.value, end.time, end.value);
+        let u = (time - t0) / (t1 - t0);
+        Some(match self.interpolation {
+            Interpolation::Linear => p0 + (p1 - p0) * u,
+            Interpolation::Bezier => {
+                let (c1, c2) = (p0 + start.out_handle, p1 + end.in_handle);
+                let v = 1.0 - u;
+                p0 * (v * v * v) + c1 * (3.0 * v * v * u) + c2 * (3.0 * v * u * u) + p1 * (u * u * u)
+            }
+            Interpolation::CatmullRom => {
+                let m0 = self.tangent(k) * (t1 - t0);
+                let m1 = self.tangent(k + 1) * (t1 - t0);
+                let (u2, u3) = (u * u, u * u * u);
+                p0 * (2.0 * u3 - 3.0 * u2 + 1.0)
+                    + m0 * (u3 - 2.0 * u2 + u)
+                    + p1 * (3.0 * u2 - 2.0 * u3)
+                    + m1 * (u3 - u2)
+            }
+        })
+    }
+
+    // Rate of change at key `k` per second, one-sided at the ends
+    fn tangent(&self, k: usize) -> T {
+        let before = self.keys[k.saturating_sub(1)];
+        let after = self.keys[(k + 1).min(self.keys.len() - 1)];
+        let dt = after.time - before.time;
+        if dt <= 0.0 {
+            return before.value * 0.0;
+        }
+        (after.value - before.value) * (1.0 / dt)
+    }
+}
+
+// An object moved around by keyframed translation, rotation (degrees about x, y, z) and
+// uniform scale
+pub struct AnimatedObject {
+    object: Arc<dyn Hittable>,
+    pub name: Option<String>,
+    pub translation: Track<Vec3>,
+    pub rotation: Track<Vec3>,
+    pub scale: Track<f64>,
+}
+
+impl AnimatedObject {
+    pub fn new(object: Arc<dyn Hittable>) -> Self {
+        Self {
+            object,
+            name: None,
+            translation: Track::constant(Vec3::new(0.0, 0.0, 0.0)),
+            rotation: Track::constant(Vec3::new(0.0, 0.0, 0.0)),
+            scale: Track::constant(1.0),
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_translation(mut self, translation: Track<Vec3>) -> Self {
+        self.translation = translation;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Track<Vec3>) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: Track<f64>) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn at(&self, time: f64) -> Transform {
+        Transform::new(
+            self.object.clone(),
+            self.translation.sample(time).unwrap_or(Vec3::new(0.0, 0.0, 0.0)),
+            self.rotation.sample(time).unwrap_or(Vec3::new(0.0, 0.0, 0.0)),
+            self.scale.sample(time).unwrap_or(1.0),
+        )
+    }
+}
+
+// Renders an image sequence. Camera tracks left as None keep the camera's own settings.
+// The static part of the scene goes into one BVH that every frame shares; only the
+// animated objects get placed anew each frame.
+pub struct Animation {
+    pub frames: RangeInclusive<u32>,
+    pub fps: f64,
+    // Runs of '#' get replaced by the zero-padded frame number
+    pub output_pattern: String,
+    pub lookfrom: Option<Track<Point3>>,
+    pub lookat: Option<Track<Point3>>,
+    pub vfov: Option<Track<f64>>,
+    pub focus_dist: Option<Track<f64>>,
+    pub objects: Vec<AnimatedObject>,
+}
+
+impl Animation {
+    pub fn new(frames: RangeInclusive<u32>, fps: f64) -> Self {
+        Self {
+            frames,
+            fps,
+            output_pattern: String::from("frame_####.ppm"),
+            lookfrom: None,
+            lookat: None,
+            vfov: None,
+            focus_dist: None,
+            objects: Vec::new(),
+        }
+    }
+
+    pub fn with_output_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.output_pattern = pattern.into();
+        self
+    }
+
+    pub fn with_lookfrom(mut self, track: Track<Point3>) -> Self {
+        self.lookfrom = Some(track);
+        self
+    }
+
+    pub fn with_lookat(mut self, track: Track<Point3>) -> Self {
+        self.lookat = Some(track);
+        self
+    }
+
+    pub fn with_vfov(mut self, track: Track<f64>) -> Self {
+        self.vfov = Some(track);
+        self
+    }
+
+    pub fn with_focus_dist(mut self, track: Track<f64>) -> Self {
+        self.focus_dist = Some(track);
+        self
+    }
+
+    pub fn with_object(mut self, object: AnimatedObject) -> Self {
+        self.objects.push(object);
+        self
+    }
+
+    pub fn frame_time(&self, frame: u32) -> f64 {
+        frame as f64 / self.fps
+    }
+
+    // The output pattern with its last run of '#' replaced by the frame number, padded to
+    // the run's length. Without any '#' the number goes before the extension.
+    pub fn frame_path(&self, frame: u32) -> PathBuf {
+        let pattern = &self.output_pattern;
+        if let Some(end) = pattern.rfind('#') {
+            let start = pattern[..end].rfind(|c| c != '#').map_or(0, |i| i + 1);
+            let width = end + 1 - start;
+            return PathBuf::from(format!("{}{:0width$}{}", &pattern[..start], frame, &pattern[end + 1..]));
+        }
+        numbered_path(Path::new(pattern), frame)
+    }
+
+    pub fn apply_camera(&self, camera: &mut Camera, time: f64) {
+        if let Some(lookfrom) = self.lookfrom.as_ref().and_then(|track| track.sample(time)) {
+            camera.lookfrom = lookfrom;
+        }
+        if let Some(lookat) = self.lookat.as_ref().and_then(|track| track.sample(time)) {
+            camera.lookat = lookat;
+        }
+        if let Some(vfov) = self.vfov.as_ref().and_then(|track| track.sample(time)) {
+            camera.vfov = vfov;
+        }
+        if let Some(focus_dist) = self.focus_dist.as_ref().and_then(|track| track.sample(time)) {
+            camera.focus_dist = focus_dist;
+        }
+    }
+
+    // The scene at `time`: the shared static BVH plus every animated object in place. The
+    // list is put together the same way every frame, so every object keeps its ID.
+    pub fn world_at(&self, static_world: &Arc<Bvh>, time: f64) -> HittableList {
+        let mut world = HittableList::new();
+        world.add(Box::new(static_world.clone()));
+        for object in &self.objects {
+            match &object.name {
+                Some(name) => world.add_named(Box::new(object.at(time)), name.clone()),
+                None => world.add(Box::new(object.at(time))),
+            }
+        }
+        world
+    }
+
+    // Renders every frame to its numbered file, stopping early on Ctrl-C. Every other file
+    // the camera writes, checkpoints included, gets the frame number added as well so no
+    // frame resumes from or overwrites another's.
+    pub fn render(&self, camera: &mut Camera, static_world: HittableList, lights: &HittableList) -> Vec<RenderStats> {
+        let static_world = Arc::new(Bvh::new(static_world));
+        let checkpoint_path = camera.checkpoint_path.clone();
+        let snapshot_path = camera.snapshot_path.clone();
+        let sample_heatmap_path = camera.sample_heatmap_path.clone();
+        let stats_json_path = camera.stats_json_path.clone();
+        let aovs = camera.aovs.clone();
+        let mut stats = Vec::new();
+        for frame in self.frames.clone() {
+            let time = self.frame_time(frame);
+            self.apply_camera(camera, time);
+            let world = self.world_at(&static_world, time);
+            let path = self.frame_path(frame);
+            eprintln!("\rFrame {} ({:.3}s) -> {}", frame, time, path.display());
+            camera.output_path = Some(path);
+            camera.checkpoint_path = checkpoint_path.as_deref().map(|path| numbered_path(path, frame));
+            camera.snapshot_path = numbered_path(&snapshot_path, frame);
+            camera.sample_heatmap_path = sample_heatmap_path.as_deref().map(|path| numbered_path(path, frame));
+            camera.stats_json_path = stats_json_path.as_deref().map(|path| numbered_path(path, frame));
+            camera.aovs = aovs.clone().map(|aovs| AovOutput { path: numbered_path(&aovs.path, frame), ..aovs });
+            stats.push(camera.render_with_lights(&world, lights));
+            if interrupt::requested() {
+                break;
+            }
+        }
+        camera.checkpoint_path = checkpoint_path;
+        camera.snapshot_path = snapshot_path;
+        camera.sample_heatmap_path = sample_heatmap_path;
+        camera.stats_json_path = stats_json_path;
+        camera.aovs = aovs;
+        stats
+    }
+}
+
+// `path` with the frame number added to its file stem, e.g. passes.exr -> passes_0012.exr
+pub fn numbered_path(path: &Path, frame: u32) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{:04}.{}", stem, frame, extension.to_string_lossy()),
+        None => format!("{}_{:04}", stem, frame),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_bezier_handles_ease_in_and_out() {
+        let track = Track::new(Interpolation::Bezier).with_key(1.0, 2.0).with_key(3.0, 6.0);
+        assert_eq!(track.sample(0.0), Some(2.0));
+        assert_eq!(track.sample(2.0), Some(4.0));
+        // Smoothstep a quarter of the way in
+        assert!((track.sample(1.5).unwrap() - (2.0 + 4.0 * 0.15625)).abs() < 1e-12);
+        assert_eq!(track.sample(4.0), Some(6.0));
+    }
+
+    #[test]
+    fn bezier_handles_shape_the_curve() {
+        let track = Track::new(Interpolation::Bezier)
+            .with_bezier_key(0.0, 0.0, 0.0, 1.0)
+            .with_bezier_key(1.0, 1.0, 0.0, 0.0);
+        // Control points 0, 1, 1, 1
+        assert!((track.sample(0.5).unwrap() - 7.0 / 8.0).abs() < 1e-12);
+        // Handles pulling back past the keys overshoot them
+        let track = Track::new(Interpolation::Bezier)
+            .with_bezier_key(0.0, 0.0, 0.0, -3.0)
+            .with_bezier_key(1.0, 1.0, 3.0, 0.0);
+        assert!(track.sample(0.2).unwrap() < 0.0);
+        assert!(track.sample(0.8).unwrap() > 1.0);
+        // Linear ignores handles
+        let track = Track::new(Interpolation::Linear).with_bezier_key(0.0, 0.0, 0.0, 5.0).with_key(1.0, 1.0);
+        assert_eq!(track.sample(0.25), Some(0.25));
+    }
+}
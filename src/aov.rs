@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::color::Color;
+use crate::cryptomatte::cryptomatte_layer;
+use crate::exr::{write_exr, ExrChannel};
+use crate::framebuffer::{write_ppm, FeatureBuffers, FrameBuffer};
+use crate::material::MaterialIds;
+use crate::tile::Tile;
+use crate::tonemap::{DisplayTransform, ToneMap};
+use crate::vec3::Vec3;
+
+// Arbitrary output variables: extra images recorded alongside the render. The geometry
+// passes come from what each camera ray hits first, the light passes split the image into
+// light that reached the camera after at most one bounce and everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    MaterialId,
+    ObjectId,
+    Direct,
+    Indirect,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::MaterialId,
+        Aov::ObjectId,
+        Aov::Direct,
+        Aov::Indirect,
+    ];
+
+    // File name suffix and EXR layer name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::MaterialId => "material_id",
+            Aov::ObjectId => "object_id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+        }
+    }
+
+    pub fn is_light_pass(&self) -> bool {
+        matches!(self, Aov::Direct | Aov::Indirect)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AovFormat {
+    // One 8-bit image per pass, made viewable: normals mapped to [0, 1], depth and position
+    // scaled to the image's range, IDs as random colors
+    #[default]
+    Ppm,
+    // A single multi-layer file holding the linear image and every pass at full precision
+    Exr,
+}
+
+// Which passes to write and where. For PPM `path` names the main image and each pass goes
+// next to it with its name appended to the file stem.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AovOutput {
+    pub path: PathBuf,
+    pub format: AovFormat,
+    pub passes: Vec<Aov>,
+    // Cryptomatte object and material mattes keeping this many IDs per pixel. Their IDs are
+    // hashes stored in float channels, so they only go into EXR output.
+    pub cryptomatte: Option<usize>,
+}
+
+impl AovOutput {
+    // Every pass
+    pub fn new(path: impl Into<PathBuf>, format: AovFormat) -> Self {
+        Self { path: path.into(), format, passes: Aov::ALL.to_vec(), cryptomatte: None }
+    }
+
+    pub fn with_passes(mut self, passes: &[Aov]) -> Self {
+        self.passes = passes.to_vec();
+        self
+    }
+
+    // 6 ranks is what most compositors expect
+    pub fn with_cryptomatte(mut self, ranks: usize) -> Self {
+        self.cryptomatte = Some(ranks);
+        self
+    }
+
+    pub fn needs_features(&self) -> bool {
+        self.passes.iter().any(|pass| !pass.is_light_pass())
+    }
+
+    pub fn needs_light_passes(&self) -> bool {
+        self.passes.iter().any(Aov::is_light_pass)
+    }
+
+    pub fn needs_coverage(&self) -> bool {
+        self.cryptomatte.is_some()
+    }
+
+    // Where a pass goes in PPM mode
+    pub fn pass_path(&self, pass: Aov) -> PathBuf {
+        let stem = self.path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        self.path.with_file_name(format!("{}_{}.ppm", stem, pass.name()))
+    }
+
+    // Writes the passes recorded in `framebuffer`. `image` is the linear final image,
+    // `object_names` and `materials` name the IDs for the mattes, unnamed ones go by their
+    // number.
+    // `frame_size` is the full image the framebuffer is a window of, EXR files keep the
+    // window's place in it.
+    pub fn write(&self, framebuffer: &FrameBuffer, image: &[Color], object_names: &HashMap<u32, String>, materials: &MaterialIds, display: &DisplayTransform, frame_size: (usize, usize)) -> io::Result<()> {
+        let missing = |pass: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("the {} pass wasn't recorded", pass));
+        let (width, height) = (framebuffer.width, framebuffer.height);
+        let features = framebuffer.feature_buffers();
+        let features = features.as_ref();
+        let light_passes = framebuffer.light_passes();
+        let light_passes = light_passes.as_ref();
+        match self.format {
+            AovFormat::Ppm => {
+                for &pass in &self.passes {
+                    let pixels = match pass {
+                        Aov::Direct | Aov::Indirect => {
+                            let (direct, indirect) = light_passes.ok_or_else(|| missing(pass.name()))?;
+                            let light = if pass == Aov::Direct { direct } else { indirect };
+                            light.iter().map(|&c| display.apply(c)).collect()
+                        }
+                        _ => viewable(pass, features.ok_or_else(|| missing(pass.name()))?, display),
+                    };
+                    let mut out = BufWriter::new(File::create(self.pass_path(pass))?);
+                    write_ppm(&mut out, width, height, pass.name(), pixels)?;
+                    out.flush()?;
+                }
+                if self.cryptomatte.is_some() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cryptomatte needs EXR output"));
+                }
+                Ok(())
+            }
+            AovFormat::Exr => {
+                let mut channels = color_channels("", image);
+                for &pass in &self.passes {
+                    match pass {
+                        Aov::Direct | Aov::Indirect => {
+                            let (direct, indirect) = light_passes.ok_or_else(|| missing(pass.name()))?;
+                            let light = if pass == Aov::Direct { direct } else { indirect };
+                            channels.extend(color_channels(&format!("{}.", pass.name()), light));
+                        }
+                        _ => channels.extend(exr_channels(pass, features.ok_or_else(|| missing(pass.name()))?)),
+                    }
+                }
+                let mut attributes = Vec::new();
+                if let Some(ranks) = self.cryptomatte {
+                    let coverage = framebuffer.pixel_coverage().ok_or_else(|| missing("cryptomatte"))?;
+                    let objects: Vec<_> = coverage.iter().map(|c| c.ranked(&c.objects)).collect();
+                    let object_name = |id| object_names.get(&id).cloned().unwrap_or_else(|| format!("object{}", id));
+                    let (object_channels, object_attributes) = cryptomatte_layer("CryptoObject", ranks, &objects, object_name);
+                    let ranked_materials: Vec<_> = coverage.iter().map(|c| c.ranked(&c.materials)).collect();
+                    let material_name = |id| materials.name_of(id).map(String::from).unwrap_or_else(|| format!("material{}", id));
+                    let (material_channels, material_attributes) = cryptomatte_layer("CryptoMaterial", ranks, &ranked_materials, material_name);
+                    channels.extend(object_channels.into_iter().chain(material_channels));
+                    attributes.extend(object_attributes.into_iter().chain(material_attributes));
+                }
+                let (x0, y0) = framebuffer.origin;
+                let data_window = Tile { x0, y0, x1: x0 + width, y1: y0 + height };
+                write_exr(&self.path, data_window, frame_size, &channels, &attributes)
+            }
+        }
+    }
+}
+
+// R, G and B channels of `image`, prefixed with a layer name
+fn color_channels(layer: &str, image: &[Color]) -> Vec<ExrChannel> {
+    ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(axis, name)| ExrChannel::float(format!("{}{}", layer, name), image.iter().map(|c| c.e[axis] as f32).collect()))
+        .collect()
+}
+
+fn vector_channels(layer: &str, values: &[Vec3]) -> Vec<ExrChannel> {
+    ["X", "Y", "Z"]
+        .iter()
+        .enumerate()
+        .map(|(axis, name)| ExrChannel::float(format!("{}.{}", layer, name), values.iter().map(|v| v.e[axis] as f32).collect()))
+        .collect()
+}
+
+fn exr_channels(pass: Aov, f: &FeatureBuffers) -> Vec<ExrChannel> {
+    match pass {
+        Aov::Albedo => color_channels("albedo.", &f.albedo),
+        Aov::Normal => vector_channels("normal", &f.normal),
+        Aov::Depth => vec![ExrChannel::float("depth.Z", f.depth.iter().map(|&d| d as f32).collect())],
+        Aov::Position => vector_channels("position", &f.position),
+        Aov::MaterialId => vec![ExrChannel::uint("material_id.id", f.material_id.clone())],
+        Aov::ObjectId => vec![ExrChannel::uint("object_id.id", f.object_id.clone())],
+        Aov::Direct | Aov::Indirect => Vec::new(),
+    }
+}
+
+// A geometry pass as display values for an 8-bit image
+fn viewable(pass: Aov, f: &FeatureBuffers, display: &DisplayTransform) -> Vec<Color> {
+    match pass {
+        // A reflectance, not radiance, so it skips exposure and tone mapping
+        Aov::Albedo => {
+            let plain = DisplayTransform { exposure: 0.0, white_balance: None, tone_map: ToneMap::Clamp, ..*display };
+            f.albedo.iter().map(|&c| plain.apply(c)).collect()
+        }
+        Aov::Normal => f.normal.iter().map(|&n| (n + Color::new(1.0, 1.0, 1.0)) * 0.5).collect(),
+        // Black up close to white at the farthest hit, escaped rays stay black
+        Aov::Depth => {
+            let max = f.depth.iter().copied().fold(0.0, f64::max).max(1e-9);
+            f.depth.iter().map(|&d| Color::new(d, d, d) / max).collect()
+        }
+        // Each axis scaled to the range of the hit points
+        Aov::Position => {
+            let hits = || f.position.iter().zip(&f.depth).filter(|&(_, &d)| d > 0.0).map(|(&p, _)| p);
+            let Some(first) = hits().next() else {
+                return vec![Color::new(0.0, 0.0, 0.0); f.position.len()];
+            };
+            let (min, max) = hits().fold((first, first), |(min, max), p| {
+                (Vec3::new(min.x().min(p.x()), min.y().min(p.y()), min.z().min(p.z())), Vec3::new(max.x().max(p.x()), max.y().max(p.y()), max.z().max(p.z())))
+            });
+            let extent = max - min;
+            let scale = |v: f64, lo: f64, size: f64| if size > 0.0 { (v - lo) / size } else { 0.5 };
+            f.position
+                .iter()
+                .zip(&f.depth)
+                .map(|(&p, &d)| {
+                    if d <= 0.0 {
+                        return Color::new(0.0, 0.0, 0.0);
+                    }
+                    Color::new(scale(p.x(), min.x(), extent.x()), scale(p.y(), min.y(), extent.y()), scale(p.z(), min.z(), extent.z()))
+                })
+                .collect()
+        }
+        Aov::MaterialId => f.material_id.iter().map(|&id| id_color(id)).collect(),
+        Aov::ObjectId => f.object_id.iter().map(|&id| id_color(id)).collect(),
+        Aov::Direct | Aov::Indirect => Vec::new(),
+    }
+}
+
+// A stable random color per ID, black for 0
+pub fn id_color(id: u32) -> Color {
+    if id == 0 {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+    // Integer finalizer from MurmurHash3
+    let mut h = id;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    let channel = |shift: u32| 0.2 + 0.8 * ((h >> shift) & 0xff) as f64 / 255.0;
+    Color::new(channel(0), channel(8), channel(16))
+}
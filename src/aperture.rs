@@ -0,0 +1,234 @@
+use std::f64::consts::PI;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::color::luminance;
+use crate::framebuffer::read_ppm;
+use crate::vec3::{sample_unit_disk, Vec3};
+
+// Shape of the lens opening, and so of out of focus highlights. Shapes live in unit
+// coordinates and get scaled to the lens radius by the camera.
+#[derive(Debug, Clone, Default)]
+pub enum Aperture {
+    #[default]
+    Circular,
+    // Regular polygon of `blades` sides inscribed in the unit circle, turned by `rotation` degrees
+    Polygon { blades: u32, rotation: f64 },
+    // Image covering [-1, 1] x [-1, 1], brighter pixels let more light through
+    Mask(Arc<ApertureMask>),
+}
+
+impl Aperture {
+    // Point on the aperture, uniform over its area (or proportional to the mask)
+    pub fn sample(&self, u: (f64, f64)) -> Vec3 {
+        match self {
+            Aperture::Circular => sample_unit_disk(u),
+            Aperture::Polygon { blades, rotation } => {
+                // Pick one of the equal triangles fanning out from the center, then a point in it
+                let blades = (*blades).max(3);
+                let scaled = u.0 * blades as f64;
+                let k = (scaled as u32).min(blades - 1);
+                let a = polygon_vertex(k, blades, *rotation);
+                let b = polygon_vertex(k + 1, blades, *rotation);
+                let su = (scaled - k as f64).sqrt();
+                (a * (1.0 - u.1) + b * u.1) * su
+            }
+            Aperture::Mask(mask) => mask.sample(u),
+        }
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        match self {
+            Aperture::Circular => x * x + y * y <= 1.0,
+            Aperture::Polygon { blades, rotation } => {
+                let blades = (*blades).max(3);
+                (0..blades).all(|k| {
+                    let a = polygon_vertex(k, blades, *rotation);
+                    let b = polygon_vertex(k + 1, blades, *rotation);
+                    (b.x() - a.x()) * (y - a.y()) - (b.y() - a.y()) * (x - a.x()) >= 0.0
+                })
+            }
+            Aperture::Mask(mask) => mask.weight_at(x, y) > 0.0,
+        }
+    }
+
+    // Area of the opening in unit coordinates
+    pub fn area(&self) -> f64 {
+        match self {
+            Aperture::Circular => PI,
+            Aperture::Polygon { blades, .. } => {
+                let n = (*blades).max(3) as f64;
+                0.5 * n * (2.0 * PI / n).sin()
+            }
+            Aperture::Mask(mask) => mask.open_area(),
+        }
+    }
+
+    // Light through a mask isn't uniform over its area
+    pub fn is_uniform(&self) -> bool {
+        !matches!(self, Aperture::Mask(_))
+    }
+}
+
+// Corners go counter-clockwise from the top
+fn polygon_vertex(k: u32, blades: u32, rotation: f64) -> Vec3 {
+    let angle = PI / 2.0 + rotation.to_radians() + 2.0 * PI * k as f64 / blades as f64;
+    Vec3::new(angle.cos(), angle.sin(), 0.0)
+}
+
+// Grayscale aperture image with a 2D CDF for sampling it: rows are picked by their total
+// weight, then a column within the row
+#[derive(Debug, Clone)]
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    weights: Vec<f64>,
+    row_cdf: Vec<f64>,
+    column_cdfs: Vec<f64>,
+}
+
+impl ApertureMask {
+    // `weights` are row-major from the top left
+    pub fn new(width: usize, height: usize, weights: Vec<f64>) -> Self {
+        assert!(width > 0 && height > 0, "aperture mask is empty");
+        assert_eq!(weights.len(), width * height, "aperture mask size doesn't match its weights");
+        let mut column_cdfs = Vec::with_capacity(width * height);
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut total = 0.0;
+        for row in weights.chunks(width) {
+            let mut sum = 0.0;
+            for &w in row {
+                sum += w.max(0.0);
+                column_cdfs.push(sum);
+            }
+            total += sum;
+            row_cdf.push(total);
+        }
+        Self { width, height, weights, row_cdf, column_cdfs }
+    }
+
+    // Reads a P3 or P6 PPM, weighting pixels by their luminance
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let (width, height, pixels) = read_ppm(path)?;
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: empty image", path.display())));
+        }
+        Ok(Self::new(width, height, pixels.into_iter().map(luminance).collect()))
+    }
+
+    fn sample(&self, u: (f64, f64)) -> Vec3 {
+        let total = self.row_cdf.last().copied().unwrap_or(0.0);
+        if total <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let (row, row_u) = pick(&self.row_cdf, u.1 * total);
+        let columns = &self.column_cdfs[row * self.width..(row + 1) * self.width];
+        let row_total = columns[self.width - 1];
+        let (column, column_u) = pick(columns, u.0 * row_total);
+
+        let x = (column as f64 + column_u) / self.width as f64;
+        let y = (row as f64 + row_u) / self.height as f64;
+        Vec3::new(2.0 * x - 1.0, 1.0 - 2.0 * y, 0.0)
+    }
+
+    fn weight_at(&self, x: f64, y: f64) -> f64 {
+        if !(-1.0..1.0).contains(&x) || !(-1.0..1.0).contains(&y) {
+            return 0.0;
+        }
+        let column = (((x + 1.0) / 2.0 * self.width as f64) as usize).min(self.width - 1);
+        let row = (((1.0 - y) / 2.0 * self.height as f64) as usize).min(self.height - 1);
+        self.weights[row * self.width + column]
+    }
+
+    // Area of the pixels that let any light through, in unit coordinates
+    fn open_area(&self) -> f64 {
+        let open = self.weights.iter().filter(|&&w| w > 0.0).count();
+        4.0 * open as f64 / self.weights.len().max(1) as f64
+    }
+}
+
+// Index of the CDF bucket `target` falls in and how far into it, in [0, 1)
+fn pick(cdf: &[f64], target: f64) -> (usize, f64) {
+    let index = cdf.partition_point(|&c| c <= target).min(cdf.len() - 1);
+    let low = if index > 0 { cdf[index - 1] } else { 0.0 };
+    let width = cdf[index] - low;
+    let offset = if width > 0.0 { ((target - low) / width).clamp(0.0, 1.0 - f64::EPSILON) } else { 0.5 };
+    (index, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points of a regular grid over the unit square of sample values
+    fn grid(n: usize) -> impl Iterator<Item = (f64, f64)> {
+        (0..n * n).map(move |k| (((k % n) as f64 + 0.5) / n as f64, ((k / n) as f64 + 0.5) / n as f64))
+    }
+
+    // Share of samples in each cell of a 4 x 4 grid over [-1, 1]^2 next to the share of the
+    // open area in it, which is what uniform sampling gives
+    fn check_uniform(aperture: &Aperture) {
+        let cell = |x: f64, y: f64| ((x + 1.0) * 2.0).clamp(0.0, 3.0) as usize + 4 * ((y + 1.0) * 2.0).clamp(0.0, 3.0) as usize;
+        let mut sampled = [0.0; 16];
+        for u in grid(300) {
+            let p = aperture.sample(u);
+            assert!(aperture.contains(p.x() * 0.999, p.y() * 0.999), "{:?} sampled {:?}", aperture, p);
+            sampled[cell(p.x(), p.y())] += 1.0 / (300.0 * 300.0);
+        }
+        let mut open = [0.0; 16];
+        for (x, y) in grid(400) {
+            let (x, y) = (2.0 * x - 1.0, 2.0 * y - 1.0);
+            if aperture.contains(x, y) {
+                open[cell(x, y)] += 4.0 / (400.0 * 400.0);
+            }
+        }
+        for k in 0..16 {
+            assert!((sampled[k] - open[k] / aperture.area()).abs() < 0.005, "{:?} cell {}: {} vs {}", aperture, k, sampled[k], open[k] / aperture.area());
+        }
+    }
+
+    #[test]
+    fn shapes_are_sampled_uniformly_over_their_area() {
+        check_uniform(&Aperture::Circular);
+        check_uniform(&Aperture::Polygon { blades: 5, rotation: 0.0 });
+        check_uniform(&Aperture::Polygon { blades: 6, rotation: 17.0 });
+        // Four blades turned by 45 degrees make an axis aligned square with area 2
+        let square = Aperture::Polygon { blades: 4, rotation: 45.0 };
+        assert!((square.area() - 2.0).abs() < 1e-12);
+        assert!(square.contains(0.7, -0.7) && !square.contains(0.72, 0.0) && !square.contains(0.0, 0.72));
+    }
+
+    #[test]
+    fn masks_are_sampled_by_weight() {
+        // Left column closed, then 1 and 3 on the top row and a fully closed bottom row
+        let mask = Aperture::Mask(Arc::new(ApertureMask::new(3, 2, vec![0.0, 1.0, 3.0, 0.0, 0.0, 0.0])));
+        assert!((mask.area() - 4.0 * 2.0 / 6.0).abs() < 1e-12);
+        assert!(!mask.is_uniform());
+        assert!(!mask.contains(-0.9, 0.5) && mask.contains(0.0, 0.5) && !mask.contains(0.0, -0.5));
+        let mut right = 0;
+        for u in grid(100) {
+            let p = mask.sample(u);
+            assert!(mask.contains(p.x(), p.y()), "sampled {:?}", p);
+            if p.x() > 1.0 / 3.0 {
+                right += 1;
+            }
+        }
+        assert_eq!(right, 7500);
+    }
+
+    #[test]
+    #[should_panic(expected = "empty")]
+    fn masks_without_pixels_are_rejected() {
+        ApertureMask::new(0, 3, Vec::new());
+    }
+
+    #[test]
+    fn loading_an_empty_mask_is_an_error() {
+        let path = std::env::temp_dir().join(format!("raytracing-empty-mask-{}.ppm", std::process::id()));
+        std::fs::write(&path, "P3\n0 2\n255\n").unwrap();
+        let loaded = ApertureMask::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
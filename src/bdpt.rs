@@ -0,0 +1,502 @@
+use std::f64::consts::PI;
+
+use crate::color::Color;
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::{sample_cosine_direction, Integrator, Scene};
+use crate::interval::Interval;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
+use crate::vec3::{dot, unit_vector, Point3, Vec3};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+// One vertex of a camera or light subpath. Densities are per unit area: `pdf_fwd` is the
+// density with which the subpath's own walk reached the vertex, `pdf_rev` the density a
+// walk coming from the other end would have reached it with.
+struct Vertex {
+    kind: VertexKind,
+    p: Point3,
+    // Points towards the side the vertex was reached from, zero on the camera
+    normal: Vec3,
+    // Direction back to the previous vertex of the subpath
+    wo: Vec3,
+    rec: Option<HitRecord>,
+    beta: Color,
+    // Reached by specular scattering, so it can't be connected to
+    delta: bool,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl Vertex {
+    fn camera(p: Point3, beta: Color) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            p,
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            wo: Vec3::new(0.0, 0.0, 0.0),
+            rec: None,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(rec: HitRecord, beta: Color, pdf_fwd: f64) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            p: rec.p,
+            normal: rec.normal,
+            wo: Vec3::new(0.0, 0.0, 0.0),
+            rec: Some(rec),
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn surface(rec: HitRecord, wo: Vec3, beta: Color, pdf_fwd: f64) -> Self {
+        Self {
+            kind: VertexKind::Surface,
+            p: rec.p,
+            normal: rec.normal,
+            wo,
+            rec: Some(rec),
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn rec(&self) -> &HitRecord {
+        self.rec.as_ref().unwrap()
+    }
+
+    fn is_on_surface(&self) -> bool {
+        self.kind != VertexKind::Camera
+    }
+
+    // Vertices on emitters that the light walk could have started from
+    fn is_light(&self) -> bool {
+        self.kind == VertexKind::Light || (self.kind == VertexKind::Surface && !is_black(self.rec().mat.emitted(self.rec())))
+    }
+
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Surface => !self.rec().mat.is_specular(),
+            _ => true,
+        }
+    }
+
+    // BSDF value for light leaving towards `next`
+    fn f(&self, next: &Vertex) -> Color {
+        let wi = unit_vector(next.p - self.p);
+        self.rec().mat.eval(self.rec(), self.wo, wi)
+    }
+
+    // Radiance this emitter vertex sends towards `towards`
+    fn le(&self, towards: &Vertex) -> Color {
+        let rec = self.rec();
+        if self.kind == VertexKind::Surface {
+            // Hit from `towards`, so the record already knows which face it sees
+            return rec.mat.emitted(rec)
+        }
+        if dot(rec.normal, towards.p - self.p) <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0)
+        }
+        // Sampled light points are always front facing
+        rec.mat.emitted(rec)
+    }
+
+    // Turns a solid angle density at this vertex into an area density at `next`
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let dist_squared = w.length_squared();
+        if dist_squared == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf / dist_squared;
+        if next.is_on_surface() {
+            pdf *= dot(next.normal, w / dist_squared.sqrt()).abs();
+        }
+        pdf
+    }
+
+    // Area density of this vertex sampling `next`, having been reached from `prev`
+    fn pdf(&self, scene: &Scene, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        if self.kind == VertexKind::Light {
+            return self.pdf_light(next);
+        }
+        let wn = unit_vector(next.p - self.p);
+        let pdf = match self.kind {
+            VertexKind::Camera => scene.camera.pdf_direction(&Ray::new(self.p, wn)),
+            _ => {
+                let Some(prev) = prev else {
+                    return 0.0;
+                };
+                let wp = unit_vector(prev.p - self.p);
+                self.rec().mat.pdf(self.rec(), wp, wn)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+
+    // Area density of an emitter at this vertex sending its light towards `next`
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let dist_squared = w.length_squared();
+        let w = w / dist_squared.sqrt();
+        let pdf_dir = dot(self.normal, w).max(0.0) / PI;
+        let mut pdf = pdf_dir / dist_squared;
+        if next.is_on_surface() {
+            pdf *= dot(next.normal, w).abs();
+        }
+        pdf
+    }
+
+    // Area density of a light walk starting at this point
+    fn pdf_light_origin(&self, scene: &Scene) -> f64 {
+        scene.lights.surface_pdf(self.p)
+    }
+}
+
+fn geometry(scene: &Scene, a: &Vertex, b: &Vertex) -> f64 {
+    let w = b.p - a.p;
+    let dist_squared = w.length_squared();
+    let w = w / dist_squared.sqrt();
+    let mut g = 1.0 / dist_squared;
+    if a.is_on_surface() {
+        g *= dot(a.normal, w).abs();
+    }
+    if b.is_on_surface() {
+        g *= dot(b.normal, w).abs();
+    }
+    if g == 0.0 || !visible(scene, a.p, b.p) {
+        return 0.0;
+    }
+    g
+}
+
+fn visible(scene: &Scene, from: Point3, to: Point3) -> bool {
+    stats::increment(Counter::ShadowRays);
+    let distance = (to - from).length();
+    let r = Ray::new(from, (to - from) / distance);
+    scene.world.hit(&r, Interval::new(0.001, distance - 0.001)).is_none()
+}
+
+fn is_black(c: Color) -> bool {
+    c.x() == 0.0 && c.y() == 0.0 && c.z() == 0.0
+}
+
+// Random walk shared by both subpaths. `pdf` is the solid angle density of `r`.
+// Returns the sky radiance the walk picked up if it left the scene.
+fn random_walk(scene: &Scene, r: Ray, beta: Color, pdf: f64, max_vertices: usize, path: &mut Vec<Vertex>, sampler: &mut dyn Sampler) -> Color {
+    let mut r = r;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf;
+
+    while path.len() < max_vertices {
+        let Some(rec) = scene.hit(&r) else {
+            return beta * scene.background(&r)
+        };
+        let wo = -unit_vector(r.direction());
+        let prev_index = path.len() - 1;
+        let mut vertex = Vertex::surface(rec, wo, beta, 0.0);
+        vertex.pdf_fwd = path[prev_index].convert_density(pdf_fwd, &vertex);
+        path.push(vertex);
+        if path.len() >= max_vertices {
+            break;
+        }
+
+        let vertex = path.last().unwrap();
+        let rec = vertex.rec();
+        let Some(sc) = rec.mat.scatter(&r, rec, sampler) else {
+            break;
+        };
+        let wi = unit_vector(sc.ray.direction());
+        let specular = rec.mat.is_specular();
+        let pdf_rev;
+        if specular {
+            pdf_fwd = 0.0;
+            pdf_rev = 0.0;
+        } else {
+            pdf_fwd = rec.mat.pdf(rec, wo, wi);
+            pdf_rev = rec.mat.pdf(rec, wi, wo);
+            if pdf_fwd <= 0.0 {
+                break;
+            }
+        }
+        beta = beta * sc.attenuation;
+        stats::increment(Counter::SecondaryRays);
+
+        let n = path.len();
+        path[n - 1].delta = specular;
+        let rev = path[n - 1].convert_density(pdf_rev, &path[n - 2]);
+        path[n - 2].pdf_rev = rev;
+        r = sc.ray;
+    }
+    Color::new(0.0, 0.0, 0.0)
+}
+
+// Bidirectional path tracing: traces a subpath from the camera and one from a light, then
+// joins every prefix of one with every prefix of the other. Each way of building the same
+// path is weighted with the power heuristic, so the strategy that finds a path most easily
+// dominates, e.g. light tracing for caustics seen on diffuse surfaces.
+// Light walks start on the emitters in `Scene::lights`; the sky is only reached from the camera.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BdptIntegrator;
+
+impl BdptIntegrator {
+    fn camera_subpath(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler, path: &mut Vec<Vertex>) -> Color {
+        let mut camera = Vertex::camera(r.origin(), Color::new(1.0, 1.0, 1.0));
+        // Without lens connections light tracing can't produce these paths, so MIS must not count on it
+        camera.delta = !scene.camera.supports_lens_connections();
+        path.push(camera);
+        let pdf_dir = scene.camera.pdf_direction(&r);
+        let max_vertices = scene.max_depth.max(0) as usize + 1;
+        random_walk(scene, r, Color::new(1.0, 1.0, 1.0), pdf_dir, max_vertices, path, sampler)
+    }
+
+    fn light_subpath(&self, scene: &Scene, sampler: &mut dyn Sampler, path: &mut Vec<Vertex>) {
+        let Some((rec, pdf_pos)) = scene.sample_light(sampler) else {
+            return;
+        };
+        let direction = Onb::new(rec.normal).transform(sample_cosine_direction(sampler.get_2d()));
+        let cos_theta = dot(rec.normal, direction);
+        let pdf_dir = cos_theta / PI;
+        let le = rec.mat.emitted(&rec);
+        if pdf_pos <= 0.0 || pdf_dir <= 0.0 || is_black(le) {
+            return;
+        }
+
+        let r = Ray::new(rec.p, direction);
+        let beta = le * (cos_theta / (pdf_pos * pdf_dir));
+        path.push(Vertex::light(rec, le, pdf_pos));
+        let max_vertices = scene.max_depth.max(0) as usize;
+        random_walk(scene, r, beta, pdf_dir, max_vertices, path, sampler);
+    }
+
+    // Contribution of joining the first `s` light vertices with the first `t` camera vertices.
+    // Light tracing (t == 1) splats straight into the film instead of returning.
+    fn connect(&self, scene: &Scene, light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize, sampler: &mut dyn Sampler) -> Color {
+        let black = Color::new(0.0, 0.0, 0.0);
+        let pt = &camera_path[t - 1];
+        if t > 1 && s != 0 && pt.kind == VertexKind::Light {
+            return black;
+        }
+
+        let mut sampled: Option<Vertex> = None;
+        let mut raster: Option<(f64, f64)> = None;
+        let l = if s == 0 {
+            // The camera walk hit an emitter on its own
+            if pt.kind != VertexKind::Surface || !pt.is_light() {
+                return black;
+            }
+            pt.beta * pt.le(&camera_path[t - 2])
+        } else if t == 1 {
+            let qs = &light_path[s - 1];
+            if !qs.is_connectible() {
+                return black;
+            }
+            let Some(connection) = scene.camera.sample_connection(qs.p, sampler) else {
+                return black;
+            };
+            if connection.pdf <= 0.0 || connection.importance <= 0.0 {
+                return black;
+            }
+            let camera = Vertex::camera(connection.lens_point, Color::new(1.0, 1.0, 1.0) * (connection.importance / connection.pdf));
+            let l = qs.beta * qs.f(&camera) * camera.beta * dot(qs.normal, unit_vector(camera.p - qs.p)).abs();
+            if is_black(l) || !visible(scene, qs.p, camera.p) {
+                return black;
+            }
+            raster = Some(connection.raster);
+            sampled = Some(camera);
+            l
+        } else if s == 1 {
+            if !pt.is_connectible() {
+                return black;
+            }
+            // This only ever picks emitters in `scene.lights`, as do light subpaths. That is
+            // what lets `connect` give paths ending on any other emitter the full weight.
+            let Some((rec, pdf_area)) = scene.sample_light(sampler) else {
+                return black;
+            };
+            let mut light = Vertex::light(rec, Color::new(0.0, 0.0, 0.0), pdf_area);
+            let to_light = light.p - pt.p;
+            let dist_squared = to_light.length_squared();
+            let cos_light = dot(light.normal, -to_light / dist_squared.sqrt());
+            if pdf_area <= 0.0 || cos_light <= 0.0 {
+                return black;
+            }
+            let pdf = pdf_area * dist_squared / cos_light;
+            light.beta = light.le(pt) / pdf;
+            let cos_pt = dot(pt.normal, to_light / dist_squared.sqrt()).abs();
+            let l = pt.beta * pt.f(&light) * light.beta * cos_pt;
+            if is_black(l) || !visible(scene, pt.p, light.p) {
+                return black;
+            }
+            sampled = Some(light);
+            l
+        } else {
+            let qs = &light_path[s - 1];
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return black;
+            }
+            let l = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta;
+            if is_black(l) {
+                return black;
+            }
+            l * geometry(scene, qs, pt)
+        };
+
+        if is_black(l) {
+            return black;
+        }
+        // Emitters missing from the light list can only be found by the camera walk, so no
+        // other strategy shares in the path
+        if s == 0 && pt.pdf_light_origin(scene) == 0.0 {
+            return l;
+        }
+        let weight = self.mis_weight(scene, light_path, camera_path, sampled.as_ref(), s, t);
+        if let Some((x, y)) = raster {
+            scene.film.add_splat(x, y, l * weight);
+            return black;
+        }
+        l * weight
+    }
+
+    // Power heuristic over every strategy that could have produced this path. Walks outwards
+    // from the connection, turning each vertex's forward density into its reverse one.
+    fn mis_weight(&self, scene: &Scene, light_path: &[Vertex], camera_path: &[Vertex], sampled: Option<&Vertex>, s: usize, t: usize) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let remap0 = |f: f64| if f != 0.0 { f } else { 1.0 };
+
+        // (pdf_fwd, pdf_rev, delta) of each vertex, with the connection applied
+        let mut camera: Vec<(f64, f64, bool)> = camera_path[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+        let mut light: Vec<(f64, f64, bool)> = light_path[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+
+        let pt = if t == 1 { sampled.unwrap() } else { &camera_path[t - 1] };
+        let qs = match s {
+            0 => None,
+            1 => Some(sampled.unwrap()),
+            _ => Some(&light_path[s - 1]),
+        };
+        if t == 1 {
+            camera[0] = (pt.pdf_fwd, pt.pdf_rev, pt.delta);
+        }
+        if s == 1 {
+            let qs = qs.unwrap();
+            light[0] = (qs.pdf_fwd, qs.pdf_rev, qs.delta);
+        }
+        let pt_minus = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+        let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+
+        camera[t - 1].2 = false;
+        if s > 0 {
+            light[s - 1].2 = false;
+        }
+        camera[t - 1].1 = match qs {
+            Some(qs) => qs.pdf(scene, qs_minus, pt),
+            None => pt.pdf_light_origin(scene),
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera[t - 2].1 = match qs {
+                Some(qs) => pt.pdf(scene, Some(qs), pt_minus),
+                None => pt.pdf_light(pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            light[s - 1].1 = pt.pdf(scene, pt_minus, qs);
+            if let Some(qs_minus) = qs_minus {
+                light[s - 2].1 = qs.pdf(scene, Some(pt), qs_minus);
+            }
+        }
+
+        let mut sum_ri = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(camera[i].1) / remap0(camera[i].0);
+            if !camera[i].2 && !camera[i - 1].2 {
+                sum_ri += ri;
+            }
+        }
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(light[i].1) / remap0(light[i].0);
+            let delta_before = i > 0 && light[i - 1].2;
+            if !light[i].2 && !delta_before {
+                sum_ri += ri;
+            }
+        }
+        1.0 / (1.0 + sum_ri)
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+        let mut camera_path = Vec::new();
+        let mut light_path = Vec::new();
+        let mut radiance = self.camera_subpath(r, scene, sampler, &mut camera_path);
+        self.light_subpath(scene, sampler, &mut light_path);
+        stats::record_path(camera_path.len() - 1);
+
+        // Paths bounce at most `max_depth - 1` times, like the path integrator's
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = s as i32 + t as i32 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth >= scene.max_depth {
+                    continue;
+                }
+                radiance += self.connect(scene, &light_path, &camera_path, s, t, sampler);
+            }
+        }
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::integrator::tests::{assert_close, diffuse_scene, mean, render, test_camera};
+    use crate::integrator::{DirectLightingIntegrator, PathIntegrator};
+    use crate::material::DiffuseLight;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn bdpt_matches_path_tracing() {
+        let (world, lights) = diffuse_scene();
+        let bdpt = render(&mut test_camera(32).with_integrator(BdptIntegrator), &world, &lights);
+        let path = render(&mut test_camera(256).with_integrator(PathIntegrator), &world, &lights);
+        assert_close(mean(&bdpt), mean(&path), 0.03);
+    }
+
+    // One emitter is in the light list, the other only in the world and so only found by
+    // following the BSDF
+    #[test]
+    fn emitters_outside_the_light_list_keep_their_light() {
+        let (mut world, lights) = diffuse_scene();
+        world.add(Box::new(Sphere::new(Point3::new(-0.9, 1.0, 0.6), 0.4, Arc::new(DiffuseLight::new(Color::new(3.0, 2.0, 1.0))))));
+
+        let path = render(&mut test_camera(256).with_max_depth(2).with_integrator(PathIntegrator), &world, &lights);
+        let direct = render(&mut test_camera(128).with_integrator(DirectLightingIntegrator), &world, &lights);
+        assert_close(mean(&direct), mean(&path), 0.03);
+
+        let path = render(&mut test_camera(256).with_integrator(PathIntegrator), &world, &lights);
+        let bdpt = render(&mut test_camera(32).with_integrator(BdptIntegrator), &world, &lights);
+        assert_close(mean(&bdpt), mean(&path), 0.03);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::iter;
+
+use crate::aabb::Aabb;
+use crate::hittable::{HitRecord, Hittable};
+use crate::hittable_list::{nested_object_id, HittableList};
+use crate::interval::Interval;
+use crate::material::MaterialIds;
+use crate::ray::Ray;
+use crate::stats::{self, Counter};
+use crate::vec3::Point3;
+
+// Objects per leaf before a range gets split further
+const MAX_LEAF_OBJECTS: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+enum NodeKind {
+    // Objects `start..start + count`
+    Leaf { start: usize, count: usize },
+    // The left child follows its parent directly
+    Interior { right: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    bbox: Aabb,
+    kind: NodeKind,
+}
+
+// An object with the ID it had in the list
+type Entry = (Box<dyn Hittable>, u32);
+
+// Bounding volume hierarchy over a list of objects, split at the median along the longest
+// axis. Nodes sit in one array in depth-first order. Build it once for geometry that
+// doesn't move and share it between worlds through an `Arc`.
+pub struct Bvh {
+    objects: Vec<Entry>,
+    nodes: Vec<Node>,
+    // Objects without a bounding box, tested by every ray
+    unbounded: Vec<Entry>,
+    // Names from the list and the objects in it
+    names: HashMap<u32, String>,
+    // Materials of the objects in list order, with the list's names
+    materials: MaterialIds,
+}
+
+impl Bvh {
+    pub fn new(list: HittableList) -> Self {
+        let mut names = HashMap::new();
+        list.object_names(&mut names);
+        let mut materials = MaterialIds::default();
+        list.scene_materials(&mut materials);
+        let (bounded, unbounded): (Vec<_>, Vec<_>) =
+            list.objects.into_iter().zip(list.ids.into_iter().chain(iter::repeat(0))).partition(|(object, _)| object.bounding_box().is_some());
+        let mut entries: Vec<(Aabb, Entry)> =
+            bounded.into_iter().map(|entry| (entry.0.bounding_box().unwrap(), entry)).collect();
+
+        let mut nodes = Vec::new();
+        if !entries.is_empty() {
+            build(&mut entries, 0, &mut nodes);
+        }
+        Self {
+            objects: entries.into_iter().map(|(_, object)| object).collect(),
+            nodes,
+            unbounded,
+            names,
+            materials,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len() + self.unbounded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Builds the subtree over `entries`, whose first object has index `offset` in the final list
+fn build<T>(entries: &mut [(Aabb, T)], offset: usize, nodes: &mut Vec<Node>) {
+    let bbox = entries[1..].iter().fold(entries[0].0, |bbox, (object_box, _)| Aabb::enclosing(&bbox, object_box));
+    let index = nodes.len();
+    if entries.len() <= MAX_LEAF_OBJECTS {
+        nodes.push(Node { bbox, kind: NodeKind::Leaf { start: offset, count: entries.len() } });
+        return;
+    }
+
+    // Split on the axis the object centers spread furthest along
+    let centers = entries[1..].iter().fold(
+        Aabb::from_points(center(&entries[0].0), center(&entries[0].0)),
+        |bounds, (object_box, _)| Aabb::enclosing(&bounds, &Aabb::from_points(center(object_box), center(object_box))),
+    );
+    let axis = centers.longest_axis();
+    let mid = entries.len() / 2;
+    entries.select_nth_unstable_by(mid, |a, b| a.0.centroid(axis).total_cmp(&b.0.centroid(axis)));
+
+    nodes.push(Node { bbox, kind: NodeKind::Interior { right: 0 } });
+    let (left, right) = entries.split_at_mut(mid);
+    build(left, offset, nodes);
+    let right_index = nodes.len();
+    build(right, offset + mid, nodes);
+    nodes[index].kind = NodeKind::Interior { right: right_index };
+}
+
+// Same IDs `HittableList` gives its objects
+fn with_object_id(mut rec: HitRecord, id: u32) -> HitRecord {
+    rec.object_id = nested_object_id(id, rec.object_id);
+    rec
+}
+
+fn center(bbox: &Aabb) -> Point3 {
+    Point3::new(bbox.centroid(0), bbox.centroid(1), bbox.centroid(2))
+}
+
+impl Hittable for Bvh {
+    fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let mut closest: Option<HitRecord> = None;
+        let mut closest_so_far = ray_t.max;
+        for (object, id) in &self.unbounded {
+            if let Some(rec) = object.hit(r, Interval::new(ray_t.min, closest_so_far)) {
+                closest_so_far = rec.t;
+                closest = Some(with_object_id(rec, *id));
+            }
+        }
+        if self.nodes.is_empty() {
+            return closest;
+        }
+
+        // Median splits keep the tree balanced, so its depth stays far below the stack size
+        let mut stack = [0usize; 64];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let index = stack[stack_len];
+            let node = &self.nodes[index];
+            stats::increment(Counter::BvhNodeTests);
+            if !node.bbox.hit(r, Interval::new(ray_t.min, closest_so_far)) {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    for (object, id) in &self.objects[start..start + count] {
+                        if let Some(rec) = object.hit(r, Interval::new(ray_t.min, closest_so_far)) {
+                            closest_so_far = rec.t;
+                            closest = Some(with_object_id(rec, *id));
+                        }
+                    }
+                }
+                NodeKind::Interior { right } => {
+                    stack[stack_len] = right;
+                    stack[stack_len + 1] = index + 1;
+                    stack_len += 2;
+                }
+            }
+        }
+        closest
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        self.nodes.first().map(|node| node.bbox)
+    }
+
+    fn object_names(&self, names: &mut HashMap<u32, String>) {
+        names.extend(self.names.iter().map(|(&id, name)| (id, name.clone())));
+    }
+
+    fn scene_materials(&self, materials: &mut MaterialIds) {
+        materials.extend(&self.materials);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::integrator::tests::{diffuse_scene, test_camera};
+    use crate::stats::RenderStats;
+
+    #[test]
+    fn bvh_renders_like_the_list_and_counts_node_tests() {
+        let (world, lights) = diffuse_scene();
+        let render = |world: &dyn Hittable| -> (Vec<Color>, RenderStats) {
+            let mut image = Vec::new();
+            let stats = test_camera(4).render_passes(world, &lights, &mut |framebuffer| {
+                image = framebuffer.resolve();
+                true
+            });
+            (image, stats)
+        };
+        let (list_image, list_stats) = render(&world);
+        let (bvh_image, bvh_stats) = render(&Bvh::new(world));
+        assert_eq!(list_stats.counters.get(Counter::BvhNodeTests), 0);
+        assert!(bvh_stats.counters.get(Counter::BvhNodeTests) > 0);
+        assert!(bvh_stats.bvh_node_tests_per_ray() > 0.0);
+        assert_eq!(bvh_image, list_image);
+    }
+}
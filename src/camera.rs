@@ -1,25 +1,138 @@
-use rand::Rng;
 use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use crate::{color::{color_to_string, Color}, hittable::Hittable, interval::Interval, ray::Ray, vec3::{cross, random_in_unit_disk, unit_vector, Point3, Vec3}};
+use std::time::{Duration, Instant};
+use crate::{aov::AovOutput, aperture::Aperture, color::{Color, ColorSpace, Transfer}, cryptomatte::CoverageCell, denoise::Denoiser, filter::Filter, framebuffer::{FeatureCell, FrameBuffer, PixelStats}, hittable::Hittable, hittable_list::HittableList, integrator::{Integrator, PathIntegrator, Scene}, interrupt, interval::Interval, lens::LensSystem, material::MaterialIds, projection::Projection, ray::Ray, sampler::{Sampler, SamplerKind}, stereo::{Eye, Stereo}, stats::{self, Counter, Counters, RenderStats}, tonemap::{DisplayTransform, ToneMap, WhiteBalance}, tile::{generate_tiles, CropWindow, Tile, TileOrder, TileTiming}, vec3::{cross, dot, sample_unit_disk, unit_vector, Point3, Vec3}};
 
+// Samples an unconverged pixel takes per pass when adaptive sampling runs non-progressively
+const ADAPTIVE_BATCH: u32 = 16;
 
-static TRANS_FLAG: bool = false;
+// Physical lens settings assume a 36mm wide full frame sensor and scene units of meters
+const SENSOR_WIDTH_MM: f64 = 36.0;
+const MM_PER_UNIT: f64 = 1000.0;
+
+// Where `focus_dist` comes from. Focusing on a point puts the focal plane through it, so
+// the distance is measured along the view direction.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FocusMode {
+    // Use `focus_dist` as set
+    #[default]
+    Manual,
+    LookAt,
+    Point(Point3),
+    // Whatever the ray through the image center hits first, `focus_dist` if it hits nothing
+    CenterProbe,
+}
+
+// A light path vertex joined to the lens, see `Camera::sample_connection`
+pub struct LensConnection {
+    pub lens_point: Point3,
+    pub raster: (f64, f64),
+    pub importance: f64,
+    // Solid angle density of the lens point as seen from the connected vertex
+    pub pdf: f64,
+}
+
+// What one render worker reports back after a pass
+struct WorkerResult {
+    active_pixels: usize,
+    // (tile index, time spent on it)
+    tile_times: Vec<(usize, Duration)>,
+    counters: Counters,
+}
 
 pub struct Camera {
     pub  aspect_ratio: f64,
     pub image_width: i32,
     pub samples_per_pixel: i32,
     pub max_depth: i32,
+    // Bounce count after which paths start playing Russian roulette, None disables it
+    pub russian_roulette_depth: Option<i32>,
 
     // Camera location / FOV settings
     pub vfov: f64,
+    pub projection: Projection,
     pub lookfrom: Point3,
     pub lookat: Point3,
     pub vup: Vec3, // Camera-relative "up"
 
     pub defocus_angle: f64,
     pub focus_dist: f64,
+    pub focus: FocusMode,
+    // Focal length in millimeters and f-number. When set the render uses what that lens
+    // would give in place of `vfov` and `defocus_angle`, which are left as they are.
+    pub focal_length: Option<f64>,
+    pub f_stop: Option<f64>,
+    // Lens shift as a fraction of the image width and height, right and up. Moves the image
+    // across the focal plane without turning the camera, keeping verticals parallel.
+    pub lens_shift: (f64, f64),
+    // Scheimpflug tilt of the focal plane in degrees: `tilt` turns it about the horizontal
+    // axis so its top recedes, `swing` about the vertical axis so its right side recedes
+    pub tilt: f64,
+    pub swing: f64,
+    // Shape of the lens opening, which out of focus highlights take on
+    pub aperture: Aperture,
+    // How far the lens barrel clips the aperture towards the image corners, squeezing
+    // bokeh there into cat eyes. 0 disables it, 1 halves the aperture in the corners.
+    pub cat_eye: f64,
+    // Traced lens elements instead of the thin lens, see `with_lens_system`
+    pub lens_system: Option<Arc<LensSystem>>,
+    // Renders both eyes into one image, `image_width` and `aspect_ratio` are per eye
+    pub stereo: Option<Stereo>,
+
+    // Trace hero wavelengths instead of RGB, so dispersive materials split light
+    pub spectral: bool,
+
+    // Light transport algorithm that turns camera rays into radiance
+    pub integrator: Arc<dyn Integrator>,
+
+    // Progressive mode renders one sample per pixel per pass and can be stopped early
+    pub progressive: bool,
+    pub snapshot_every_passes: Option<u32>,
+    pub snapshot_every: Option<Duration>,
+    pub snapshot_path: PathBuf,
+    pub time_budget: Option<Duration>,
+    // Accumulated samples are loaded from here when the file exists and saved back with
+    // every snapshot and at the end, so a stopped render can be resumed or a finished one
+    // given more samples. Ctrl-C stops the render early and saves.
+    pub checkpoint_path: Option<PathBuf>,
+
+    // Adaptive sampling stops a pixel once its relative error drops below the threshold.
+    // `samples_per_pixel` is the upper bound, `adaptive_min_samples` the lower one.
+    pub adaptive_threshold: Option<f64>,
+    pub adaptive_min_samples: i32,
+    pub sample_heatmap_path: Option<PathBuf>,
+
+    // Where the random numbers for each path come from
+    pub sampler: SamplerKind,
+    pub seed: u64,
+
+    // Reconstruction filter used to splat samples into the surrounding pixels
+    pub filter: Filter,
+
+    // Exposure, white balance, tone curve and display encoding applied when the image is
+    // written. Its `working_space` is the linear RGB space materials get shaded in and the
+    // framebuffer holds.
+    pub display: DisplayTransform,
+    // Filters the finished image before it is written, snapshots stay as rendered
+    pub denoiser: Option<Denoiser>,
+    // Extra passes written after the image
+    pub aovs: Option<AovOutput>,
+
+    // Square tiles handed out to the render threads in `tile_order`
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    // Renders and writes only this part of the frame
+    pub crop_window: Option<CropWindow>,
+
+    // Also write the end-of-render statistics here as JSON
+    pub stats_json_path: Option<PathBuf>,
+    // Where the finished image goes, standard output when None
+    pub output_path: Option<PathBuf>,
 
     // Camera frame basis vectors
     u: Vec3, 
@@ -28,13 +141,24 @@ pub struct Camera {
 
     // Private fields that get initialized later
     image_height: Option<i32>,
-    pixel_samples_scale: Option<f64>,
     center: Option<Point3>,
     pixel00_loc: Option<Point3>,
     pixel_delta_u: Option<Vec3>,
     pixel_delta_v: Option<Vec3>,
     defocus_disk_u: Option<Vec3>,
     defocus_disk_v: Option<Vec3>,
+    // `vfov` and `defocus_angle`, or what `focal_length` and `f_stop` give
+    effective_vfov: Option<f64>,
+    effective_defocus_angle: Option<f64>,
+    focused_lens: Option<LensSystem>,
+    // Normal of a tilted focal plane, facing the camera
+    focal_plane_normal: Option<Vec3>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Camera {
@@ -44,23 +168,60 @@ impl Camera {
             image_width: 100,
             samples_per_pixel: 500,
             max_depth: 10,
+            russian_roulette_depth: None,
             vfov: 90.0,
+            projection: Projection::Perspective,
             lookfrom: Point3::new(0.0, 0.0, 0.0),
             lookat: Point3::new(0.0,0.0,-1.0),
             vup: Vec3::new(0.0, 1.0, 0.0),
             defocus_angle: 0.0,
             focus_dist: 10.0,
+            focus: FocusMode::Manual,
+            focal_length: None,
+            f_stop: None,
+            lens_shift: (0.0, 0.0),
+            tilt: 0.0,
+            swing: 0.0,
+            aperture: Aperture::Circular,
+            cat_eye: 0.0,
+            lens_system: None,
+            stereo: None,
+            spectral: false,
+            integrator: Arc::new(PathIntegrator),
+            progressive: false,
+            snapshot_every_passes: None,
+            snapshot_every: None,
+            snapshot_path: PathBuf::from("snapshot.ppm"),
+            time_budget: None,
+            checkpoint_path: None,
+            adaptive_threshold: None,
+            adaptive_min_samples: 16,
+            sample_heatmap_path: None,
+            sampler: SamplerKind::Independent,
+            seed: 0,
+            filter: Filter::default(),
+            display: DisplayTransform::default(),
+            denoiser: None,
+            aovs: None,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            crop_window: None,
+            stats_json_path: None,
+            output_path: None,
             u: Vec3::new(0.0, 0.0, 0.0), //Blank vectors to begin with, should they be options? dunno maybe
             v: Vec3::new(0.0, 0.0, 0.0),
             w: Vec3::new(0.0, 0.0, 0.0),
             image_height: None,
-            pixel_samples_scale: None,
             center: None,
             pixel00_loc: None,
             pixel_delta_u: None,
             pixel_delta_v: None,
             defocus_disk_u: None,
             defocus_disk_v: None,
+            effective_vfov: None,
+            effective_defocus_angle: None,
+            focused_lens: None,
+            focal_plane_normal: None,
         }
     }
     
@@ -74,6 +235,11 @@ impl Camera {
         self
     }
     
+    pub fn with_russian_roulette(mut self, min_depth: i32) -> Self {
+        self.russian_roulette_depth = Some(min_depth);
+        self
+    }
+
     pub fn with_samples_per_pixel(mut self, samples_per_pixel: i32) -> Self {
         self.samples_per_pixel = samples_per_pixel;
         self
@@ -89,6 +255,11 @@ impl Camera {
         self
     }
 
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
     pub fn with_lookfrom(mut self, lookfrom: Point3) -> Self {
         self.lookfrom = lookfrom;
         self
@@ -114,6 +285,184 @@ impl Camera {
         self
     }
 
+    pub fn with_focus(mut self, focus: FocusMode) -> Self {
+        self.focus = focus;
+        self
+    }
+
+    pub fn with_lens(mut self, focal_length: f64, f_stop: f64) -> Self {
+        self.focal_length = Some(focal_length);
+        self.f_stop = Some(f_stop);
+        self
+    }
+
+    pub fn with_lens_shift(mut self, horizontal: f64, vertical: f64) -> Self {
+        self.lens_shift = (horizontal, vertical);
+        self
+    }
+
+    pub fn with_tilt(mut self, tilt: f64, swing: f64) -> Self {
+        self.tilt = tilt;
+        self.swing = swing;
+        self
+    }
+
+    pub fn with_aperture(mut self, aperture: Aperture) -> Self {
+        self.aperture = aperture;
+        self
+    }
+
+    pub fn with_cat_eye(mut self, cat_eye: f64) -> Self {
+        self.cat_eye = cat_eye;
+        self
+    }
+
+    // Renders through a lens system with the film at `lookfrom`. Its focal length and sensor
+    // set the field of view and its stop the depth of field, so `vfov` and `defocus_angle`
+    // don't apply. The film is moved to focus at `focus_dist`, and the stop takes the shape
+    // of `aperture`. `lens_shift` slides the film across the image circle, but the film
+    // can't be tilted.
+    pub fn with_lens_system(mut self, lens_system: LensSystem) -> Self {
+        self.lens_system = Some(Arc::new(lens_system));
+        self
+    }
+
+    pub fn with_stereo(mut self, stereo: Stereo) -> Self {
+        self.stereo = Some(stereo);
+        self
+    }
+
+    pub fn with_spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+
+    pub fn with_integrator<I: Integrator + 'static>(mut self, integrator: I) -> Self {
+        self.integrator = Arc::new(integrator);
+        self
+    }
+
+    pub fn with_progressive(mut self, progressive: bool) -> Self {
+        self.progressive = progressive;
+        self
+    }
+
+    pub fn with_snapshot_every_passes(mut self, passes: u32) -> Self {
+        self.snapshot_every_passes = Some(passes);
+        self
+    }
+
+    pub fn with_snapshot_every(mut self, interval: Duration) -> Self {
+        self.snapshot_every = Some(interval);
+        self
+    }
+
+    pub fn with_snapshot_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot_path = path.into();
+        self
+    }
+
+    pub fn with_time_budget(mut self, budget: Duration) -> Self {
+        self.time_budget = Some(budget);
+        self
+    }
+
+    pub fn with_adaptive_sampling(mut self, threshold: f64, min_samples_per_pixel: i32, max_samples_per_pixel: i32) -> Self {
+        self.adaptive_threshold = Some(threshold);
+        self.adaptive_min_samples = min_samples_per_pixel;
+        self.samples_per_pixel = max_samples_per_pixel;
+        self
+    }
+
+    pub fn with_sample_heatmap(mut self, path: impl Into<PathBuf>) -> Self {
+        self.sample_heatmap_path = Some(path.into());
+        self
+    }
+
+    pub fn with_sampler(mut self, sampler: SamplerKind) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // Exposure compensation in stops
+    pub fn with_exposure(mut self, exposure: f64) -> Self {
+        self.display.exposure = exposure;
+        self
+    }
+
+    // Color temperature in kelvin of the light that should come out white, see `WhiteBalance`
+    pub fn with_white_balance(mut self, temperature: f64, tint: f64) -> Self {
+        self.display.white_balance = Some(WhiteBalance::new(temperature, tint));
+        self
+    }
+
+    pub fn with_tone_map(mut self, tone_map: ToneMap) -> Self {
+        self.display.tone_map = tone_map;
+        self
+    }
+
+    pub fn with_denoiser(mut self, denoiser: Denoiser) -> Self {
+        self.denoiser = Some(denoiser);
+        self
+    }
+
+    pub fn with_aovs(mut self, aovs: AovOutput) -> Self {
+        self.aovs = Some(aovs);
+        self
+    }
+
+    pub fn with_working_space(mut self, working_space: ColorSpace) -> Self {
+        self.display.working_space = working_space;
+        self
+    }
+
+    // Primaries and transfer curve of the written image, sRGB by default
+    pub fn with_display(mut self, display_space: ColorSpace, transfer: Transfer) -> Self {
+        self.display.display_space = display_space;
+        self.display.transfer = transfer;
+        self
+    }
+
+    pub fn with_tile_size(mut self, tile_size: usize) -> Self {
+        self.tile_size = tile_size;
+        self
+    }
+
+    pub fn with_tile_order(mut self, tile_order: TileOrder) -> Self {
+        self.tile_order = tile_order;
+        self
+    }
+
+    pub fn with_crop_window(mut self, crop_window: CropWindow) -> Self {
+        self.crop_window = Some(crop_window);
+        self
+    }
+
+    pub fn with_checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint_path = Some(path.into());
+        self
+    }
+
+    pub fn with_stats_json(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stats_json_path = Some(path.into());
+        self
+    }
+
+    pub fn with_output_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output_path = Some(path.into());
+        self
+    }
+
     // Setters for updating after creation
     pub fn set_aspect_ratio(&mut self, aspect_ratio: f64) {
         self.aspect_ratio = aspect_ratio;
@@ -133,67 +482,358 @@ impl Camera {
         self.pixel00_loc = None;
     }
 
-    pub fn render(&mut self, world: &dyn Hittable) {
+    pub fn render(&mut self, world: &dyn Hittable) -> RenderStats {
+        self.render_with_lights(world, &HittableList::new())
+    }
+
+    // Like `render`, but integrators that sample lights directly get to pick from `lights`
+    pub fn render_with_lights(&mut self, world: &dyn Hittable, lights: &HittableList) -> RenderStats {
+        self.render_observed(world, lights, None)
+    }
+
+    // Renders progressively and hands the framebuffer to `on_pass` after every pass instead
+    // of writing anything. Stops when `on_pass` returns false, every pixel has its samples
+    // or the time budget runs out. Checkpoints are neither loaded nor saved.
+    pub fn render_passes(&mut self, world: &dyn Hittable, lights: &HittableList, on_pass: &mut dyn FnMut(&FrameBuffer) -> bool) -> RenderStats {
+        self.render_observed(world, lights, Some(on_pass))
+    }
+
+    fn render_observed(&mut self, world: &dyn Hittable, lights: &HittableList, mut on_pass: Option<&mut dyn FnMut(&FrameBuffer) -> bool>) -> RenderStats {
+        let observed = on_pass.is_some();
+        let progressive = self.progressive || observed;
         self.initialize();
-        
-        let image_height = self.image_height.unwrap();
-        let pixel00_loc = self.pixel00_loc.unwrap();
-        let pixel_delta_u = self.pixel_delta_u.unwrap();
-        let pixel_delta_v = self.pixel_delta_v.unwrap();
-        let center = self.center.unwrap();
-
-        // Extract all needed data from self BEFORE the parallel operation
-        let image_width = self.image_width;
-        let samples_per_pixel = self.samples_per_pixel;
-        let pixel_samples_scale = self.pixel_samples_scale.unwrap();
-
-        println!("P3\n{} {}\n255", self.image_width, image_height);
-
-        let completed_rows = AtomicUsize::new(0);
-
-        let rows: Vec<Vec<String>> = (0..image_height)
-            .into_par_iter()
-            .map(|j| {
-                let mut row_pixels = Vec::new();
-
-                for i in 0..self.image_width {
-                    let mut rng = rand::rng();
-                    let mut pixel_color = Color::new(0.0, 0.0, 0.0);
-                    for _ in 0..self.samples_per_pixel {
-                        let r: Ray = self.get_ray(i, j, &mut rng);
-                        pixel_color += self.ray_color(r, self.max_depth, world, &mut rng);
+        if self.focus == FocusMode::CenterProbe {
+            self.probe_focus(world);
+        }
+
+
+        let region = self.render_region();
+        let (image_width, image_height) = (region.x1 - region.x0, region.y1 - region.y0);
+        let (frame_width, frame_height) = self.image_size();
+        let mut framebuffer = FrameBuffer::new(image_width, image_height, self.filter)
+            .with_origin(region.x0, region.y0)
+            .with_frame_size(frame_width, frame_height);
+        if self.denoiser.is_some() || self.aovs.as_ref().is_some_and(AovOutput::needs_features) {
+            framebuffer = framebuffer.with_features();
+        }
+        if self.aovs.as_ref().is_some_and(AovOutput::needs_light_passes) {
+            framebuffer = framebuffer.with_direct();
+        }
+        if self.aovs.as_ref().is_some_and(AovOutput::needs_coverage) {
+            framebuffer = framebuffer.with_coverage();
+        }
+        // Pass numbers carry on from the checkpoint, so integrators that change from pass to
+        // pass, like the photon mapper's shrinking radius, continue where they left off
+        let mut resumed_passes = 0;
+        if let Some(path) = &self.checkpoint_path
+            && path.exists()
+            && !observed {
+            match framebuffer.load_checkpoint(path) {
+                Ok(passes) => {
+                    resumed_passes = passes;
+                    eprintln!("Resuming from {} ({} samples per pixel minimum)", path.display(), framebuffer.min_samples());
+                }
+                Err(err) => eprintln!("Not resuming from checkpoint {}: {}", path.display(), err),
+            }
+        }
+        let splats = framebuffer.splats();
+        let mut materials = MaterialIds::default();
+        world.scene_materials(&mut materials);
+        let scene = Scene {
+            world,
+            lights,
+            max_depth: self.max_depth,
+            russian_roulette_depth: self.russian_roulette_depth,
+            spectral: self.spectral,
+            working_space: self.display.working_space,
+            camera: self,
+            film: &splats,
+            tile: 0,
+            materials: &materials,
+        };
+        let tiles = generate_tiles(image_width, image_height, self.tile_size, self.tile_order);
+        let mut tile_times = vec![Duration::ZERO; tiles.len()];
+        let mut counters = Counters::default();
+
+        // A non-progressive render is just a single pass that takes every sample at once
+        let max_samples = self.samples_per_pixel.max(1) as u32;
+        let batch = if progressive {
+            1
+        } else if self.adaptive_threshold.is_some() {
+            ADAPTIVE_BATCH
+        } else {
+            max_samples
+        };
+        let batch = match self.integrator.max_samples_per_pass() {
+            Some(cap) => batch.min(cap.max(1)),
+            None => batch,
+        };
+
+        // Checkpointed renders can be stopped in any mode, they finish their current tiles
+        let interruptible = progressive || self.checkpoint_path.is_some();
+        if interruptible {
+            interrupt::install_handler();
+        }
+        let start = Instant::now();
+        let mut last_snapshot = start;
+        let mut pass = resumed_passes;
+
+        loop {
+            pass += 1;
+            self.integrator.begin_pass(&scene, pass);
+            let next_tile = AtomicUsize::new(0);
+            let completed_tiles = AtomicUsize::new(0);
+
+            // Each worker keeps its own sampler and pulls tiles off a shared counter, so
+            // tiles are started in `tile_order` and expensive ones don't stall a whole row.
+            let workers = rayon::current_num_threads().min(tiles.len()).max(1);
+            let results: Vec<WorkerResult> = (0..workers)
+                .into_par_iter()
+                .map(|_| {
+                    let mut sampler = self.sampler.create(max_samples, self.seed);
+                    let mut active_pixels = 0;
+                    let mut timings = Vec::new();
+                    loop {
+                        if self.checkpoint_path.is_some() && interrupt::requested() {
+                            break;
+                        }
+                        let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                        let Some(tile) = tiles.get(index) else {
+                            break;
+                        };
+                        let tile_scene = Scene { tile: index, ..scene };
+                        let tile_start = Instant::now();
+                        active_pixels += self.render_tile(tile, &framebuffer, &tile_scene, sampler.as_mut(), batch, max_samples);
+                        timings.push((index, tile_start.elapsed()));
+
+                        if !progressive {
+                            let completed = completed_tiles.fetch_add(1, Ordering::Relaxed) + 1;
+                            eprintln!("\rTiles remaining: {} ", tiles.len() - completed);
+                        }
                     }
+                    WorkerResult { active_pixels, tile_times: timings, counters: stats::take_thread_counters() }
+                })
+                .collect();
+
+            let mut active_pixels = 0;
+            for result in results {
+                active_pixels += result.active_pixels;
+                counters.merge(&result.counters);
+                for (index, time) in result.tile_times {
+                    tile_times[index] += time;
+                }
+            }
+            if let Some(on_pass) = on_pass.as_mut()
+                && !on_pass(&framebuffer) {
+                break;
+            }
+            if !progressive && interruptible && interrupt::requested() {
+                eprintln!("\rInterrupted with {} samples per pixel minimum.", framebuffer.min_samples());
+                break;
+            }
+            if active_pixels == 0 {
+                break;
+            }
+            if (progressive || batch < max_samples) && !observed {
+                eprint!("\rPass {}: {} pixels still sampling ", pass, active_pixels);
+            }
+            if !progressive {
+                continue;
+            }
 
-                    row_pixels.push(color_to_string(pixel_color * self.pixel_samples_scale.unwrap()));
+            let by_passes = self.snapshot_every_passes.is_some_and(|n| n > 0 && pass.is_multiple_of(n));
+            let by_time = self.snapshot_every.is_some_and(|interval| last_snapshot.elapsed() >= interval);
+            if by_passes || by_time {
+                self.save_snapshot(&framebuffer);
+                if !observed {
+                    self.save_checkpoint(&framebuffer, pass);
                 }
-                let completed = completed_rows.fetch_add(1, Ordering::Relaxed) + 1;
-                eprintln!("\rScanlines remaining: {} ", image_height as usize - completed);
+                last_snapshot = Instant::now();
+            }
 
-                row_pixels
-            })
-            .collect();
-        // Output all rows in order
-        for row in rows {
-            for pixel_str in row {
-                print!("{}", pixel_str);
+            if interrupt::requested() {
+                eprintln!("\rInterrupted after {} passes ({} samples per pixel minimum).", pass, framebuffer.min_samples());
+                break;
+            }
+            if self.time_budget.is_some_and(|budget| start.elapsed() >= budget) {
+                eprintln!("\rTime budget reached after {} passes ({} samples per pixel minimum).", pass, framebuffer.min_samples());
+                break;
+            }
+        }
+
+        let render_stats = RenderStats {
+            wall_time: start.elapsed(),
+            passes: pass - resumed_passes,
+            image_width,
+            image_height,
+            counters,
+            tile_timings: tiles.iter().zip(&tile_times)
+                .map(|(tile, time)| TileTiming { tile: *tile, time: *time })
+                .collect(),
+        };
+        if observed {
+            return render_stats;
+        }
+
+        self.save_checkpoint(&framebuffer, pass);
+        self.write_outputs(world, &materials, &framebuffer);
+        eprint!("\r{}", render_stats.report());
+        if let Some(path) = &self.stats_json_path
+            && let Err(err) = fs::write(path, render_stats.to_json()) {
+            eprintln!("Failed to write render statistics {}: {}", path.display(), err);
+        }
+
+        eprintln!("\rDone.");
+        render_stats
+    }
+
+    // The finished image, then whichever extra files were asked for
+    fn write_outputs(&self, world: &dyn Hittable, materials: &MaterialIds, framebuffer: &FrameBuffer) {
+        let mut image = framebuffer.resolve();
+        let features = framebuffer.feature_buffers();
+        if let Some(denoiser) = &self.denoiser {
+            image = denoiser.denoise(framebuffer.width, framebuffer.height, &image, features.as_ref());
+        }
+        let written = match &self.output_path {
+            Some(path) => File::create(path).and_then(|file| framebuffer.write_image_ppm(&mut BufWriter::new(file), &image, &self.display)),
+            None => framebuffer.write_image_ppm(&mut io::stdout().lock(), &image, &self.display),
+        };
+        if let Err(err) = written {
+            eprintln!("Failed to write image: {}", err);
+        }
+        if let Some(aovs) = &self.aovs {
+            let mut object_names = HashMap::new();
+            world.object_names(&mut object_names);
+            if let Err(err) = aovs.write(framebuffer, &image, &object_names, materials, &self.display, self.image_size()) {
+                eprintln!("Failed to write passes {}: {}", aovs.path.display(), err);
             }
         }
+        if let Some(path) = &self.sample_heatmap_path
+            && let Err(err) = framebuffer.save_sample_heatmap(path) {
+            eprintln!("Failed to write sample heatmap {}: {}", path.display(), err);
+        }
+    }
 
-        eprintln!("\rDone.");     
+    // Samples every pixel of `tile` for this pass and returns how many still want more samples
+    fn render_tile(&self, tile: &Tile, framebuffer: &FrameBuffer, scene: &Scene, sampler: &mut dyn Sampler, batch: u32, max_samples: u32) -> usize {
+        let mut active_pixels = 0;
+        for j in tile.y0..tile.y1 {
+            for i in tile.x0..tile.x1 {
+                let cell = &framebuffer.stats[j * framebuffer.width + i];
+                let features = framebuffer.features.as_ref().map(|features| &features[j * framebuffer.width + i]);
+                let coverage = framebuffer.coverage.as_ref().map(|coverage| &coverage[j * framebuffer.width + i]);
+                // The pixel's place in the full frame, which rays and sample patterns follow
+                let (x, y) = (framebuffer.origin.0 + i, framebuffer.origin.1 + j);
+                let mut pixel_stats = cell.load();
+                let samples = self.samples_this_pass(&pixel_stats, batch, max_samples);
+                for _ in 0..samples {
+                    sampler.start_pixel_sample(x as i32, y as i32, pixel_stats.count);
+                    let (offset, sample, direct) = self.sample_pixel(x as i32, y as i32, scene, sampler, features, coverage);
+                    let (film_x, film_y) = (i as f64 + 0.5 + offset.x(), j as f64 + 0.5 + offset.y());
+                    framebuffer.film.add_sample(film_x, film_y, sample);
+                    if let Some(film) = &framebuffer.direct {
+                        film.add_sample(film_x, film_y, direct);
+                    }
+                    pixel_stats.add(sample);
+                }
+                cell.store(pixel_stats);
+                if self.samples_this_pass(&pixel_stats, batch, max_samples) > 0 {
+                    active_pixels += 1;
+                }
+            }
+        }
+        active_pixels
+    }
+
+    // How many samples a pixel should take in the current pass, 0 once it is finished
+    fn samples_this_pass(&self, stats: &PixelStats, batch: u32, max_samples: u32) -> u32 {
+        if stats.count >= max_samples {
+            return 0;
+        }
+        let remaining = max_samples - stats.count;
+        let Some(threshold) = self.adaptive_threshold else {
+            return batch.min(remaining);
+        };
+
+        // Below the minimum the error estimate means little, so the pixel just keeps taking
+        // its batch, one sample per pass in progressive mode
+        let min_samples = (self.adaptive_min_samples.max(2) as u32).min(max_samples);
+        if stats.count >= min_samples && stats.relative_error() < threshold {
+            return 0;
+        }
+        batch.min(remaining)
+    }
+
+    fn save_snapshot(&self, framebuffer: &FrameBuffer) {
+        if let Err(err) = framebuffer.save_ppm(&self.snapshot_path, &self.display) {
+            eprintln!("\rFailed to write snapshot {}: {}", self.snapshot_path.display(), err);
+        }
+    }
+
+    fn save_checkpoint(&self, framebuffer: &FrameBuffer, passes: u32) {
+        if let Some(path) = &self.checkpoint_path
+            && let Err(err) = framebuffer.save_checkpoint(path, passes) {
+            eprintln!("\rFailed to write checkpoint {}: {}", path.display(), err);
+        }
+    }
+
+    // Traces one camera sample, returning its offset from the pixel center, its radiance and
+    // the direct light part of that
+    fn sample_pixel(&self, i: i32, j: i32, scene: &Scene, sampler: &mut dyn Sampler, features: Option<&FeatureCell>, coverage: Option<&CoverageCell>) -> (Vec3, Color, Color) {
+        let offset = self.sample_square(sampler);
+        let Some(r) = self.get_ray(i, j, offset, sampler) else {
+            if let Some(coverage) = coverage {
+                coverage.add(0, 0);
+            }
+            return (offset, Color::new(0.0, 0.0, 0.0), Color::new(0.0, 0.0, 0.0))
+        };
+        stats::increment(Counter::PrimaryRays);
+        if features.is_some() || coverage.is_some() {
+            let first = scene.primary_features(&r);
+            if let Some(features) = features {
+                features.add(first);
+            }
+            if let Some(coverage) = coverage {
+                coverage.add(first.object_id, first.material_id);
+            }
+        }
+        let (radiance, direct) = self.integrator.ray_color_with_direct(r, scene, sampler);
+        (offset, radiance, direct)
     }
 
     fn initialize(&mut self) {
         self.image_height = Some((self.image_width as f64 / self.aspect_ratio) as i32);
         self.image_height = Some(self.image_height.unwrap().max(1));
-        self.pixel_samples_scale = Some(1.0 / self.samples_per_pixel as f64);
 
         self.center = Some(self.lookfrom);
 
+        match self.focus {
+            FocusMode::LookAt => self.focus_on(self.lookat),
+            FocusMode::Point(p) => self.focus_on(p),
+            FocusMode::Manual | FocusMode::CenterProbe => {}
+        }
+        let mut vfov = self.vfov;
+        let mut defocus_angle = self.defocus_angle;
+        if let Some(focal_length) = self.focal_length {
+            let sensor_height = SENSOR_WIDTH_MM * self.image_height.unwrap() as f64 / self.image_width as f64;
+            vfov = 2.0 * (sensor_height / (2.0 * focal_length)).atan().to_degrees();
+            if let Some(f_stop) = self.f_stop {
+                let lens_radius = focal_length / f_stop / 2.0 / MM_PER_UNIT;
+                defocus_angle = 2.0 * (lens_radius / self.focus_dist).atan().to_degrees();
+            }
+        }
+        self.effective_vfov = Some(vfov);
+        self.effective_defocus_angle = Some(defocus_angle);
+
         // Determine viewport dimensions
-        let theta = self.vfov.to_radians();
+        let theta = vfov.to_radians();
         let h = (theta/2.0).tan();
-        let viewport_height = 2.0 * h * self.focus_dist;
-        let viewport_width = viewport_height * (self.image_width as f64 / self.image_height.unwrap() as f64);
+        let mut viewport_height = 2.0 * h * self.focus_dist;
+        let mut viewport_width = viewport_height * (self.image_width as f64 / self.image_height.unwrap() as f64);
+        if let Projection::Orthographic { view_width } = self.projection {
+            viewport_width = view_width;
+            viewport_height = view_width * (self.image_height.unwrap() as f64 / self.image_width as f64);
+        }
 
         // Calculate u,v,w unit basis vectors for camera coordinate frame.
         self.w = unit_vector(self.lookfrom - self.lookat);
@@ -209,73 +849,442 @@ impl Camera {
         self.pixel_delta_v = Some(viewport_v / self.image_height.unwrap() as f64);
 
         // Calculcate location of upper left pixel
-        let viewport_upper_left = self.center.unwrap() - (self.w * self.focus_dist) - viewport_u / 2.0 - viewport_v / 2.0;
+        let mut viewport_upper_left = self.center.unwrap() - (self.w * self.focus_dist) - viewport_u / 2.0 - viewport_v / 2.0;
+        if self.lens_shift != (0.0, 0.0) {
+            viewport_upper_left += (viewport_u * self.lens_shift.0) - (viewport_v * self.lens_shift.1);
+        }
         self.pixel00_loc = Some(viewport_upper_left + (self.pixel_delta_u.unwrap() + self.pixel_delta_v.unwrap()) * 0.5);
-        let defocus_radius = self.focus_dist * (self.defocus_angle / 2.0).to_radians().tan();
+        let defocus_radius = self.focus_dist * (defocus_angle / 2.0).to_radians().tan();
 
         self.defocus_disk_u = Some(self.u * defocus_radius);
         self.defocus_disk_v = Some(self.v * defocus_radius);
-        
-    
+        self.focal_plane_normal = (self.tilt != 0.0 || self.swing != 0.0).then(|| {
+            unit_vector(self.w + (self.v * self.tilt.to_radians().tan()) + (self.u * self.swing.to_radians().tan()))
+        });
+
+        self.focused_lens = self.lens_system.as_ref().map(|lens| {
+            lens.focused(self.focus_dist / lens.scale).unwrap_or_else(|| {
+                eprintln!("Lens system can't focus at {}, leaving the film where it is", self.focus_dist);
+                (**lens).clone()
+            })
+        });
     }
 
-    fn ray_color(&self, r: Ray, depth: i32, world: &dyn Hittable, rng: &mut impl rand::RngCore) -> Color {
-        if depth <= 0 {
-            return Color::new(0.0,0.0,0.0)
+    fn focus_on(&mut self, p: Point3) {
+        let distance = dot(p - self.lookfrom, unit_vector(self.lookat - self.lookfrom));
+        if distance > 0.0 {
+            self.focus_dist = distance;
+        } else {
+            eprintln!("Focus point is behind the camera, keeping focus_dist {}", self.focus_dist);
         }
-        if let Some(rec) = world.hit(&r, Interval::new(0.001, f64::INFINITY)) { 
-            if let Some(sc) = rec.mat.scatter(&r, &rec, rng) {
-                return sc.attenuation * self.ray_color(sc.ray, depth - 1, world, rng);        
-            }
-            return Color::new(0.0,0.0,0.0) 
-        }
-
-        if TRANS_FLAG {
-            let a = 0.5 * (unit_vector(r.direction()).y() + 1.0); // 0 at bottom, 1 at top
-            let c_blue  = Color::new(0.357, 0.808, 0.980); // #5BCEFA
-            let c_pink  = Color::new(0.961, 0.663, 0.722); // #F5A9B8
-            let c_white = Color::new(1.0,  1.0,   1.0);
-
-            if a >= 0.8 {
-                return c_blue;   // top 20%
-            } else if a >= 0.6 {
-                return c_pink;   // next 20%
-            } else if a >= 0.4 {
-                return c_white;  // middle 20%
-            } else if a >= 0.2 {
-                return c_pink;   // next 20%
-            } else {
-                return c_blue;   // bottom 20%
+    }
+
+    // Focuses on what the ray through the image center hits. The viewport scales with
+    // `focus_dist`, so the camera gets initialized again afterwards.
+    fn probe_focus(&mut self, world: &dyn Hittable) {
+        let (width, height) = self.eye_size();
+        let image_center = self.pixel00_loc.unwrap()
+            + (self.pixel_delta_u.unwrap() * (width as f64 / 2.0 - 0.5))
+            + (self.pixel_delta_v.unwrap() * (height as f64 / 2.0 - 0.5));
+        let probe = Ray::new(self.center.unwrap(), image_center - self.center.unwrap());
+        match world.hit(&probe, Interval::new(0.001, f64::INFINITY)) {
+            Some(rec) => {
+                self.focus_on(rec.p);
+                self.initialize();
             }
+            None => eprintln!("Autofocus probe hit nothing, keeping focus_dist {}", self.focus_dist),
+        }
+    }
+
+    fn get_ray(&self, i: i32, j: i32, offset: Vec3, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let Some(stereo) = &self.stereo else {
+            return self.eye_ray(i, j, offset, sampler);
+        };
+        let (eye, i, j) = stereo.eye_pixel(i, j, self.eye_size());
+        let r = self.eye_ray(i, j, offset, sampler)?;
+        Some(self.stereo_ray(stereo, eye, r))
+    }
+
+    // Moves a ray of the center view over to one eye
+    fn stereo_ray(&self, stereo: &Stereo, eye: Eye, r: Ray) -> Ray {
+        let half_distance = eye.sign() * stereo.interpupillary_distance / 2.0;
+        let convergence = stereo.convergence_distance;
+        if self.projection.is_planar() {
+            // Shearing every ray the same way keeps whatever they focused on in focus: points
+            // at depth z move sideways by (1 - z / convergence) of the eye offset
+            let eye_offset = self.u * half_distance;
+            let depth = dot(r.origin() - self.center.unwrap(), -self.w);
+            let origin = r.origin() + eye_offset * (1.0 - depth / convergence);
+            let direction = r.direction() - eye_offset * (dot(r.direction(), -self.w) / convergence);
+            return Ray::new(origin, direction);
+        }
+
+        // Omni-directional stereo: the eyes sit on a circle, each ray leaving it sideways
+        let direction = unit_vector(r.direction());
+        let mut side = cross(direction, self.v);
+        side = if side.length_squared() > 1e-12 { unit_vector(side) } else { self.u };
+        let origin = r.origin() + side * half_distance;
+        if convergence.is_finite() {
+            Ray::new(origin, r.origin() + direction * convergence - origin)
         } else {
-            let unit_direction = unit_vector(r.direction());
-            let a = 0.5 * (unit_direction.y() + 1.0);
-            (Color::new(1.0, 1.0, 1.0) * (1.0 - a)) + (Color::new(0.5, 0.7, 1.0) * a)
+            Ray::new(origin, r.direction())
         }
     }
 
-    fn get_ray(&self, i: i32, j: i32, rng: &mut impl rand::RngCore) -> Ray {
-        // Construct a camera ray originating from the defocus disk and directed at a randomly sampled point around the pixel location i,j
-        let offset = self.sample_square(rng);
+    fn eye_ray(&self, i: i32, j: i32, offset: Vec3, sampler: &mut dyn Sampler) -> Option<Ray> {
+        // Construct a camera ray originating from the defocus disk and directed at the point `offset` away from the pixel location i,j
         let pixel_sample = self.pixel00_loc.unwrap()
                             + (self.pixel_delta_u.unwrap() * (i as f64 + offset.x()))
                             + (self.pixel_delta_v.unwrap() * (j as f64 + offset.y()));
 
-        let ray_origin = if self.defocus_angle <= 0.0 {
-            self.center.unwrap()
-        } else {
-            self.defocus_disk_sample(rng)
+        // Always draw the lens sample so the sampler dimensions line up with or without defocus
+        let lens_u = sampler.get_2d();
+        if let Some(lens) = &self.focused_lens {
+            return self.lens_system_ray(lens, i, j, offset, lens_u, sampler);
+        }
+        let (width, height) = self.eye_size();
+        let s = (i as f64 + 0.5 + offset.x()) / width as f64;
+        let t = (j as f64 + 0.5 + offset.y()) / height as f64;
+        let lens_sample = self.lens_position(self.aperture_sample(lens_u, s, t)?);
+        match self.projection {
+            Projection::Perspective => {
+                let ray_origin = if self.effective_defocus_angle.unwrap() <= 0.0 {
+                    self.center.unwrap()
+                } else {
+                    lens_sample
+                };
+                match self.focus_target(self.center.unwrap(), pixel_sample) {
+                    Some(target) => Some(Ray::new(ray_origin, target - ray_origin)),
+                    // Past the horizon of a tilted focal plane things are in focus at infinity
+                    None => Some(Ray::new(ray_origin, pixel_sample - self.center.unwrap())),
+                }
+            }
+            Projection::Orthographic { .. } => {
+                // The lens sits in a plane of the same size as the viewport, focus_dist behind it
+                let chief_origin = pixel_sample + self.w * self.focus_dist;
+                let mut ray_origin = chief_origin;
+                if self.effective_defocus_angle.unwrap() > 0.0 {
+                    ray_origin += lens_sample - self.center.unwrap();
+                }
+                match self.focus_target(chief_origin, pixel_sample) {
+                    Some(target) => Some(Ray::new(ray_origin, target - ray_origin)),
+                    None => Some(Ray::new(ray_origin, pixel_sample - chief_origin)),
+                }
+            }
+            projection => {
+                let d = projection.direction(s, t, width as f64 / height as f64)?;
+                Some(Ray::new(self.center.unwrap(), (self.u * d.x()) + (self.v * d.y()) - (self.w * d.z())))
+            }
+        }
+    }
+
+    // Where the ray from the lens center through `pixel_sample` meets the focal plane, None
+    // if it never does
+    fn focus_target(&self, chief_origin: Point3, pixel_sample: Point3) -> Option<Point3> {
+        let Some(normal) = self.focal_plane_normal else {
+            return Some(pixel_sample);
         };
-        Ray::new(ray_origin, pixel_sample - ray_origin)
+        let direction = pixel_sample - chief_origin;
+        let plane_point = self.center.unwrap() - self.w * self.focus_dist;
+        let t = dot(plane_point - chief_origin, normal) / dot(direction, normal);
+        (t > 0.0 && t.is_finite()).then(|| chief_origin + direction * t)
+    }
+
+    // Point on the unit aperture for the image position (s, t), None where the lens barrel
+    // blocks it
+    fn aperture_sample(&self, u: (f64, f64), s: f64, t: f64) -> Option<Vec3> {
+        let p = self.aperture.sample(u);
+        if self.cat_eye > 0.0 && self.effective_defocus_angle.unwrap() > 0.0 {
+            // The barrel is a second disc sliding off center as the image position moves out,
+            // by `cat_eye` aperture radii in the corners
+            let aspect_ratio = self.aspect_ratio.max(f64::EPSILON);
+            let (shift_x, shift_y) = self.lens_shift;
+            let position = Vec3::new((2.0 * (s + shift_x) - 1.0) * aspect_ratio, 1.0 - 2.0 * (t - shift_y), 0.0);
+            let shift = position * (self.cat_eye / (aspect_ratio * aspect_ratio + 1.0).sqrt());
+            if (p - shift).length_squared() > 1.0 {
+                return None;
+            }
+        }
+        Some(p)
+    }
+
+    // Film point for the pixel position, traced through the lens elements into the scene.
+    // Rays are aimed at the rear element and thinned out by cos^4 for the natural falloff.
+    fn lens_system_ray(&self, lens: &LensSystem, i: i32, j: i32, offset: Vec3, u: (f64, f64), sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (width, height) = self.eye_size();
+        let aspect_ratio = width as f64 / height as f64;
+        let half_height = lens.sensor_diagonal / 2.0 / (aspect_ratio * aspect_ratio + 1.0).sqrt();
+        let half_width = half_height * aspect_ratio;
+        let x = 2.0 * ((i as f64 + 0.5 + offset.x()) / width as f64 + self.lens_shift.0) - 1.0;
+        let y = 1.0 - 2.0 * ((j as f64 + 0.5 + offset.y()) / height as f64 - self.lens_shift.1);
+        // The lens turns the image upside down, so the film is read mirrored
+        let film_point = Point3::new(-x * half_width, -y * half_height, 0.0);
+        let rear_point = sample_unit_disk(u) * lens.rear_radius() + Vec3::new(0.0, 0.0, lens.rear_z());
+
+        let direction = unit_vector(rear_point - film_point);
+        if sampler.get_1d() >= direction.z().powi(4) {
+            return None;
+        }
+        let (o, d) = lens.trace_from_film(film_point, direction, &self.aperture)?;
+        let origin = self.center.unwrap() + ((self.u * o.x()) + (self.v * o.y()) + (self.w * o.z())) * lens.scale;
+        Some(Ray::new(origin, (self.u * d.x()) + (self.v * d.y()) + (self.w * d.z())))
+    }
+
+    // Image size in pixels, valid once rendering has started. Holds both eyes in stereo.
+    pub fn image_size(&self) -> (usize, usize) {
+        match &self.stereo {
+            Some(stereo) => stereo.image_size(self.eye_size()),
+            None => self.eye_size(),
+        }
+    }
+
+    // Pixels of the full image that get rendered, all of them without a crop window
+    pub fn render_region(&self) -> Tile {
+        let (width, height) = self.image_size();
+        match &self.crop_window {
+            Some(crop_window) => crop_window.to_tile(width, height),
+            None => Tile { x0: 0, y0: 0, x1: width, y1: height },
+        }
+    }
+
+    fn eye_size(&self) -> (usize, usize) {
+        (self.image_width as usize, self.image_height.unwrap() as usize)
+    }
+
+    // Camera ray through a continuous raster position, drawing its lens sample from `sampler`
+    pub fn generate_ray(&self, raster: (f64, f64), sampler: &mut dyn Sampler) -> Option<Ray> {
+        let i = raster.0.floor();
+        let j = raster.1.floor();
+        let offset = Vec3::new(raster.0 - i - 0.5, raster.1 - j - 0.5, 0.0);
+        self.get_ray(i as i32, j as i32, offset, sampler)
+    }
+
+    // Light paths can only be joined to the lens of the perspective projection, and only
+    // when every point of the aperture passes the same light
+    pub fn supports_lens_connections(&self) -> bool {
+        let uniform_aperture = self.effective_defocus_angle.unwrap() <= 0.0
+            || (self.aperture.is_uniform() && self.cat_eye <= 0.0 && self.focal_plane_normal.is_none());
+        self.projection == Projection::Perspective && self.lens_system.is_none() && self.stereo.is_none() && uniform_aperture
+    }
+
+    // Connects a point in the scene to the camera for light tracing: picks a lens point that
+    // sees `p` and reports where on the image it lands and how much importance it carries
+    pub fn sample_connection(&self, p: Point3, sampler: &mut dyn Sampler) -> Option<LensConnection> {
+        let lens_point = self.lens_position(self.aperture.sample(sampler.get_2d()));
+        let to_lens = lens_point - p;
+        let r = Ray::new(lens_point, -to_lens);
+        let (importance, raster) = self.importance(&r)?;
+        let cos_theta = dot(unit_vector(r.direction()), -self.w);
+        Some(LensConnection {
+            lens_point,
+            raster,
+            importance,
+            pdf: to_lens.length_squared() / (cos_theta * self.lens_area()),
+        })
+    }
+
+    // Importance carried by a ray leaving the lens and the raster position it passes
+    // through, None if it misses the image
+    pub fn importance(&self, r: &Ray) -> Option<(f64, (f64, f64))> {
+        if !self.supports_lens_connections() {
+            return None;
+        }
+        let direction = unit_vector(r.direction());
+        let cos_theta = dot(direction, -self.w);
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        let raster = self.raster_position(r.origin(), direction, cos_theta)?;
+        Some((1.0 / (self.film_area() * self.lens_area() * cos_theta.powi(4)), raster))
+    }
+
+    // Solid angle density of `get_ray` producing this direction from the ray's lens point
+    pub fn pdf_direction(&self, r: &Ray) -> f64 {
+        if !self.supports_lens_connections() {
+            return 0.0;
+        }
+        let direction = unit_vector(r.direction());
+        let cos_theta = dot(direction, -self.w);
+        if cos_theta <= 0.0 || self.raster_position(r.origin(), direction, cos_theta).is_none() {
+            return 0.0;
+        }
+        1.0 / (self.film_area() * cos_theta.powi(3))
+    }
+
+    fn raster_position(&self, lens_point: Point3, direction: Vec3, cos_theta: f64) -> Option<(f64, f64)> {
+        let delta_u = self.pixel_delta_u.unwrap();
+        let delta_v = self.pixel_delta_v.unwrap();
+        let focus_point = lens_point + direction * (self.focus_dist / cos_theta);
+        let upper_left = self.pixel00_loc.unwrap() - (delta_u + delta_v) * 0.5;
+        let x = dot(focus_point - upper_left, delta_u) / delta_u.length_squared();
+        let y = dot(focus_point - upper_left, delta_v) / delta_v.length_squared();
+        if x < 0.0 || y < 0.0 || x >= self.image_width as f64 || y >= self.image_height.unwrap() as f64 {
+            return None;
+        }
+        Some((x, y))
+    }
+
+    // Area of the image projected onto a plane at unit distance in front of the lens
+    fn film_area(&self) -> f64 {
+        let width = self.pixel_delta_u.unwrap().length() * self.image_width as f64;
+        let height = self.pixel_delta_v.unwrap().length() * self.image_height.unwrap() as f64;
+        width * height / (self.focus_dist * self.focus_dist)
+    }
+
+    // A pinhole counts as unit area so its lens density is a plain delta
+    fn lens_area(&self) -> f64 {
+        let radius = self.defocus_disk_u.unwrap().length();
+        if radius <= 0.0 {
+            return 1.0;
+        }
+        self.aperture.area() * radius * radius
     }
 
-    fn sample_square(&self, rng: &mut impl rand::RngCore) -> Vec3 {
-        Vec3::new(rng.random::<f64>() - 0.5, rng.random::<f64>() - 0.5, 0.0)
+    fn sample_square(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let (x, y) = sampler.get_pixel_2d();
+        Vec3::new(x - 0.5, y - 0.5, 0.0)
     }
 
-    fn defocus_disk_sample(&self, rng: &mut impl rand::RngCore) -> Point3 {
-        let p = random_in_unit_disk(rng);
+    // Lens point for a point on the unit aperture
+    fn lens_position(&self, p: Vec3) -> Point3 {
         self.center.unwrap() + (self.defocus_disk_u.unwrap() * p.x()) + (self.defocus_disk_v.unwrap() * p.y())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bdpt::BdptIntegrator;
+    use crate::integrator::tests::{assert_close, diffuse_scene, mean, render, test_camera};
+    use crate::mlt::MltIntegrator;
+    use crate::sampler::IndependentSampler;
+
+    // Mean of the pixels of a `width` wide image inside `tile`
+    fn region_mean(image: &[Color], width: usize, tile: Tile) -> Color {
+        let pixels: Vec<Color> = (tile.y0..tile.y1).flat_map(|y| (tile.x0..tile.x1).map(move |x| image[y * width + x])).collect();
+        mean(&pixels)
+    }
+
+    #[test]
+    fn crop_window_matches_the_full_frame_with_splats() {
+        let (world, lights) = diffuse_scene();
+        let tile = Tile { x0: 4, y0: 6, x1: 16, y1: 18 };
+        let mut full = test_camera(64).with_integrator(BdptIntegrator);
+        let full_image = render(&mut full, &world, &lights);
+        let mut crop = test_camera(64)
+            .with_integrator(BdptIntegrator)
+            .with_crop_window(CropWindow::Pixels { x0: tile.x0, y0: tile.y0, x1: tile.x1, y1: tile.y1 });
+        let crop_image = render(&mut crop, &world, &lights);
+        assert_eq!(crop_image.len(), 12 * 12);
+        assert_close(mean(&crop_image), region_mean(&full_image, 24, tile), 0.05);
+    }
+
+    fn mlt_camera(samples_per_pixel: i32) -> Camera {
+        test_camera(samples_per_pixel)
+            .with_progressive(true)
+            .with_integrator(MltIntegrator::new(PathIntegrator).with_bootstrap_samples(4000).with_mutations_per_pixel(32))
+    }
+
+    #[test]
+    fn resumed_mlt_render_bootstraps() {
+        let (world, lights) = diffuse_scene();
+        let dir = std::env::temp_dir();
+        let checkpoint = dir.join(format!("raytracing-mlt-resume-{}.bin", std::process::id()));
+        let output = dir.join(format!("raytracing-mlt-resume-{}.ppm", std::process::id()));
+        let _ = fs::remove_file(&checkpoint);
+
+        mlt_camera(4).with_checkpoint(&checkpoint).with_output_path(&output).render_with_lights(&world, &lights);
+        let mut resumed = mlt_camera(8).with_checkpoint(&checkpoint).with_output_path(&output);
+        let stats = resumed.render_with_lights(&world, &lights);
+        assert_eq!(stats.passes, 4);
+
+        let mut framebuffer = FrameBuffer::new(24, 24, resumed.filter);
+        assert_eq!(framebuffer.load_checkpoint(&checkpoint).unwrap(), 8);
+        let _ = fs::remove_file(&checkpoint);
+        let _ = fs::remove_file(&output);
+        assert_eq!(framebuffer.min_samples(), 8);
+
+        let straight = render(&mut mlt_camera(8), &world, &lights);
+        assert_close(mean(&framebuffer.resolve()), mean(&straight), 0.1);
+    }
+
+    #[test]
+    fn adaptive_pixels_reach_their_minimum_one_batch_at_a_time() {
+        let camera = Camera::new().with_adaptive_sampling(0.5, 16, 64);
+        // Constant pixels have no error, but still get their minimum first
+        let mut stats = PixelStats::default();
+        let mut passes = 0;
+        while stats.count < 16 {
+            let samples = camera.samples_this_pass(&stats, 1, 64);
+            assert_eq!(samples, 1);
+            for _ in 0..samples {
+                stats.add(Color::new(0.5, 0.5, 0.5));
+            }
+            passes += 1;
+        }
+        assert_eq!(passes, 16);
+        assert_eq!(camera.samples_this_pass(&stats, 1, 64), 0);
+
+        assert_eq!(camera.samples_this_pass(&PixelStats::default(), 4, 64), 4);
+        // Noisy pixels past the minimum stop at the maximum
+        let noisy = PixelStats { count: 62, mean: 0.5, m2: 10000.0 };
+        assert_eq!(camera.samples_this_pass(&noisy, 4, 64), 2);
+    }
+
+    #[test]
+    fn perspective_rays_land_back_on_their_raster_position() {
+        for defocus_angle in [0.0, 2.0] {
+            let mut camera = test_camera(1).with_defocus_angle(defocus_angle).with_focus_dist(2.5);
+            camera.initialize();
+            let mut sampler = IndependentSampler::new(1);
+            for (i, j, x, y) in [(0, 0, -0.5, -0.5), (5, 17, 0.2, -0.3), (23, 23, 0.49, 0.49), (12, 3, 0.0, 0.0)] {
+                sampler.start_pixel_sample(i, j, 0);
+                let r = camera.eye_ray(i, j, Vec3::new(x, y, 0.0), &mut sampler).unwrap();
+                let (_, (u, v)) = camera.importance(&r).unwrap();
+                assert!((u - (i as f64 + 0.5 + x)).abs() < 1e-9 && (v - (j as f64 + 0.5 + y)).abs() < 1e-9, "pixel ({}, {}) came back as ({}, {})", i, j, u, v);
+            }
+        }
+    }
+
+    #[test]
+    fn stereo_eyes_sit_apart_and_converge() {
+        let mut sampler = IndependentSampler::new(1);
+        let mut eye_rays = |camera: &Camera, i: i32, j: i32| {
+            sampler.start_pixel_sample(i, j, 0);
+            let left = camera.get_ray(i, j, Vec3::new(0.0, 0.0, 0.0), &mut sampler).unwrap();
+            let right = camera.get_ray(i + 24, j, Vec3::new(0.0, 0.0, 0.0), &mut sampler).unwrap();
+            (left, right)
+        };
+
+        // Parallel eyes look the same way from either side of the center
+        let mut camera = test_camera(1).with_stereo(Stereo::new(0.2));
+        camera.initialize();
+        let (left, right) = eye_rays(&camera, 7, 15);
+        assert!((right.origin() - left.origin() - camera.u * 0.2).length() < 1e-12);
+        assert!((unit_vector(left.direction()) - unit_vector(right.direction())).length() < 1e-12);
+
+        // Converged eyes see the same point at the convergence distance
+        let mut camera = test_camera(1).with_stereo(Stereo::new(0.2).with_convergence(2.0));
+        camera.initialize();
+        for (i, j) in [(0, 0), (7, 15), (23, 11)] {
+            let (left, right) = eye_rays(&camera, i, j);
+            let at_convergence = |r: &Ray| {
+                let depth = dot(r.origin() - camera.center.unwrap(), -camera.w);
+                r.at((2.0 - depth) / dot(r.direction(), -camera.w))
+            };
+            assert!((at_convergence(&left) - at_convergence(&right)).length() < 1e-9);
+            assert!((right.origin() - left.origin()).length() > 0.1);
+        }
+
+        // Panoramic eyes are offset across each ray's own direction
+        let mut camera = test_camera(1).with_aspect_ratio(2.0).with_image_width(24).with_projection(Projection::Equirectangular).with_stereo(Stereo::new(0.2));
+        camera.initialize();
+        for (i, j) in [(0, 6), (9, 2), (17, 8)] {
+            let (left, right) = eye_rays(&camera, i, j);
+            let gap = right.origin() - left.origin();
+            assert!((gap.length() - 0.2).abs() < 1e-12);
+            assert!(dot(gap, left.direction()).abs() < 1e-9 && dot(gap, camera.v).abs() < 1e-12);
+        }
+    }
+
+}
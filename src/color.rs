@@ -1,25 +1,308 @@
-use crate::{interval::{self, Interval}, vec3::Vec3};
-use std::io::{self, Write};
+use std::sync::OnceLock;
+
+use crate::{interval::Interval, vec3::Vec3};
 
 
 pub type Color = Vec3;
 
-pub fn color_to_string(pixel_color: Color) -> String {
-    let r = linear_to_gamma(pixel_color.x());
-    let g = linear_to_gamma(pixel_color.y());
-    let b = linear_to_gamma(pixel_color.z());
+// Relative luminance of a linear Rec.709 color
+pub fn luminance(c: Color) -> f64 {
+    0.2126 * c.x() + 0.7152 * c.y() + 0.0722 * c.z()
+}
 
+// Quantizes a display encoded color to 8 bits per channel
+pub fn to_rgb8(pixel_color: Color) -> [u8; 3] {
     let intensity = Interval::new(0.000, 0.999);
-    let rbyte: u8 = (255.999 * intensity.clamp(r)) as u8;
-    let gbyte: u8 = (255.999 * intensity.clamp(g)) as u8;
-    let bbyte: u8 = (255.999 * intensity.clamp(b)) as u8;
+    let rbyte: u8 = (255.999 * intensity.clamp(pixel_color.x())) as u8;
+    let gbyte: u8 = (255.999 * intensity.clamp(pixel_color.y())) as u8;
+    let bbyte: u8 = (255.999 * intensity.clamp(pixel_color.z())) as u8;
+    [rbyte, gbyte, bbyte]
+}
 
+pub fn color_to_string(pixel_color: Color) -> String {
+    let [rbyte, gbyte, bbyte] = to_rgb8(pixel_color);
     format!("{} {} {}\n", rbyte, gbyte, bbyte)
 }
 
-fn linear_to_gamma(linear_component: f64) -> f64 {
-    if linear_component > 0.0 {
-        return linear_component.sqrt()
+// The piecewise sRGB curve, linear light to encoded value
+pub fn srgb_oetf(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        return 12.92 * linear
+    }
+    1.055 * linear.powf(1.0 / 2.4) - 0.055
+}
+
+pub fn srgb_eotf(encoded: f64) -> f64 {
+    if encoded <= 0.04045 {
+        return encoded / 12.92
+    }
+    ((encoded + 0.055) / 1.055).powf(2.4)
+}
+
+// How values are encoded for storage or display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transfer {
+    Linear,
+    #[default]
+    Srgb,
+    // The ITU-R BT.709 camera curve, which Rec.2020 uses as well
+    Rec709,
+}
+
+impl Transfer {
+    pub fn encode(&self, linear: f64) -> f64 {
+        match self {
+            Transfer::Linear => linear,
+            Transfer::Srgb => srgb_oetf(linear),
+            Transfer::Rec709 if linear < 0.018 => 4.5 * linear,
+            Transfer::Rec709 => 1.099 * linear.powf(0.45) - 0.099,
+        }
+    }
+
+    pub fn decode(&self, encoded: f64) -> f64 {
+        match self {
+            Transfer::Linear => encoded,
+            Transfer::Srgb => srgb_eotf(encoded),
+            Transfer::Rec709 if encoded < 0.081 => encoded / 4.5,
+            Transfer::Rec709 => ((encoded + 0.099) / 1.099).powf(1.0 / 0.45),
+        }
+    }
+
+    pub fn encode_color(&self, c: Color) -> Color {
+        Color::new(self.encode(c.x()), self.encode(c.y()), self.encode(c.z()))
+    }
+
+    pub fn decode_color(&self, c: Color) -> Color {
+        Color::new(self.decode(c.x()), self.decode(c.y()), self.decode(c.z()))
+    }
+}
+
+// Linear RGB spaces the renderer can shade in and write out. Plain `Color` values in
+// scenes are linear sRGB and get converted when the working space differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    // Rec.709 primaries with a D65 white
+    #[default]
+    LinearSrgb,
+    // ACES AP1 primaries with the ACES white, the usual space for rendering in ACES
+    AcesCg,
+    // Wide gamut UHDTV primaries with a D65 white
+    Rec2020,
+}
+
+const COLOR_SPACES: [ColorSpace; 3] = [ColorSpace::LinearSrgb, ColorSpace::AcesCg, ColorSpace::Rec2020];
+
+impl ColorSpace {
+    // Chromaticities of the red, green and blue primaries and of white
+    fn chromaticities(&self) -> [(f64, f64); 4] {
+        match self {
+            ColorSpace::LinearSrgb => [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06), D65_WHITE],
+            ColorSpace::AcesCg => [(0.713, 0.293), (0.165, 0.830), (0.128, 0.044), (0.32168, 0.33767)],
+            ColorSpace::Rec2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046), D65_WHITE],
+        }
+    }
+
+    // To CIE XYZ, with the space's white adapted to D65 so all spaces share one XYZ
+    pub fn to_xyz(&self) -> Mat3 {
+        let [r, g, b, white] = self.chromaticities();
+        let primaries = transpose(&[xy_to_xyz(r), xy_to_xyz(g), xy_to_xyz(b)]);
+        // Scale the primaries so RGB (1, 1, 1) lands on the white point
+        let s = mat3_mul(&mat3_inverse(&primaries), xy_to_xyz(white));
+        let mut m = primaries;
+        for row in m.iter_mut() {
+            row[0] *= s.x();
+            row[1] *= s.y();
+            row[2] *= s.z();
+        }
+        if white == D65_WHITE {
+            return m;
+        }
+        mat3_product(&bradford_adaptation(xy_to_xyz(white), xy_to_xyz(D65_WHITE)), &m)
+    }
+
+    pub fn from_xyz(&self) -> Mat3 {
+        mat3_inverse(&self.to_xyz())
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            ColorSpace::LinearSrgb => 0,
+            ColorSpace::AcesCg => 1,
+            ColorSpace::Rec2020 => 2,
+        }
+    }
+}
+
+const D65_WHITE: (f64, f64) = (0.3127, 0.3290);
+
+// Converts a linear color between spaces
+pub fn convert(c: Color, from: ColorSpace, to: ColorSpace) -> Color {
+    if from == to {
+        return c;
+    }
+    static MATRICES: OnceLock<[[Mat3; 3]; 3]> = OnceLock::new();
+    let matrices = MATRICES.get_or_init(|| {
+        COLOR_SPACES.map(|from| COLOR_SPACES.map(|to| mat3_product(&to.from_xyz(), &from.to_xyz())))
+    });
+    mat3_mul(&matrices[from.index()][to.index()], c)
+}
+
+// A scene color converted up front into every space the renderer can shade in, so looking
+// it up during a render costs nothing whichever working space the camera picked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorkingColor([Color; 3]);
+
+impl WorkingColor {
+    pub fn new(c: Color, space: ColorSpace) -> Self {
+        Self(COLOR_SPACES.map(|to| convert(c, space, to)))
+    }
+
+    // Plain scene colors are linear sRGB
+    pub fn from_linear_srgb(c: Color) -> Self {
+        Self::new(c, ColorSpace::LinearSrgb)
+    }
+
+    pub fn get(&self, space: ColorSpace) -> Color {
+        self.0[space.index()]
+    }
+}
+
+// XYZ with unit luminance for a chromaticity
+pub fn xy_to_xyz((x, y): (f64, f64)) -> Vec3 {
+    Vec3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+const BRADFORD: Mat3 = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+// Von Kries adaptation in the Bradford cone space, taking XYZ under `from` to XYZ under `to`
+pub fn bradford_adaptation(from: Vec3, to: Vec3) -> Mat3 {
+    let source = mat3_mul(&BRADFORD, from);
+    let target = mat3_mul(&BRADFORD, to);
+    let gains = [target.x() / source.x(), target.y() / source.y(), target.z() / source.z()];
+    let mut scaled = BRADFORD;
+    for (row, gain) in scaled.iter_mut().zip(gains) {
+        for value in row.iter_mut() {
+            *value *= gain;
+        }
+    }
+    mat3_product(&mat3_inverse(&BRADFORD), &scaled)
+}
+
+pub type Mat3 = [[f64; 3]; 3];
+
+pub fn mat3_mul(m: &Mat3, v: Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x() + m[0][1] * v.y() + m[0][2] * v.z(),
+        m[1][0] * v.x() + m[1][1] * v.y() + m[1][2] * v.z(),
+        m[2][0] * v.x() + m[2][1] * v.y() + m[2][2] * v.z(),
+    )
+}
+
+pub fn mat3_product(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
+        }
+    }
+    m
+}
+
+fn transpose(m: &[Vec3; 3]) -> Mat3 {
+    [
+        [m[0].x(), m[1].x(), m[2].x()],
+        [m[0].y(), m[1].y(), m[2].y()],
+        [m[0].z(), m[1].z(), m[2].z()],
+    ]
+}
+
+pub fn mat3_inverse(m: &Mat3) -> Mat3 {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let inv_det = 1.0 / det;
+    [
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det,
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det,
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det,
+        ],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Color, b: Color, tolerance: f64) {
+        assert!((a - b).length() < tolerance, "{:?} != {:?}", a, b);
     }
-    return 0.0
-}
\ No newline at end of file
+
+    #[test]
+    fn xyz_matrices_invert_each_other() {
+        for space in COLOR_SPACES {
+            let identity = mat3_product(&space.from_xyz(), &space.to_xyz());
+            for (i, row) in identity.iter().enumerate() {
+                for (j, &value) in row.iter().enumerate() {
+                    assert!((value - if i == j { 1.0 } else { 0.0 }).abs() < 1e-12, "{:?}", space);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conversions_round_trip_and_keep_white() {
+        let c = Color::new(0.8, 0.3, 0.05);
+        for from in COLOR_SPACES {
+            for to in COLOR_SPACES {
+                assert_near(convert(convert(c, from, to), to, from), c, 1e-12);
+                assert_near(convert(Color::new(1.0, 1.0, 1.0), from, to), Color::new(1.0, 1.0, 1.0), 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_matches_the_published_matrix() {
+        let m = ColorSpace::LinearSrgb.to_xyz();
+        let published = [[0.4124, 0.3576, 0.1805], [0.2126, 0.7152, 0.0722], [0.0193, 0.1192, 0.9505]];
+        for (row, expected) in m.iter().zip(published) {
+            for (value, expected) in row.iter().zip(expected) {
+                assert!((value - expected).abs() < 5e-4, "{:?}", m);
+            }
+        }
+    }
+
+    #[test]
+    fn transfers_decode_what_they_encode() {
+        for transfer in [Transfer::Linear, Transfer::Srgb, Transfer::Rec709] {
+            assert_eq!(transfer.encode(0.0), 0.0);
+            assert!((transfer.encode(1.0) - 1.0).abs() < 1e-3, "{:?}", transfer);
+            let mut previous = -1.0;
+            for k in 0..=1000 {
+                let linear = k as f64 / 1000.0;
+                let encoded = transfer.encode(linear);
+                assert!(encoded > previous, "{:?} isn't increasing at {}", transfer, linear);
+                assert!((transfer.decode(encoded) - linear).abs() < 1e-9, "{:?} at {}", transfer, linear);
+                previous = encoded;
+            }
+        }
+        // The pieces of the curves meet where they switch over, up to the rounding of the
+        // published constants which leaves Rec. 709 a step of about 3e-4
+        assert!((srgb_oetf(0.0031308) - 0.04045).abs() < 1e-5);
+        assert!((Transfer::Rec709.encode(0.018 - 1e-12) - Transfer::Rec709.encode(0.018)).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::exr::ExrChannel;
+
+// MurmurHash3, x86 32-bit variant
+pub fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        h ^= mix(u32::from_le_bytes([block[0], block[1], block[2], block[3]]));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail.iter().rev().fold(0u32, |k, &byte| (k << 8) | byte as u32);
+        h ^= mix(k);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+// Bits of the float a name is stored as: its hash with the exponent moved off 0 and 255,
+// so no ID is a denormal, infinity or NaN
+pub fn name_hash(name: &str) -> u32 {
+    let hash = murmur3_32(name.as_bytes(), 0);
+    let exponent = (hash >> 23) & 0xff;
+    if exponent == 0 || exponent == 0xff {
+        return hash ^ (1 << 23);
+    }
+    hash
+}
+
+pub fn name_to_float(name: &str) -> f32 {
+    f32::from_bits(name_hash(name))
+}
+
+// How many of a pixel's samples hit each object and material first
+#[derive(Debug, Clone, Default)]
+pub struct PixelCoverage {
+    pub samples: u32,
+    // (ID, samples), IDs of 0 are left out
+    pub objects: Vec<(u32, u32)>,
+    pub materials: Vec<(u32, u32)>,
+}
+
+impl PixelCoverage {
+    // Fraction of the pixel each ID covers, largest first
+    pub fn ranked(&self, counts: &[(u32, u32)]) -> Vec<(u32, f64)> {
+        let mut ranked: Vec<(u32, f64)> = counts.iter().map(|&(id, n)| (id, n as f64 / self.samples.max(1) as f64)).collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+}
+
+// IDs a pixel keeps counts for. Samples of any further IDs still count towards the pixel's
+// total, so the IDs it does keep cover what they really cover.
+pub const COVERAGE_SLOTS: usize = 8;
+
+// A pixel's coverage, counted without locks by whichever threads render it. Each slot
+// packs an ID in the high half and its sample count in the low half, 0 while unclaimed.
+#[derive(Default)]
+pub struct CoverageCell {
+    samples: AtomicU32,
+    objects: [AtomicU64; COVERAGE_SLOTS],
+    materials: [AtomicU64; COVERAGE_SLOTS],
+}
+
+impl CoverageCell {
+    // IDs of 0 count as a sample that hit nothing
+    pub fn add(&self, object_id: u32, material_id: u32) {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        count(&self.objects, object_id);
+        count(&self.materials, material_id);
+    }
+
+    pub fn load(&self) -> PixelCoverage {
+        PixelCoverage {
+            samples: self.samples.load(Ordering::Relaxed),
+            objects: counts(&self.objects),
+            materials: counts(&self.materials),
+        }
+    }
+
+    // Keeps the first `COVERAGE_SLOTS` IDs of each list
+    pub fn store(&self, coverage: PixelCoverage) {
+        self.samples.store(coverage.samples, Ordering::Relaxed);
+        for (slots, counts) in [(&self.objects, &coverage.objects), (&self.materials, &coverage.materials)] {
+            for (index, slot) in slots.iter().enumerate() {
+                let packed = counts.get(index).map_or(0, |&(id, n)| (id as u64) << 32 | n as u64);
+                slot.store(packed, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Bumps the slot holding `id`, claiming the first free one if none does yet
+fn count(slots: &[AtomicU64], id: u32) {
+    if id == 0 {
+        return;
+    }
+    for slot in slots {
+        let mut packed = slot.load(Ordering::Relaxed);
+        if packed == 0 {
+            match slot.compare_exchange(0, (id as u64) << 32 | 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return,
+                // Another thread took it first, maybe for this same ID
+                Err(claimed) => packed = claimed,
+            }
+        }
+        if (packed >> 32) as u32 == id {
+            slot.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+fn counts(slots: &[AtomicU64]) -> Vec<(u32, u32)> {
+    slots
+        .iter()
+        .map(|slot| slot.load(Ordering::Relaxed))
+        .take_while(|&packed| packed != 0)
+        .map(|packed| ((packed >> 32) as u32, packed as u32))
+        .collect()
+}
+
+// EXR channels and header attributes of one Cryptomatte layer, e.g. "CryptoObject". Each
+// pixel keeps its `ranks` best covered IDs, two to an RGBA channel set: hash and coverage
+// of one in R and G, of the next in B and A. `pixels` holds each pixel's ranked coverage
+// and `name` turns IDs into the names that get hashed.
+pub fn cryptomatte_layer(layer: &str, ranks: usize, pixels: &[Vec<(u32, f64)>], name: impl Fn(u32) -> String) -> (Vec<ExrChannel>, Vec<(String, String)>) {
+    let sets = ranks.div_ceil(2).max(1);
+    let mut names = BTreeMap::new();
+    let mut hash = |id: u32| names.entry(id).or_insert_with(|| {
+        let name = name(id);
+        (name_hash(&name), name)
+    }).0;
+
+    let mut values = vec![vec![0.0f32; pixels.len()]; sets * 4];
+    for (p, ranked) in pixels.iter().enumerate() {
+        for (rank, &(id, coverage)) in ranked.iter().take(ranks).enumerate() {
+            let channel = (rank / 2) * 4 + (rank % 2) * 2;
+            values[channel][p] = f32::from_bits(hash(id));
+            values[channel + 1][p] = coverage as f32;
+        }
+    }
+    let channels = values
+        .into_iter()
+        .enumerate()
+        .map(|(index, values)| ExrChannel::float(format!("{}{:02}.{}", layer, index / 4, ["R", "G", "B", "A"][index % 4]), values))
+        .collect();
+
+    // Readers find the layer's metadata under a key made from its name's hash
+    let key = &format!("{:08x}", murmur3_32(layer.as_bytes(), 0))[..7];
+    let manifest: BTreeMap<&str, u32> = names.values().map(|(hash, name)| (name.as_str(), *hash)).collect();
+    let manifest = manifest.iter().map(|(name, hash)| format!("\"{}\":\"{:08x}\"", json_escape(name), hash)).collect::<Vec<_>>().join(",");
+    let attributes = vec![
+        (format!("cryptomatte/{}/name", key), layer.to_string()),
+        (format!("cryptomatte/{}/hash", key), String::from("MurmurHash3_32")),
+        (format!("cryptomatte/{}/conversion", key), String::from("uint32_to_float32")),
+        (format!("cryptomatte/{}/manifest", key), format!("{{{}}}", manifest)),
+    ];
+    (channels, attributes)
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn coverage_counts_from_many_threads() {
+        let cell = CoverageCell::default();
+        thread::scope(|scope| {
+            for t in 0..4u32 {
+                let cell = &cell;
+                scope.spawn(move || {
+                    for i in 0..1000u32 {
+                        cell.add(1 + (i + t) % 3, if i % 2 == 0 { 0 } else { 7 });
+                    }
+                });
+            }
+        });
+        let coverage = cell.load();
+        assert_eq!(coverage.samples, 4000);
+        let mut objects = coverage.objects.clone();
+        objects.sort();
+        assert_eq!(objects, vec![(1, 1334), (2, 1333), (3, 1333)]);
+        assert_eq!(coverage.materials, vec![(7, 2000)]);
+        assert_eq!(coverage.ranked(&coverage.materials), vec![(7, 0.5)]);
+    }
+
+    #[test]
+    fn coverage_keeps_a_fixed_number_of_ids() {
+        let cell = CoverageCell::default();
+        for id in 1..=COVERAGE_SLOTS as u32 + 4 {
+            cell.add(id, 1);
+        }
+        cell.add(1, 1);
+        let coverage = cell.load();
+        assert_eq!(coverage.samples, COVERAGE_SLOTS as u32 + 5);
+        assert_eq!(coverage.objects.len(), COVERAGE_SLOTS);
+        assert_eq!(coverage.objects[0], (1, 2));
+        assert_eq!(coverage.materials, vec![(1, COVERAGE_SLOTS as u32 + 5)]);
+
+        let copy = CoverageCell::default();
+        copy.store(coverage.clone());
+        assert_eq!(format!("{:?}", copy.load()), format!("{:?}", coverage));
+    }
+}
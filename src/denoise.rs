@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::color::{Color, Transfer};
+use crate::framebuffer::{read_ppm, write_ppm, FeatureBuffers};
+
+// Joint bilateral filter for noisy low sample renders. Each pixel becomes a weighted mean of
+// its neighborhood, where neighbors lose weight with distance and with how much their color,
+// albedo, normal and depth differ. The feature buffers are nearly noise free even at a few
+// samples per pixel, so they keep edges and texture detail that the noisy color alone would
+// blur away. Without them the filter falls back to a plain bilateral on color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Denoiser {
+    // 0 leaves the image alone, 1 is a good default for 16 samples per pixel. Scales the
+    // filter's footprint and how different colors may be and still blend.
+    pub strength: f64,
+    // Gaussian widths of each guide. Colors are compared after compressing their range, so
+    // fireflies don't stand out as edges.
+    pub sigma_color: f64,
+    pub sigma_albedo: f64,
+    pub sigma_normal: f64,
+    // Relative to the pixel's own depth
+    pub sigma_depth: f64,
+}
+
+// Spatial Gaussian width in pixels at strength 1
+const SIGMA_SPATIAL: f64 = 2.5;
+// Largest footprint radius, keeps very high strengths from getting too slow
+const MAX_RADIUS: i64 = 12;
+
+impl Denoiser {
+    pub fn new(strength: f64) -> Self {
+        Self { strength, sigma_color: 0.15, sigma_albedo: 0.1, sigma_normal: 0.25, sigma_depth: 0.05 }
+    }
+
+    pub fn with_sigmas(mut self, color: f64, albedo: f64, normal: f64, depth: f64) -> Self {
+        self.sigma_color = color;
+        self.sigma_albedo = albedo;
+        self.sigma_normal = normal;
+        self.sigma_depth = depth;
+        self
+    }
+
+    // Filters a linear image of `width` x `height`, row by row from the top. `features` have
+    // to match its size.
+    pub fn denoise(&self, width: usize, height: usize, image: &[Color], features: Option<&FeatureBuffers>) -> Vec<Color> {
+        if self.strength <= 0.0 || image.is_empty() {
+            return image.to_vec();
+        }
+        let features = features.filter(|f| f.width == width && f.height == height);
+
+        let sigma_spatial = SIGMA_SPATIAL * self.strength;
+        let radius = ((2.0 * sigma_spatial).ceil() as i64).min(MAX_RADIUS);
+        let sigma_color = self.sigma_color * self.strength;
+        let compressed: Vec<Color> = image.iter().map(|&c| compress(c)).collect();
+
+        (0..height)
+            .into_par_iter()
+            .flat_map_iter(|j| {
+                let compressed = &compressed;
+                (0..width).map(move |i| {
+                    let p = j * width + i;
+                    let mut sum = Color::new(0.0, 0.0, 0.0);
+                    let mut total = 0.0;
+                    for dj in -radius..=radius {
+                        let y = j as i64 + dj;
+                        if y < 0 || y >= height as i64 {
+                            continue;
+                        }
+                        for di in -radius..=radius {
+                            let x = i as i64 + di;
+                            if x < 0 || x >= width as i64 {
+                                continue;
+                            }
+                            let q = y as usize * width + x as usize;
+                            let mut exponent = (di * di + dj * dj) as f64 / (2.0 * sigma_spatial * sigma_spatial);
+                            exponent += (compressed[p] - compressed[q]).length_squared() / (2.0 * sigma_color * sigma_color);
+                            if let Some(f) = features {
+                                exponent += self.feature_distance(f, p, q);
+                            }
+                            let weight = (-exponent).exp();
+                            sum += image[q] * weight;
+                            total += weight;
+                        }
+                    }
+                    sum / total
+                })
+            })
+            .collect()
+    }
+
+    fn feature_distance(&self, f: &FeatureBuffers, p: usize, q: usize) -> f64 {
+        let albedo = (f.albedo[p] - f.albedo[q]).length_squared();
+        let normal = (f.normal[p] - f.normal[q]).length_squared();
+        let depth = (f.depth[p] - f.depth[q]) / f.depth[p].max(f.depth[q]).max(1e-6);
+        albedo / (2.0 * self.sigma_albedo * self.sigma_albedo)
+            + normal / (2.0 * self.sigma_normal * self.sigma_normal)
+            + depth * depth / (2.0 * self.sigma_depth * self.sigma_depth)
+    }
+
+    // Denoises a saved 8-bit sRGB PPM into `output`, guided by color alone
+    pub fn denoise_ppm(&self, input: &Path, output: &Path) -> io::Result<()> {
+        let (width, height, pixels) = read_ppm(input)?;
+        let linear: Vec<Color> = pixels.into_iter().map(|c| Transfer::Srgb.decode_color(c)).collect();
+        let denoised = self.denoise(width, height, &linear, None);
+        let mut out = BufWriter::new(File::create(output)?);
+        let comment = format!("denoised, strength {}", self.strength);
+        write_ppm(&mut out, width, height, &comment, denoised.into_iter().map(|c| Transfer::Srgb.encode_color(c)))
+    }
+}
+
+// Brings radiance into a range where differences look about as big as they do on screen
+fn compress(c: Color) -> Color {
+    let f = |x: f64| (x.max(0.0) / (1.0 + x.max(0.0))).sqrt();
+    Color::new(f(c.x()), f(c.y()), f(c.z()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::{Point3, Vec3};
+
+    const SIZE: usize = 16;
+
+    fn grey(l: f64) -> Color {
+        Color::new(l, l, l)
+    }
+
+    // Left half at 0.2 and right half at 0.3, with a deterministic ±0.1 of noise per pixel
+    fn noisy_step() -> Vec<Color> {
+        (0..SIZE * SIZE)
+            .map(|p| {
+                let base = if p % SIZE < SIZE / 2 { 0.2 } else { 0.3 };
+                grey(base + ((p * 7919) % 13) as f64 / 60.0 - 0.1)
+            })
+            .collect()
+    }
+
+    // Albedo changes where the image does, everything else is flat
+    fn step_features(size: usize) -> FeatureBuffers {
+        let n = size * size;
+        FeatureBuffers {
+            width: size,
+            height: size,
+            albedo: (0..n).map(|p| grey(if p % size < size / 2 { 0.2 } else { 0.8 })).collect(),
+            normal: vec![Vec3::new(0.0, 1.0, 0.0); n],
+            depth: vec![1.0; n],
+            position: vec![Point3::new(0.0, 0.0, 0.0); n],
+            material_id: vec![0; n],
+            object_id: vec![0; n],
+        }
+    }
+
+    // How far the columns on either side of the edge have been pulled towards each other
+    fn edge_blur(image: &[Color]) -> f64 {
+        let column_mean = |i: usize| (0..SIZE).map(|j| image[j * SIZE + i].x()).sum::<f64>() / SIZE as f64;
+        0.1 - (column_mean(SIZE / 2) - column_mean(SIZE / 2 - 1))
+    }
+
+    // Mean distance from the true value away from the edge
+    fn noise(image: &[Color]) -> f64 {
+        (0..SIZE).flat_map(|j| (0..4).map(move |i| j * SIZE + i)).map(|p| (image[p].x() - 0.2).abs()).sum::<f64>()
+            / (4 * SIZE) as f64
+    }
+
+    #[test]
+    fn zero_strength_leaves_the_image_alone() {
+        let image = noisy_step();
+        assert_eq!(Denoiser::new(0.0).denoise(SIZE, SIZE, &image, Some(&step_features(SIZE))), image);
+    }
+
+    #[test]
+    fn flat_images_stay_flat() {
+        let image = vec![grey(0.4); SIZE * SIZE];
+        for c in Denoiser::new(2.0).denoise(SIZE, SIZE, &image, Some(&step_features(SIZE))) {
+            assert!((c.x() - 0.4).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn features_keep_edges_the_noise_would_hide() {
+        let image = noisy_step();
+        let denoiser = Denoiser::new(1.0);
+        let guided = denoiser.denoise(SIZE, SIZE, &image, Some(&step_features(SIZE)));
+        let unguided = denoiser.denoise(SIZE, SIZE, &image, None);
+        assert!(noise(&guided) < 0.5 * noise(&image), "{} vs {}", noise(&guided), noise(&image));
+        assert!(edge_blur(&guided).abs() < 0.01, "{}", edge_blur(&guided));
+        assert!(edge_blur(&unguided) > 0.03, "{}", edge_blur(&unguided));
+
+        // Features of the wrong size are ignored rather than misread
+        assert_eq!(denoiser.denoise(SIZE, SIZE, &image, Some(&step_features(SIZE / 2))), unguided);
+    }
+}
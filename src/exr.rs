@@ -0,0 +1,285 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::tile::Tile;
+
+// Samples of one EXR channel, row by row from the top
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExrPixels {
+    Float(Vec<f32>),
+    // Exact integers, for IDs
+    Uint(Vec<u32>),
+}
+
+impl ExrPixels {
+    fn len(&self) -> usize {
+        match self {
+            ExrPixels::Float(values) => values.len(),
+            ExrPixels::Uint(values) => values.len(),
+        }
+    }
+
+    fn pixel_type(&self) -> i32 {
+        match self {
+            ExrPixels::Float(_) => 2,
+            ExrPixels::Uint(_) => 0,
+        }
+    }
+}
+
+// A named channel. Names like "albedo.R" put the channel in the "albedo" layer, bare "R",
+// "G", "B" and "A" are the main image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExrChannel {
+    pub name: String,
+    pub pixels: ExrPixels,
+}
+
+impl ExrChannel {
+    pub fn float(name: impl Into<String>, values: Vec<f32>) -> Self {
+        Self { name: name.into(), pixels: ExrPixels::Float(values) }
+    }
+
+    pub fn uint(name: impl Into<String>, values: Vec<u32>) -> Self {
+        Self { name: name.into(), pixels: ExrPixels::Uint(values) }
+    }
+}
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const VERSION: i32 = 2;
+// Set when some attribute or channel name is longer than 31 bytes
+const LONG_NAMES: i32 = 0x400;
+
+// Writes an uncompressed single part scanline OpenEXR file. `data_window` is where the
+// channels' pixels sit in a `display_size` frame, smaller than it for crop renders, and
+// every channel needs one value per pixel of it. `attributes` become extra string
+// attributes in the header.
+pub fn write_exr(path: &Path, data_window: Tile, display_size: (usize, usize), channels: &[ExrChannel], attributes: &[(String, String)]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_exr_to(&mut out, data_window, display_size, channels, attributes)?;
+    out.flush()
+}
+
+pub fn write_exr_to(out: &mut impl Write, data_window: Tile, display_size: (usize, usize), channels: &[ExrChannel], attributes: &[(String, String)]) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let (width, height) = (data_window.x1.saturating_sub(data_window.x0), data_window.y1.saturating_sub(data_window.y0));
+    if width == 0 || height == 0 || display_size.0 == 0 || display_size.1 == 0 {
+        return Err(invalid(String::from("image is empty")));
+    }
+    if let Some(channel) = channels.iter().find(|c| c.pixels.len() != width * height) {
+        return Err(invalid(format!("channel {} has {} values for {} pixels", channel.name, channel.pixels.len(), width * height)));
+    }
+    // Readers expect the channel list, and the data in each scanline, sorted by name
+    let mut channels: Vec<&ExrChannel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+    if channels.windows(2).any(|pair| pair[0].name == pair[1].name) {
+        return Err(invalid(String::from("channel names must be unique")));
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC);
+    let long_names = channels.iter().map(|c| c.name.as_str()).chain(attributes.iter().map(|(name, _)| name.as_str())).any(|name| name.len() > 31);
+    let version = if long_names { VERSION | LONG_NAMES } else { VERSION };
+    header.extend_from_slice(&version.to_le_bytes());
+
+    let mut chlist = Vec::new();
+    for channel in &channels {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&channel.pixels.pixel_type().to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+    attribute(&mut header, "channels", "chlist", &chlist);
+    attribute(&mut header, "compression", "compression", &[0]);
+
+    // Both windows are inclusive pixel bounds
+    let box2i = |x0: usize, y0: usize, x1: usize, y1: usize| -> Vec<u8> {
+        [x0 as i32, y0 as i32, x1 as i32 - 1, y1 as i32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect()
+    };
+    attribute(&mut header, "dataWindow", "box2i", &box2i(data_window.x0, data_window.y0, data_window.x1, data_window.y1));
+    attribute(&mut header, "displayWindow", "box2i", &box2i(0, 0, display_size.0, display_size.1));
+    // Increasing y
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0u8; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    for (name, value) in attributes {
+        attribute(&mut header, name, "string", value.as_bytes());
+    }
+    header.push(0);
+
+    // One scanline per chunk without compression. Each is its y in the frame, its byte count
+    // and then every channel's row in turn, all four byte values.
+    let line_size = channels.len() * width * 4;
+    let first_line = header.len() + height * 8;
+    out.write_all(&header)?;
+    for y in 0..height {
+        let offset = (first_line + y * (8 + line_size)) as u64;
+        out.write_all(&offset.to_le_bytes())?;
+    }
+    let mut line = Vec::with_capacity(8 + line_size);
+    for y in 0..height {
+        line.clear();
+        line.extend_from_slice(&((data_window.y0 + y) as i32).to_le_bytes());
+        line.extend_from_slice(&(line_size as i32).to_le_bytes());
+        let row = y * width..(y + 1) * width;
+        for channel in &channels {
+            match &channel.pixels {
+                ExrPixels::Float(values) => line.extend(values[row.clone()].iter().flat_map(|v| v.to_le_bytes())),
+                ExrPixels::Uint(values) => line.extend(values[row.clone()].iter().flat_map(|v| v.to_le_bytes())),
+            }
+        }
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Reader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl Reader<'_> {
+        fn bytes(&mut self, n: usize) -> &[u8] {
+            self.pos += n;
+            &self.data[self.pos - n..self.pos]
+        }
+
+        fn i32(&mut self) -> i32 {
+            i32::from_le_bytes(self.bytes(4).try_into().unwrap())
+        }
+
+        fn name(&mut self) -> String {
+            let end = self.pos + self.data[self.pos..].iter().position(|&b| b == 0).unwrap();
+            let name = String::from_utf8(self.data[self.pos..end].to_vec()).unwrap();
+            self.pos = end + 1;
+            name
+        }
+    }
+
+    type Attributes = HashMap<String, (String, Vec<u8>)>;
+    // Name, pixel type and raw values
+    type Channel = (String, i32, Vec<[u8; 4]>);
+
+    fn i32s(bytes: &[u8]) -> Vec<i32> {
+        bytes.chunks(4).map(|b| i32::from_le_bytes(b.try_into().unwrap())).collect()
+    }
+
+    // Reads the file back independently of the writer: version, header attributes by name, and
+    // every channel's values through the offset table
+    fn read(data: &[u8]) -> (i32, Attributes, Vec<Channel>) {
+        let mut r = Reader { data, pos: 0 };
+        assert_eq!(r.bytes(4), MAGIC);
+        let version = r.i32();
+        let mut attributes = HashMap::new();
+        loop {
+            let name = r.name();
+            if name.is_empty() {
+                break;
+            }
+            let kind = r.name();
+            let size = r.i32() as usize;
+            attributes.insert(name, (kind, r.bytes(size).to_vec()));
+        }
+
+        let mut channels = Vec::new();
+        let mut list = Reader { data: &attributes["channels"].1, pos: 0 };
+        loop {
+            let name = list.name();
+            if name.is_empty() {
+                break;
+            }
+            let pixel_type = list.i32();
+            assert_eq!(list.bytes(12), [0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+            channels.push((name, pixel_type, Vec::new()));
+        }
+
+        let window = i32s(&attributes["dataWindow"].1);
+        let (width, height) = ((window[2] - window[0] + 1) as usize, (window[3] - window[1] + 1) as usize);
+        let offsets: Vec<usize> = (0..height).map(|_| u64::from_le_bytes(r.bytes(8).try_into().unwrap()) as usize).collect();
+        for (y, offset) in offsets.into_iter().enumerate() {
+            let mut chunk = Reader { data, pos: offset };
+            assert_eq!(chunk.i32(), window[1] + y as i32);
+            assert_eq!(chunk.i32() as usize, channels.len() * width * 4);
+            for channel in &mut channels {
+                for _ in 0..width {
+                    channel.2.push(chunk.bytes(4).try_into().unwrap());
+                }
+            }
+            if y == height - 1 {
+                assert_eq!(chunk.pos, data.len());
+            }
+        }
+        (version, attributes, channels)
+    }
+
+    fn write(data_window: Tile, display_size: (usize, usize), channels: &[ExrChannel], attributes: &[(String, String)]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        write_exr_to(&mut data, data_window, display_size, channels, attributes)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn channels_read_back_sorted_with_their_values() {
+        let window = Tile { x0: 1, y0: 2, x1: 4, y1: 4 };
+        let red: Vec<f32> = (0..6).map(|v| v as f32 * 0.5).collect();
+        let ids: Vec<u32> = (0..6).map(|v| 1000 + v).collect();
+        let channels = [ExrChannel::float("R", red.clone()), ExrChannel::uint("id", ids.clone()), ExrChannel::float("B", vec![-1.0; 6])];
+        let extra = [(String::from("comment"), String::from("test render"))];
+        let (version, attributes, read_channels) = read(&write(window, (8, 5), &channels, &extra).unwrap());
+
+        assert_eq!(version, VERSION);
+        assert_eq!(i32s(&attributes["dataWindow"].1), [1, 2, 3, 3]);
+        assert_eq!(i32s(&attributes["displayWindow"].1), [0, 0, 7, 4]);
+        assert_eq!(attributes["compression"].1, [0]);
+        assert_eq!(attributes["comment"], (String::from("string"), b"test render".to_vec()));
+
+        let names: Vec<&str> = read_channels.iter().map(|c| c.0.as_str()).collect();
+        assert_eq!(names, ["B", "R", "id"]);
+        assert_eq!(read_channels[1].1, 2);
+        assert_eq!(read_channels[1].2.iter().map(|&b| f32::from_le_bytes(b)).collect::<Vec<_>>(), red);
+        assert_eq!(read_channels[2].1, 0);
+        assert_eq!(read_channels[2].2.iter().map(|&b| u32::from_le_bytes(b)).collect::<Vec<_>>(), ids);
+    }
+
+    #[test]
+    fn long_names_set_the_version_flag() {
+        let window = Tile { x0: 0, y0: 0, x1: 1, y1: 1 };
+        let name = "cryptomatte_material_layer.00.R";
+        assert_eq!(name.len(), 31);
+        let (version, _, _) = read(&write(window, (1, 1), &[ExrChannel::float(name, vec![0.0])], &[]).unwrap());
+        assert_eq!(version, VERSION);
+        let longer = format!("{}G", name);
+        let (version, _, channels) = read(&write(window, (1, 1), &[ExrChannel::float(longer.clone(), vec![0.0])], &[]).unwrap());
+        assert_eq!(version, VERSION | LONG_NAMES);
+        assert_eq!(channels[0].0, longer);
+    }
+
+    #[test]
+    fn bad_channel_sets_are_rejected() {
+        let window = Tile { x0: 0, y0: 0, x1: 2, y1: 2 };
+        let invalid = |result: io::Result<Vec<u8>>| result.err().map(|e| e.kind()) == Some(io::ErrorKind::InvalidInput);
+        assert!(invalid(write(window, (2, 2), &[ExrChannel::float("R", vec![0.0; 3])], &[])));
+        assert!(invalid(write(window, (2, 2), &[ExrChannel::float("R", vec![0.0; 4]), ExrChannel::uint("R", vec![0; 4])], &[])));
+        assert!(invalid(write(Tile { x0: 1, y0: 0, x1: 1, y1: 2 }, (2, 2), &[], &[])));
+    }
+}
@@ -0,0 +1,133 @@
+use std::f64::consts::PI;
+
+// Pixel reconstruction filters. Each is separable and its radius is in pixels, so a
+// sample contributes to every pixel whose center lies within `radius` of it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Box { radius: f64 },
+    Tent { radius: f64 },
+    Gaussian { radius: f64, sigma: f64 },
+    // b = c = 1/3 is the pair Mitchell and Netravali recommend
+    Mitchell { radius: f64, b: f64, c: f64 },
+    // Sinc windowed by a sinc stretched by `tau`, whose central lobe ends `tau` pixels out,
+    // and cut off at `radius`. With `tau` equal to `radius` this is the classic Lanczos filter.
+    Lanczos { radius: f64, tau: f64 },
+}
+
+impl Default for Filter {
+    // A box over the unit square, which is what plain per-pixel averaging does
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius, .. } => radius,
+        }
+    }
+
+    // Weight of a sample offset by (x, y) pixels from the pixel center
+    pub fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let r = self.radius();
+        // Half-open support so a sample exactly between two pixels only lands in one of them
+        if x < -r || x >= r {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { radius } => radius - x.abs(),
+            Filter::Gaussian { radius, sigma } => gaussian(x, sigma) - gaussian(radius, sigma),
+            Filter::Mitchell { radius, b, c } => mitchell_1d(2.0 * x / radius, b, c),
+            Filter::Lanczos { tau, .. } => sinc(x) * sinc(x / tau),
+        }
+    }
+}
+
+fn gaussian(x: f64, sigma: f64) -> f64 {
+    (-x * x / (2.0 * sigma * sigma)).exp()
+}
+
+// Mitchell-Netravali cubic over [-2, 2]
+fn mitchell_1d(x: f64, b: f64, c: f64) -> f64 {
+    let x = x.abs();
+    if x <= 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+    } else if x <= 2.0 {
+        ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::framebuffer::Film;
+
+    const FILTERS: [Filter; 5] = [
+        Filter::Box { radius: 0.5 },
+        Filter::Tent { radius: 1.0 },
+        Filter::Gaussian { radius: 1.5, sigma: 0.5 },
+        Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 },
+        Filter::Lanczos { radius: 3.0, tau: 3.0 },
+    ];
+
+    #[test]
+    fn filters_are_symmetric_and_vanish_outside_their_radius() {
+        for filter in FILTERS {
+            let r = filter.radius();
+            assert!(filter.evaluate(0.0, 0.0) > 0.0, "{:?}", filter);
+            for x in [0.1, 0.37, 0.5 * r, 0.9 * r] {
+                assert_eq!(filter.evaluate(x, 0.2), filter.evaluate(-x, 0.2), "{:?}", filter);
+                assert_eq!(filter.evaluate(x, 0.2), filter.evaluate(0.2, x), "{:?}", filter);
+            }
+            assert_eq!(filter.evaluate(r, 0.0), 0.0, "{:?}", filter);
+            assert_eq!(filter.evaluate(0.0, -r - 0.01), 0.0, "{:?}", filter);
+        }
+    }
+
+    // Pixels divide by the weight they got, so even filters with negative lobes leave a
+    // flat image flat
+    #[test]
+    fn film_reconstructs_a_constant_image_with_every_filter() {
+        let color = Color::new(0.25, 0.5, 2.0);
+        for filter in FILTERS {
+            let film = Film::new(8, 8, filter);
+            for sy in 0..32 {
+                for sx in 0..32 {
+                    film.add_sample((sx as f64 + 0.37) / 4.0, (sy as f64 + 0.71) / 4.0, color);
+                }
+            }
+            for j in 0..8 {
+                for i in 0..8 {
+                    assert!((film.pixel(i, j) - color).length() < 1e-9, "{:?} gave {:?} at ({}, {})", filter, film.pixel(i, j), i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mitchell_lobe_has_unit_area() {
+        let n = 4000;
+        let area: f64 = (0..n).map(|k| mitchell_1d(-2.0 + 4.0 * (k as f64 + 0.5) / n as f64, 1.0 / 3.0, 1.0 / 3.0)).sum::<f64>() * 4.0 / n as f64;
+        assert!((area - 1.0).abs() < 1e-6, "{}", area);
+    }
+}
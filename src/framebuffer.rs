@@ -0,0 +1,871 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::color::{color_to_string, luminance, Color};
+use crate::cryptomatte::{CoverageCell, PixelCoverage};
+use crate::filter::Filter;
+use crate::tonemap::DisplayTransform;
+use crate::vec3::{Point3, Vec3};
+
+// Per-pixel sample count and running luminance variance (Welford's online algorithm)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelStats {
+    pub count: u32,
+    pub mean: f64,
+    pub m2: f64,
+}
+
+impl PixelStats {
+    pub fn add(&mut self, sample: Color) {
+        let x = luminance(sample);
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY
+        }
+        self.m2 / (self.count - 1) as f64
+    }
+
+    // Standard error of the mean carried through a square root, d(sqrt x) = dx / (2 sqrt x), as
+    // a rough stand-in for how visible it is. The camera's display transform isn't applied,
+    // and means below 1e-4 count as 1e-4 so black pixels don't sample forever.
+    pub fn relative_error(&self) -> f64 {
+        let std_error = (self.variance() / self.count as f64).sqrt();
+        std_error / (2.0 * self.mean.max(1e-4).sqrt())
+    }
+}
+
+// Lock-free home for a pixel's statistics. Only the thread rendering the pixel's tile
+// updates it, so a plain load/modify/store is enough.
+#[derive(Default)]
+pub struct PixelStatsCell {
+    count: AtomicU32,
+    mean: AtomicU64,
+    m2: AtomicU64,
+}
+
+impl PixelStatsCell {
+    pub fn load(&self) -> PixelStats {
+        PixelStats {
+            count: self.count.load(Ordering::Relaxed),
+            mean: f64::from_bits(self.mean.load(Ordering::Relaxed)),
+            m2: f64::from_bits(self.m2.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn store(&self, stats: PixelStats) {
+        self.count.store(stats.count, Ordering::Relaxed);
+        self.mean.store(stats.mean.to_bits(), Ordering::Relaxed);
+        self.m2.store(stats.m2.to_bits(), Ordering::Relaxed);
+    }
+}
+
+// f64 that threads can add into without a lock
+#[derive(Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn add(&self, value: f64) {
+        let mut current = self.0.load(Ordering::Relaxed);
+        loop {
+            let next = (f64::from_bits(current) + value).to_bits();
+            match self.0.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct FilmPixel {
+    rgb: [AtomicF64; 3],
+    weight: AtomicF64,
+    // Unfiltered contributions from paths that reach the camera from the light side
+    splat: [AtomicF64; 3],
+}
+
+// Pixels are stored tile by tile so splats from one sample stay close together in memory
+pub const FILM_TILE_SIZE: usize = 16;
+
+// Filter-weighted radiance accumulation. Samples are splatted into every pixel their
+// filter overlaps; a pixel's value is its weighted sum divided by its total weight.
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub filter: Filter,
+    tiles_x: usize,
+    pixels: Vec<FilmPixel>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        let tiles_x = width.div_ceil(FILM_TILE_SIZE);
+        let tiles_y = height.div_ceil(FILM_TILE_SIZE);
+        let mut pixels = Vec::with_capacity(tiles_x * tiles_y * FILM_TILE_SIZE * FILM_TILE_SIZE);
+        pixels.resize_with(tiles_x * tiles_y * FILM_TILE_SIZE * FILM_TILE_SIZE, FilmPixel::default);
+        Self { width, height, filter, tiles_x, pixels }
+    }
+
+    fn index(&self, i: usize, j: usize) -> usize {
+        let tile = (j / FILM_TILE_SIZE) * self.tiles_x + i / FILM_TILE_SIZE;
+        tile * FILM_TILE_SIZE * FILM_TILE_SIZE + (j % FILM_TILE_SIZE) * FILM_TILE_SIZE + i % FILM_TILE_SIZE
+    }
+
+    // (x, y) is the sample position in continuous pixel coordinates, pixel (i, j) covers [i, i+1) x [j, j+1)
+    pub fn add_sample(&self, x: f64, y: f64, color: Color) {
+        let radius = self.filter.radius();
+        let i_min = (x - 0.5 - radius).ceil().max(0.0) as usize;
+        let j_min = (y - 0.5 - radius).ceil().max(0.0) as usize;
+        let i_max = ((x - 0.5 + radius).floor() as i64).min(self.width as i64 - 1);
+        let j_max = ((y - 0.5 + radius).floor() as i64).min(self.height as i64 - 1);
+        if i_max < 0 || j_max < 0 {
+            return;
+        }
+
+        for j in j_min..=j_max as usize {
+            for i in i_min..=i_max as usize {
+                let weight = self.filter.evaluate(i as f64 + 0.5 - x, j as f64 + 0.5 - y);
+                if weight == 0.0 {
+                    continue;
+                }
+                let pixel = &self.pixels[self.index(i, j)];
+                pixel.rgb[0].add(color.x() * weight);
+                pixel.rgb[1].add(color.y() * weight);
+                pixel.rgb[2].add(color.z() * weight);
+                pixel.weight.add(weight);
+            }
+        }
+    }
+
+    pub fn pixel(&self, i: usize, j: usize) -> Color {
+        let pixel = &self.pixels[self.index(i, j)];
+        let weight = pixel.weight.get();
+        if weight == 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        Color::new(pixel.rgb[0].get(), pixel.rgb[1].get(), pixel.rgb[2].get()) / weight
+    }
+
+    // Sum of the splats that landed in pixel (i, j)
+    pub fn splat(&self, i: usize, j: usize) -> Color {
+        let pixel = &self.pixels[self.index(i, j)];
+        Color::new(pixel.splat[0].get(), pixel.splat[1].get(), pixel.splat[2].get())
+    }
+
+    // Raw sums of every pixel, row by row: filtered RGB, weight, splatted RGB
+    fn save_state(&self, out: &mut CheckpointWriter) {
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let pixel = &self.pixels[self.index(i, j)];
+                for value in pixel.rgb.iter().chain([&pixel.weight]).chain(&pixel.splat) {
+                    out.f64(value.get());
+                }
+            }
+        }
+    }
+
+    fn load_state(&self, values: &[f64]) {
+        let mut values = values.chunks_exact(FILM_PIXEL_VALUES);
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let (pixel, saved) = (&self.pixels[self.index(i, j)], values.next().unwrap());
+                for (value, &saved) in pixel.rgb.iter().chain([&pixel.weight]).chain(&pixel.splat).zip(saved) {
+                    value.set(saved);
+                }
+            }
+        }
+    }
+}
+
+// Values `Film::save_state` writes per pixel
+const FILM_PIXEL_VALUES: usize = 7;
+
+// Takes splats in full frame raster coordinates for a film that only holds a window of the
+// frame starting at `origin`
+pub struct WindowedSplats<'a> {
+    pub film: &'a Film,
+    pub origin: (usize, usize),
+}
+
+impl SplatSink for WindowedSplats<'_> {
+    fn add_splat(&self, x: f64, y: f64, color: Color) {
+        self.film.add_splat(x - self.origin.0 as f64, y - self.origin.1 as f64, color);
+    }
+}
+
+// Receives contributions that land somewhere on the image other than the pixel being sampled
+pub trait SplatSink: Sync {
+    fn add_splat(&self, x: f64, y: f64, color: Color);
+}
+
+impl SplatSink for Film {
+    // Adds `color` to the pixel containing (x, y) as is, for light tracing style estimators
+    // whose samples don't belong to the pixel that started the path
+    fn add_splat(&self, x: f64, y: f64, color: Color) {
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            return;
+        }
+        let pixel = &self.pixels[self.index(x as usize, y as usize)];
+        pixel.splat[0].add(color.x());
+        pixel.splat[1].add(color.y());
+        pixel.splat[2].add(color.z());
+    }
+}
+
+// What the camera ray of one sample hit first: the guides a denoiser works from and the
+// geometry passes written next to the image
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FeatureSample {
+    pub albedo: Color,
+    // World space, facing the camera. Zero where the ray escaped.
+    pub normal: Vec3,
+    // Distance along the ray, zero where it escaped
+    pub depth: f64,
+    // World space hit point, zero where the ray escaped
+    pub position: Point3,
+    // 0 where the ray escaped
+    pub material_id: u32,
+    pub object_id: u32,
+}
+
+// Running sums of a pixel's feature samples. IDs can't be averaged, so the pixel keeps
+// those of its first sample.
+#[derive(Default)]
+pub struct FeatureCell {
+    albedo: [AtomicF64; 3],
+    normal: [AtomicF64; 3],
+    depth: AtomicF64,
+    position: [AtomicF64; 3],
+    material_id: AtomicU32,
+    object_id: AtomicU32,
+    count: AtomicU32,
+}
+
+impl FeatureCell {
+    pub fn add(&self, sample: FeatureSample) {
+        for axis in 0..3 {
+            self.albedo[axis].add(sample.albedo.e[axis]);
+            self.normal[axis].add(sample.normal.e[axis]);
+            self.position[axis].add(sample.position.e[axis]);
+        }
+        self.depth.add(sample.depth);
+        if self.count.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.material_id.store(sample.material_id, Ordering::Relaxed);
+            self.object_id.store(sample.object_id, Ordering::Relaxed);
+        }
+    }
+
+    // Mean over the samples taken so far. Normals are left unnormalized, so they shrink
+    // where a pixel straddles an edge.
+    pub fn load(&self) -> FeatureSample {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return FeatureSample::default();
+        }
+        let sum = |values: &[AtomicF64; 3]| Vec3::new(values[0].get(), values[1].get(), values[2].get());
+        FeatureSample {
+            albedo: sum(&self.albedo) / count as f64,
+            normal: sum(&self.normal) / count as f64,
+            depth: self.depth.get() / count as f64,
+            position: sum(&self.position) / count as f64,
+            material_id: self.material_id.load(Ordering::Relaxed),
+            object_id: self.object_id.load(Ordering::Relaxed),
+        }
+    }
+
+    fn save_state(&self, out: &mut CheckpointWriter) {
+        for value in self.albedo.iter().chain(&self.normal).chain([&self.depth]).chain(&self.position) {
+            out.f64(value.get());
+        }
+        out.u32(self.material_id.load(Ordering::Relaxed));
+        out.u32(self.object_id.load(Ordering::Relaxed));
+        out.u32(self.count.load(Ordering::Relaxed));
+    }
+
+    fn load_state(&self, input: &mut CheckpointReader) -> io::Result<()> {
+        for value in self.albedo.iter().chain(&self.normal).chain([&self.depth]).chain(&self.position) {
+            value.set(input.f64()?);
+        }
+        self.material_id.store(input.u32()?, Ordering::Relaxed);
+        self.object_id.store(input.u32()?, Ordering::Relaxed);
+        self.count.store(input.u32()?, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// Per-pixel feature images in row-major order
+#[derive(Debug, Clone, Default)]
+pub struct FeatureBuffers {
+    pub width: usize,
+    pub height: usize,
+    pub albedo: Vec<Color>,
+    pub normal: Vec<Vec3>,
+    pub depth: Vec<f64>,
+    pub position: Vec<Point3>,
+    pub material_id: Vec<u32>,
+    pub object_id: Vec<u32>,
+}
+
+// The film plus per-pixel sample statistics, shared by all render threads
+pub struct FrameBuffer {
+    pub width: usize,
+    pub height: usize,
+    // Where the top left pixel sits in the full frame, when only a window of it is rendered
+    pub origin: (usize, usize),
+    // Width and height of the full frame, which splats are spread over
+    pub frame_size: (usize, usize),
+    pub film: Film,
+    pub stats: Vec<PixelStatsCell>,
+    // First hit features, only recorded when something needs them
+    pub features: Option<Vec<FeatureCell>>,
+    // Direct light alone, filtered like the film. Only kept when the light passes are written.
+    pub direct: Option<Film>,
+    // Which objects and materials each pixel's samples hit first, for ID mattes
+    pub coverage: Option<Vec<CoverageCell>>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        Self {
+            width,
+            height,
+            origin: (0, 0),
+            frame_size: (width, height),
+            film: Film::new(width, height, filter),
+            stats: (0..width * height).map(|_| PixelStatsCell::default()).collect(),
+            features: None,
+            direct: None,
+            coverage: None,
+        }
+    }
+
+    pub fn with_origin(mut self, x: usize, y: usize) -> Self {
+        self.origin = (x, y);
+        self
+    }
+
+    pub fn with_frame_size(mut self, width: usize, height: usize) -> Self {
+        self.frame_size = (width, height);
+        self
+    }
+
+    // Splat target for integrators, which place splats in full frame coordinates
+    pub fn splats(&self) -> WindowedSplats<'_> {
+        WindowedSplats { film: &self.film, origin: self.origin }
+    }
+
+    pub fn with_features(mut self) -> Self {
+        self.features = Some((0..self.width * self.height).map(|_| FeatureCell::default()).collect());
+        self
+    }
+
+    pub fn with_direct(mut self) -> Self {
+        self.direct = Some(Film::new(self.width, self.height, self.film.filter));
+        self
+    }
+
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = Some((0..self.width * self.height).map(|_| CoverageCell::default()).collect());
+        self
+    }
+
+    // Final color of one pixel. Prefer `resolve` when reading the whole image.
+    pub fn pixel(&self, i: usize, j: usize) -> Color {
+        self.film.pixel(i, j) + self.film.splat(i, j) * self.splat_scale()
+    }
+
+    // The whole image in row-major order
+    pub fn resolve(&self) -> Vec<Color> {
+        let splat_scale = self.splat_scale();
+        let mut colors = Vec::with_capacity(self.width * self.height);
+        for j in 0..self.height {
+            for i in 0..self.width {
+                colors.push(self.film.pixel(i, j) + self.film.splat(i, j) * splat_scale);
+            }
+        }
+        colors
+    }
+
+    // Every camera sample may splat anywhere in the full frame, so splats are averaged over
+    // the mean sample count rather than per pixel. In a crop window only the window's pixels
+    // take samples, but their splats still land all over the frame.
+    fn splat_scale(&self) -> f64 {
+        let samples: u64 = self.stats.iter().map(|s| s.load().count as u64).sum();
+        if samples == 0 {
+            return 0.0;
+        }
+        (self.frame_size.0 * self.frame_size.1) as f64 / samples as f64
+    }
+
+    pub fn pixel_stats(&self, i: usize, j: usize) -> PixelStats {
+        self.stats[j * self.width + i].load()
+    }
+
+    pub fn min_samples(&self) -> u32 {
+        self.stats.iter().map(|s| s.load().count).min().unwrap_or(0)
+    }
+
+    pub fn max_samples(&self) -> u32 {
+        self.stats.iter().map(|s| s.load().count).max().unwrap_or(0)
+    }
+
+    // The resolved image through `display`, the stored radiance stays linear
+    pub fn write_ppm(&self, out: &mut impl Write, display: &DisplayTransform) -> io::Result<()> {
+        self.write_image_ppm(out, &self.resolve(), display)
+    }
+
+    pub fn save_ppm(&self, path: &Path, display: &DisplayTransform) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_ppm(&mut out, display)
+    }
+
+    // Like `write_ppm` with `image` in place of the resolved film, e.g. a denoised copy of it
+    pub fn write_image_ppm(&self, out: &mut impl Write, image: &[Color], display: &DisplayTransform) -> io::Result<()> {
+        let comment = format!("samples per pixel: {}", self.min_samples());
+        write_ppm(out, self.width, self.height, &comment, image.iter().map(|&color| display.apply(color)))
+    }
+
+    // Averaged first hit features, if they were recorded
+    pub fn feature_buffers(&self) -> Option<FeatureBuffers> {
+        let cells = self.features.as_ref()?;
+        let samples: Vec<FeatureSample> = cells.iter().map(FeatureCell::load).collect();
+        Some(FeatureBuffers {
+            width: self.width,
+            height: self.height,
+            albedo: samples.iter().map(|s| s.albedo).collect(),
+            normal: samples.iter().map(|s| s.normal).collect(),
+            depth: samples.iter().map(|s| s.depth).collect(),
+            position: samples.iter().map(|s| s.position).collect(),
+            material_id: samples.iter().map(|s| s.material_id).collect(),
+            object_id: samples.iter().map(|s| s.object_id).collect(),
+        })
+    }
+
+    // Direct and indirect light images, if direct light was recorded. Indirect light is
+    // whatever the full image holds beyond the direct part, splats included.
+    pub fn light_passes(&self) -> Option<(Vec<Color>, Vec<Color>)> {
+        let direct_film = self.direct.as_ref()?;
+        let mut direct = Vec::with_capacity(self.width * self.height);
+        for j in 0..self.height {
+            for i in 0..self.width {
+                direct.push(direct_film.pixel(i, j));
+            }
+        }
+        let indirect = self.resolve().iter().zip(&direct).map(|(&full, &direct)| full - direct).collect();
+        Some((direct, indirect))
+    }
+
+    pub fn pixel_coverage(&self) -> Option<Vec<PixelCoverage>> {
+        Some(self.coverage.as_ref()?.iter().map(CoverageCell::load).collect())
+    }
+
+    // Saves everything accumulated so far, so a later render of the same window can pick up
+    // from here: the film, the sample statistics, whichever passes are being recorded and
+    // how many render passes went into them
+    pub fn save_checkpoint(&self, path: &Path, render_passes: u32) -> io::Result<()> {
+        let mut out = CheckpointWriter::default();
+        out.bytes(CHECKPOINT_MAGIC);
+        for size in [self.width, self.height, self.origin.0, self.origin.1] {
+            out.u64(size as u64);
+        }
+        let mut sections = 0;
+        if self.direct.is_some() {
+            sections |= HAS_DIRECT;
+        }
+        if self.features.is_some() {
+            sections |= HAS_FEATURES;
+        }
+        if self.coverage.is_some() {
+            sections |= HAS_COVERAGE;
+        }
+        out.bytes(&[sections]);
+        out.u32(render_passes);
+
+        self.film.save_state(&mut out);
+        for cell in &self.stats {
+            let stats = cell.load();
+            out.u32(stats.count);
+            out.f64(stats.mean);
+            out.f64(stats.m2);
+        }
+        if let Some(direct) = &self.direct {
+            direct.save_state(&mut out);
+        }
+        for cell in self.features.iter().flatten() {
+            cell.save_state(&mut out);
+        }
+        for cell in self.coverage.iter().flatten() {
+            let coverage = cell.load();
+            out.u32(coverage.samples);
+            for counts in [&coverage.objects, &coverage.materials] {
+                out.u32(counts.len() as u32);
+                for &(id, n) in counts {
+                    out.u32(id);
+                    out.u32(n);
+                }
+            }
+        }
+
+        // Written beside the old checkpoint and moved over it, so being stopped halfway
+        // through never leaves a broken one behind
+        let partial = path.with_extension("partial");
+        fs::write(&partial, out.0)?;
+        fs::rename(&partial, path)
+    }
+
+    // Picks up a checkpoint of this same window and returns how many render passes it holds.
+    // Passes the checkpoint has but this render doesn't record are skipped, ones it lacks
+    // start out empty. Nothing changes unless the whole file reads back.
+    pub fn load_checkpoint(&mut self, path: &Path) -> io::Result<u32> {
+        let data = fs::read(path)?;
+        let mut input = CheckpointReader(&data);
+        if input.bytes(CHECKPOINT_MAGIC.len())? != CHECKPOINT_MAGIC {
+            return Err(checkpoint_error("not a render checkpoint"));
+        }
+        let (width, height) = (input.u64()? as usize, input.u64()? as usize);
+        let origin = (input.u64()? as usize, input.u64()? as usize);
+        if (width, height, origin) != (self.width, self.height, self.origin) {
+            return Err(checkpoint_error(&format!(
+                "checkpoint holds {}x{} pixels at {:?}, this render {}x{} at {:?}",
+                width, height, origin, self.width, self.height, self.origin
+            )));
+        }
+        let sections = input.bytes(1)?[0];
+        let render_passes = input.u32()?;
+        let pixels = width * height;
+
+        let film = input.f64s(pixels * FILM_PIXEL_VALUES)?;
+        let mut stats = Vec::with_capacity(pixels);
+        for _ in 0..pixels {
+            stats.push(PixelStats { count: input.u32()?, mean: input.f64()?, m2: input.f64()? });
+        }
+        let direct = match sections & HAS_DIRECT {
+            0 => None,
+            _ => Some(input.f64s(pixels * FILM_PIXEL_VALUES)?),
+        };
+        let features = match sections & HAS_FEATURES {
+            0 => None,
+            _ => {
+                let cells: Vec<FeatureCell> = (0..pixels).map(|_| FeatureCell::default()).collect();
+                for cell in &cells {
+                    cell.load_state(&mut input)?;
+                }
+                Some(cells)
+            }
+        };
+        let coverage = match sections & HAS_COVERAGE {
+            0 => None,
+            _ => {
+                let mut cells = Vec::with_capacity(pixels);
+                for _ in 0..pixels {
+                    let mut coverage = PixelCoverage { samples: input.u32()?, ..PixelCoverage::default() };
+                    for counts in [&mut coverage.objects, &mut coverage.materials] {
+                        for _ in 0..input.u32()? {
+                            counts.push((input.u32()?, input.u32()?));
+                        }
+                    }
+                    cells.push(coverage);
+                }
+                Some(cells)
+            }
+        };
+        if !input.0.is_empty() {
+            return Err(checkpoint_error("unexpected data after the checkpoint"));
+        }
+
+        self.film.load_state(&film);
+        for (cell, stats) in self.stats.iter().zip(stats) {
+            cell.store(stats);
+        }
+        if let (Some(film), Some(saved)) = (&self.direct, direct) {
+            film.load_state(&saved);
+        }
+        if self.features.is_some() && features.is_some() {
+            self.features = features;
+        }
+        if let (Some(cells), Some(saved)) = (&self.coverage, coverage) {
+            for (cell, coverage) in cells.iter().zip(saved) {
+                cell.store(coverage);
+            }
+        }
+        Ok(render_passes)
+    }
+
+    // Sample counts as a blue -> green -> red ramp, normalized to the largest count
+    pub fn save_sample_heatmap(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let max_samples = self.max_samples().max(1);
+        writeln!(out, "P3")?;
+        writeln!(out, "# max samples per pixel: {}", max_samples)?;
+        writeln!(out, "{} {}\n255", self.width, self.height)?;
+        for stats in &self.stats {
+            let t = stats.load().count as f64 / max_samples as f64;
+            let c = heat_color(t);
+            writeln!(out, "{} {} {}", (255.999 * c.x()) as u8, (255.999 * c.y()) as u8, (255.999 * c.z()) as u8)?;
+        }
+        out.flush()
+    }
+}
+
+const CHECKPOINT_MAGIC: &[u8] = b"RTCKPT02";
+// Which optional passes follow the film and statistics in a checkpoint
+const HAS_DIRECT: u8 = 1;
+const HAS_FEATURES: u8 = 2;
+const HAS_COVERAGE: u8 = 4;
+
+// Little endian checkpoint contents
+#[derive(Default)]
+struct CheckpointWriter(Vec<u8>);
+
+impl CheckpointWriter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+fn checkpoint_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct CheckpointReader<'a>(&'a [u8]);
+
+impl<'a> CheckpointReader<'a> {
+    fn bytes(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < count {
+            return Err(checkpoint_error("checkpoint is cut short"));
+        }
+        let (bytes, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f64s(&mut self, count: usize) -> io::Result<Vec<f64>> {
+        Ok(self.bytes(count * 8)?.chunks_exact(8).map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap())).collect())
+    }
+}
+
+// Writes display encoded pixels, row by row from the top, as a plain PPM
+pub fn write_ppm(out: &mut impl Write, width: usize, height: usize, comment: &str, pixels: impl IntoIterator<Item = Color>) -> io::Result<()> {
+    writeln!(out, "P3")?;
+    writeln!(out, "# {}", comment)?;
+    writeln!(out, "{} {}\n255", width, height)?;
+    for color in pixels {
+        write!(out, "{}", color_to_string(color))?;
+    }
+    out.flush()
+}
+
+// Reads an 8-bit P6 or plain P3 image, returning its size and the pixel values scaled to
+// [0, 1] row by row from the top. The values stay in whatever encoding the file used.
+pub fn read_ppm(path: &Path) -> io::Result<(usize, usize, Vec<Color>)> {
+    let data = fs::read(path)?;
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg));
+
+    // Header tokens, skipping comments
+    let mut pos = 0;
+    let mut tokens = Vec::new();
+    while tokens.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos < data.len() && data[pos] == b'#' {
+            while pos < data.len() && data[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(invalid("truncated header"));
+        }
+        tokens.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+    }
+    let number = |s: &str| s.parse::<usize>().map_err(|_| invalid("bad header number"));
+    let (width, height, max_value) = (number(&tokens[1])?, number(&tokens[2])?, number(&tokens[3])?.max(1));
+
+    let values: Vec<f64> = match tokens[0].as_str() {
+        "P6" if max_value < 256 => data.get(pos + 1..).unwrap_or(&[]).iter().map(|&b| b as f64).collect(),
+        "P3" => String::from_utf8_lossy(&data[pos..])
+            .split_whitespace()
+            .map(|s| s.parse::<f64>().map_err(|_| invalid("bad pixel value")))
+            .collect::<io::Result<_>>()?,
+        _ => return Err(invalid("only 8-bit P6 and P3 images are supported")),
+    };
+    if values.len() < width * height * 3 {
+        return Err(invalid("not enough pixel data"));
+    }
+    let pixels = values
+        .chunks(3)
+        .take(width * height)
+        .map(|rgb| Color::new(rgb[0], rgb[1], rgb[2]) / max_value as f64)
+        .collect();
+    Ok((width, height, pixels))
+}
+
+fn heat_color(t: f64) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    if t < 0.5 {
+        let s = t * 2.0;
+        Color::new(0.0, s, 1.0 - s)
+    } else {
+        let s = (t - 0.5) * 2.0;
+        Color::new(s, 1.0 - s, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A crop window with every optional pass recorded and some of everything accumulated
+    fn filled(width: usize, height: usize) -> FrameBuffer {
+        let framebuffer = FrameBuffer::new(width, height, Filter::Tent { radius: 1.0 })
+            .with_origin(3, 2)
+            .with_frame_size(width + 5, height + 4)
+            .with_features()
+            .with_direct()
+            .with_coverage();
+        for j in 0..height {
+            for i in 0..width {
+                let index = j * width + i;
+                for sample in 0..3 {
+                    let color = Color::new(index as f64 * 0.1, sample as f64 * 0.3, 1.0 / (index + sample + 1) as f64);
+                    let (x, y) = (i as f64 + 0.25 * sample as f64, j as f64 + 0.5);
+                    framebuffer.film.add_sample(x, y, color);
+                    framebuffer.direct.as_ref().unwrap().add_sample(x, y, color * 0.5);
+                    let mut stats = framebuffer.stats[index].load();
+                    stats.add(color);
+                    framebuffer.stats[index].store(stats);
+                    framebuffer.features.as_ref().unwrap()[index].add(FeatureSample {
+                        albedo: color,
+                        normal: Vec3::new(0.0, 1.0, 0.0),
+                        depth: 2.0 + sample as f64,
+                        position: Point3::new(i as f64, j as f64, -1.0),
+                        material_id: 1 + sample as u32 % 2,
+                        object_id: 4 + index as u32 % 3,
+                    });
+                    framebuffer.coverage.as_ref().unwrap()[index].add(4 + (index + sample) as u32 % 3, 1 + sample as u32 % 2);
+                }
+            }
+        }
+        // In full frame coordinates, so this lands in the window's pixel (1, 1)
+        framebuffer.splats().add_splat(4.5, 3.5, Color::new(2.0, 1.0, 0.5));
+        framebuffer
+    }
+
+    fn empty_like(framebuffer: &FrameBuffer) -> FrameBuffer {
+        FrameBuffer::new(framebuffer.width, framebuffer.height, framebuffer.film.filter)
+            .with_origin(framebuffer.origin.0, framebuffer.origin.1)
+            .with_frame_size(framebuffer.frame_size.0, framebuffer.frame_size.1)
+            .with_features()
+            .with_direct()
+            .with_coverage()
+    }
+
+    // Everything a checkpoint is meant to carry, in a comparable form
+    fn contents(framebuffer: &FrameBuffer) -> String {
+        let stats: Vec<PixelStats> = framebuffer.stats.iter().map(PixelStatsCell::load).collect();
+        format!(
+            "{:?} {:?} {:?} {:?} {:?}",
+            framebuffer.resolve(),
+            stats,
+            framebuffer.feature_buffers(),
+            framebuffer.light_passes(),
+            framebuffer.pixel_coverage()
+        )
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("raytracing-{}-{}.ckpt", name, std::process::id()))
+    }
+
+    #[test]
+    fn checkpoint_round_trips() {
+        let path = temp_path("round-trip");
+        let saved = filled(5, 4);
+        saved.save_checkpoint(&path, 7).unwrap();
+
+        let mut loaded = empty_like(&saved);
+        let passes = loaded.load_checkpoint(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(passes.unwrap(), 7);
+        assert_eq!(contents(&loaded), contents(&saved));
+    }
+
+    #[test]
+    fn checkpoint_rejects_truncated_and_mismatched_files() {
+        let path = temp_path("rejects");
+        let saved = filled(5, 4);
+        saved.save_checkpoint(&path, 3).unwrap();
+        let data = fs::read(&path).unwrap();
+
+        let mut target = empty_like(&saved);
+        let untouched = contents(&target);
+        for cut in [4, CHECKPOINT_MAGIC.len() + 10, data.len() / 2, data.len() - 1] {
+            fs::write(&path, &data[..cut]).unwrap();
+            assert!(target.load_checkpoint(&path).is_err(), "accepted a checkpoint cut to {} bytes", cut);
+            assert_eq!(contents(&target), untouched);
+        }
+
+        let mut extended = data.clone();
+        extended.push(0);
+        fs::write(&path, &extended).unwrap();
+        assert!(target.load_checkpoint(&path).is_err());
+
+        let mut wrong_magic = data.clone();
+        wrong_magic[0] ^= 0xff;
+        fs::write(&path, &wrong_magic).unwrap();
+        assert!(target.load_checkpoint(&path).is_err());
+
+        fs::write(&path, &data).unwrap();
+        let mut other_size = FrameBuffer::new(4, 4, saved.film.filter).with_origin(3, 2);
+        assert!(other_size.load_checkpoint(&path).is_err());
+        let mut other_origin = FrameBuffer::new(5, 4, saved.film.filter).with_origin(0, 0);
+        assert!(other_origin.load_checkpoint(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents(&target), untouched);
+    }
+}
@@ -1,7 +1,11 @@
+use crate::aabb::Aabb;
+use crate::color::ColorSpace;
 use crate::interval::Interval;
-use crate::material::Material;
+use crate::material::{Material, MaterialIds};
 use crate::vec3::{Point3, Vec3, dot};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -9,7 +13,14 @@ pub struct HitRecord {
     pub t: f64,
     pub p: Point3,
     pub normal: Vec3,
+    // Surface coordinates for texture lookups
+    pub u: f64,
+    pub v: f64,
     pub front_face: bool,
+    // Set by the innermost list holding the object, 0 if none did
+    pub object_id: u32,
+    // Space the material returns colors in, filled in by the `Scene` that hands out the record
+    pub working_space: ColorSpace,
     pub mat: Arc<dyn Material + Send + Sync>
 }
 
@@ -29,4 +40,72 @@ impl HitRecord {
 
 pub trait Hittable: Send + Sync {
     fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord>;
-}
\ No newline at end of file
+
+    // Box around the object, None for things without bounds. Unbounded objects stay out of
+    // the BVH and get tested by every ray.
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+    // Solid angle density of `random` picking `direction` from `origin`, for light sampling
+    fn pdf_value(&self, _origin: Point3, _direction: Vec3) -> f64 {
+        0.0
+    }
+
+    // Direction from `origin` towards a point on this object
+    fn random(&self, _origin: Point3, _sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+
+    // Point picked uniformly by area on the surface, with its density per unit area.
+    // The record's normal points outwards. Used to start paths on emitters.
+    fn sample_surface(&self, _sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        None
+    }
+
+    // Area density with which `sample_surface` picks `p`, 0 if it isn't on this object
+    fn surface_pdf(&self, _p: Point3) -> f64 {
+        0.0
+    }
+
+    // Adds the names of objects held inside this one, by object ID
+    fn object_names(&self, _names: &mut HashMap<u32, String>) {}
+
+    // Adds the materials of this object and every object inside it, in a fixed order
+    fn scene_materials(&self, _materials: &mut MaterialIds) {}
+}
+// Shared objects, so one piece of geometry can sit in several worlds, like a static BVH
+// reused from frame to frame
+impl<T: Hittable + ?Sized> Hittable for Arc<T> {
+    fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        (**self).hit(r, ray_t)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        (**self).bounding_box()
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        (**self).pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        (**self).random(origin, sampler)
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        (**self).sample_surface(sampler)
+    }
+
+    fn surface_pdf(&self, p: Point3) -> f64 {
+        (**self).surface_pdf(p)
+    }
+
+    fn object_names(&self, names: &mut HashMap<u32, String>) {
+        (**self).object_names(names)
+    }
+
+    fn scene_materials(&self, materials: &mut MaterialIds) {
+        (**self).scene_materials(materials)
+    }
+}
@@ -1,13 +1,42 @@
-use crate::{hittable::{HitRecord, Hittable}, interval::{self, Interval}};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{aabb::Aabb, hittable::{HitRecord, Hittable}, interval::Interval, material::{Material, MaterialIds}, ray::Ray, sampler::{hash, Sampler}, vec3::{Point3, Vec3}};
+
+// ID of an object inside a nested list or BVH, from the ID the outer list gave the nested
+// one and the object's ID inside it. Every list numbers its objects from 1, so this keeps
+// objects in different nested lists apart while the IDs only depend on how the scene is
+// put together. An outer ID of 0 leaves the inner one alone.
+pub fn nested_object_id(outer: u32, inner: u32) -> u32 {
+    match (outer, inner) {
+        (0, id) | (id, 0) => id,
+        _ => (hash(&[outer as u64, inner as u64]) as u32).max(1),
+    }
+}
 
 pub struct HittableList {
     pub objects: Vec<Box<dyn Hittable>>,
+    // Object ID of each entry of `objects`, 0 leaves the IDs of nested objects alone
+    pub ids: Vec<u32>,
+    // Optional names by object ID, for ID mattes
+    pub names: HashMap<u32, String>,
+    // Materials named for ID mattes
+    pub materials: MaterialIds,
+}
+
+impl Default for HittableList {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HittableList {
     pub fn new() -> Self {
         HittableList {
             objects: Vec::new(),
+            ids: Vec::new(),
+            names: HashMap::new(),
+            materials: MaterialIds::default(),
         }
     }
 
@@ -19,25 +48,184 @@ impl HittableList {
 
     pub fn clear(&mut self) {
         self.objects.clear();
+        self.ids.clear();
+        self.names.clear();
+        self.materials = MaterialIds::default();
     }
 
+    // Objects are numbered by their position in the list, from 1
     pub fn add(&mut self, object: Box<dyn Hittable>) {
+        self.add_with_id(object, self.objects.len() as u32 + 1);
+    }
+
+    pub fn add_named(&mut self, object: Box<dyn Hittable>, name: impl Into<String>) {
+        let id = self.objects.len() as u32 + 1;
+        self.names.insert(id, name.into());
+        self.add_with_id(object, id);
+    }
+
+    pub fn name_material(&mut self, material: &Arc<dyn Material + Send + Sync>, name: impl Into<String>) {
+        self.materials.name(material, name);
+    }
+
+    pub fn add_with_id(&mut self, object: Box<dyn Hittable>, id: u32) {
         self.objects.push(object);
+        self.ids.push(id);
+    }
+
+    // 0 for objects pushed onto `objects` directly
+    pub fn id(&self, index: usize) -> u32 {
+        self.ids.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
     }
 }
 
 impl Hittable for HittableList {
-    fn hit(&self, r: &crate::ray::Ray, ray_t: Interval) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
         let mut closest_hit: Option<HitRecord> = None;
         let mut closest_so_far = ray_t.max;
 
-        for object in &self.objects {
-            if let Some(hit_rec) = object.hit(r, Interval::new(ray_t.min, closest_so_far)) {
+        for (index, object) in self.objects.iter().enumerate() {
+            if let Some(mut hit_rec) = object.hit(r, Interval::new(ray_t.min, closest_so_far)) {
                 closest_so_far = hit_rec.t;
+                hit_rec.object_id = nested_object_id(self.id(index), hit_rec.object_id);
                 closest_hit = Some(hit_rec)
             }
         }
 
         closest_hit
     }
-}
\ No newline at end of file
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut boxes = self.objects.iter().map(|object| object.bounding_box());
+        let first = boxes.next()??;
+        boxes.try_fold(first, |bbox, object_box| Some(Aabb::enclosing(&bbox, &object_box?)))
+    }
+
+    // Objects are picked uniformly, so the density is the average of theirs
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        if self.objects.is_empty() {
+            return 0.0;
+        }
+        let sum: f64 = self.objects.iter().map(|object| object.pdf_value(origin, direction)).sum();
+        sum / self.objects.len() as f64
+    }
+
+    fn random(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        if self.objects.is_empty() {
+            return Vec3::new(1.0, 0.0, 0.0);
+        }
+        let index = ((sampler.get_1d() * self.objects.len() as f64) as usize).min(self.objects.len() - 1);
+        self.objects[index].random(origin, sampler)
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        if self.objects.is_empty() {
+            return None;
+        }
+        let index = ((sampler.get_1d() * self.objects.len() as f64) as usize).min(self.objects.len() - 1);
+        let (rec, pdf) = self.objects[index].sample_surface(sampler)?;
+        Some((rec, pdf / self.objects.len() as f64))
+    }
+
+    fn surface_pdf(&self, p: Point3) -> f64 {
+        if self.objects.is_empty() {
+            return 0.0;
+        }
+        let sum: f64 = self.objects.iter().map(|object| object.surface_pdf(p)).sum();
+        sum / self.objects.len() as f64
+    }
+
+    // Nested lists come after this one's own names, as their IDs win when a ray hits
+    fn object_names(&self, names: &mut HashMap<u32, String>) {
+        names.extend(self.names.iter().map(|(&id, name)| (id, name.clone())));
+        for (index, object) in self.objects.iter().enumerate() {
+            let mut nested = HashMap::new();
+            object.object_names(&mut nested);
+            names.extend(nested.into_iter().map(|(id, name)| (nested_object_id(self.id(index), id), name)));
+        }
+    }
+
+    // Names come last so naming a material doesn't change its ID
+    fn scene_materials(&self, materials: &mut MaterialIds) {
+        for object in &self.objects {
+            object.scene_materials(materials);
+        }
+        materials.extend(&self.materials);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::Bvh;
+    use crate::color::Color;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+
+    // One named object at the top and two more in a nested list, all at z = -5
+    fn scene(material: &Arc<dyn Material + Send + Sync>) -> HittableList {
+        let mut nested = HittableList::new();
+        nested.add_named(Box::new(Sphere::new(Point3::new(3.0, 0.0, -5.0), 1.0, material.clone())), "right");
+        nested.add(Box::new(Sphere::new(Point3::new(-3.0, 0.0, -5.0), 1.0, Arc::new(Lambertian::new(Color::new(0.2, 0.2, 0.2))))));
+        let mut world = HittableList::new();
+        world.add_named(Box::new(Sphere::new(Point3::new(0.0, 0.0, -5.0), 1.0, material.clone())), "middle");
+        world.add(Box::new(nested));
+        world.name_material(material, "grey");
+        world
+    }
+
+    fn ids(world: &dyn Hittable) -> Vec<u32> {
+        [-3.0, 0.0, 3.0]
+            .iter()
+            .map(|&x| world.hit(&Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(x, 0.0, -5.0)), Interval::new(0.001, f64::INFINITY)).unwrap().object_id)
+            .collect()
+    }
+
+    #[test]
+    fn object_ids_only_depend_on_the_scene() {
+        let material: Arc<dyn Material + Send + Sync> = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let first = ids(&scene(&material));
+        let (left, middle, right) = (first[0], first[1], first[2]);
+        assert_eq!(middle, 1);
+        assert!(left != right && left != middle && right != middle && left != 0);
+        assert_eq!(ids(&scene(&material)), first);
+
+        let mut names = HashMap::new();
+        scene(&material).object_names(&mut names);
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[&middle], "middle");
+        assert_eq!(names[&right], "right");
+
+        // A BVH over the same list keeps its IDs and names
+        let bvh = Bvh::new(scene(&material));
+        assert_eq!(ids(&bvh), first);
+        let mut bvh_names = HashMap::new();
+        bvh.object_names(&mut bvh_names);
+        assert_eq!(bvh_names, names);
+    }
+
+    #[test]
+    fn material_ids_follow_first_use() {
+        let material: Arc<dyn Material + Send + Sync> = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let world = scene(&material);
+        let mut materials = MaterialIds::default();
+        world.scene_materials(&mut materials);
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials.id(&material), 1);
+        assert_eq!(materials.name_of(1), Some("grey"));
+        assert_eq!(materials.name_of(2), None);
+
+        let mut from_bvh = MaterialIds::default();
+        Bvh::new(world).scene_materials(&mut from_bvh);
+        assert_eq!(from_bvh.id(&material), 1);
+        assert_eq!(from_bvh.name_of(1), Some("grey"));
+    }
+}
@@ -0,0 +1,627 @@
+use std::f64::consts::PI;
+
+use crate::camera::Camera;
+use crate::color::{convert, Color, ColorSpace};
+use crate::framebuffer::{FeatureSample, SplatSink};
+use crate::hittable::{HitRecord, Hittable};
+use crate::hittable_list::HittableList;
+use crate::interval::Interval;
+use crate::material::MaterialIds;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths};
+use crate::stats::{self, Counter};
+use crate::vec3::{dot, unit_vector, Vec3};
+
+static TRANS_FLAG: bool = false;
+
+// Smallest ray distance we accept, keeps rays from re-hitting the surface they left
+const RAY_EPSILON: f64 = 0.001;
+
+// The scene plus the render settings an integrator shades with
+pub struct Scene<'a> {
+    pub world: &'a dyn Hittable,
+    // Emitters that get sampled explicitly, usually the emissive objects of `world` again
+    pub lights: &'a HittableList,
+    pub max_depth: i32,
+    pub russian_roulette_depth: Option<i32>,
+    pub spectral: bool,
+    // Linear RGB space colors get shaded in
+    pub working_space: ColorSpace,
+    pub camera: &'a Camera,
+    // Target for contributions that reach the image from the light side
+    pub film: &'a dyn SplatSink,
+    // Index of the tile being rendered, for integrators that keep state per tile so the
+    // image doesn't depend on which thread took which tile
+    pub tile: usize,
+    // IDs of the materials in `world`, for ID mattes
+    pub materials: &'a MaterialIds,
+}
+
+impl Scene<'_> {
+    pub fn hit(&self, r: &Ray) -> Option<HitRecord> {
+        let mut rec = self.world.hit(r, Interval::new(RAY_EPSILON, f64::INFINITY))?;
+        rec.working_space = self.working_space;
+        Some(rec)
+    }
+
+    // A point on one of the lights to start a path from, with its area density
+    pub fn sample_light(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let (mut rec, pdf) = self.lights.sample_surface(sampler)?;
+        rec.working_space = self.working_space;
+        Some((rec, pdf))
+    }
+
+    // Denoiser guides and geometry passes from what the camera ray `r` hits first. Depth is
+    // the hit's `t` scaled by the ray's length, so it is a distance even for unnormalized rays.
+    pub fn primary_features(&self, r: &Ray) -> FeatureSample {
+        match self.hit(r) {
+            Some(rec) => FeatureSample {
+                albedo: rec.mat.albedo(&rec),
+                normal: rec.normal,
+                depth: rec.t * r.direction().length(),
+                position: rec.p,
+                material_id: self.materials.id(&rec.mat),
+                object_id: rec.object_id,
+            },
+            None => FeatureSample { albedo: self.background(r), ..FeatureSample::default() },
+        }
+    }
+
+    // Radiance arriving from the sky along a ray that left the scene
+    pub fn background(&self, r: &Ray) -> Color {
+        convert(self.sky(r), ColorSpace::LinearSrgb, self.working_space)
+    }
+
+    fn sky(&self, r: &Ray) -> Color {
+        if TRANS_FLAG {
+            let a = 0.5 * (unit_vector(r.direction()).y() + 1.0); // 0 at bottom, 1 at top
+            let c_blue  = Color::new(0.357, 0.808, 0.980); // #5BCEFA
+            let c_pink  = Color::new(0.961, 0.663, 0.722); // #F5A9B8
+            let c_white = Color::new(1.0,  1.0,   1.0);
+
+            if a >= 0.8 {
+                c_blue   // top 20%
+            } else if a >= 0.6 {
+                c_pink   // next 20%
+            } else if a >= 0.4 {
+                c_white  // middle 20%
+            } else if a >= 0.2 {
+                c_pink   // next 20%
+            } else {
+                c_blue   // bottom 20%
+            }
+        } else {
+            let unit_direction = unit_vector(r.direction());
+            let a = 0.5 * (unit_direction.y() + 1.0);
+            (Color::new(1.0, 1.0, 1.0) * (1.0 - a)) + (Color::new(0.5, 0.7, 1.0) * a)
+        }
+    }
+
+    // Russian roulette: past the minimum depth a path continues with probability equal to
+    // its largest throughput component. Returns the survival probability the throughput
+    // has to be divided by to stay unbiased, or None if the path was terminated.
+    pub fn russian_roulette(&self, bounces: i32, max_throughput: f64, sampler: &mut dyn Sampler) -> Option<f64> {
+        let Some(min_depth) = self.russian_roulette_depth else {
+            return Some(1.0);
+        };
+        if bounces < min_depth {
+            return Some(1.0);
+        }
+        let survival = max_throughput.min(1.0);
+        if survival <= 0.0 || sampler.get_1d() >= survival {
+            stats::increment(Counter::RussianRouletteTerminations);
+            return None;
+        }
+        Some(survival)
+    }
+}
+
+// Turns a camera ray into the radiance arriving along it
+pub trait Integrator: Send + Sync {
+    fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color;
+
+    // `ray_color` along with the part of it that is direct light: emitters and sky seen
+    // straight away or after one bounce off the first surface. Integrators that don't tell
+    // the two apart count everything as direct.
+    fn ray_color_with_direct(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> (Color, Color) {
+        let color = self.ray_color(r, scene, sampler);
+        (color, color)
+    }
+
+    // Called before every render pass (counted from 1), for integrators that precompute
+    // per-pass data such as photon maps
+    fn begin_pass(&self, _scene: &Scene, _pass: u32) {}
+
+    // Most samples per pixel a single pass may take, to make the camera run more passes
+    fn max_samples_per_pass(&self) -> Option<u32> {
+        None
+    }
+}
+
+fn max_component(c: Color) -> f64 {
+    c.x().max(c.y()).max(c.z())
+}
+
+// Balances two sampling strategies by their densities (Veach's power heuristic, beta = 2)
+pub fn power_heuristic(pdf_f: f64, pdf_g: f64) -> f64 {
+    let f2 = pdf_f * pdf_f;
+    let g2 = pdf_g * pdf_g;
+    if f2 + g2 == 0.0 {
+        return 0.0;
+    }
+    f2 / (f2 + g2)
+}
+
+// Unidirectional path tracer that only follows material scattering. With `spectral` set
+// in the scene it traces hero wavelengths instead of RGB.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathIntegrator;
+
+impl PathIntegrator {
+    // Iterative path tracer: the path carries its throughput instead of multiplying on the
+    // way back out of a recursion, so deep paths don't grow the stack.
+    fn ray_color_rgb(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> (Color, Color) {
+        let mut r = r;
+        let mut radiance = Color::new(0.0, 0.0, 0.0);
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut bounces = 0;
+        // What `radiance` held once the path got past its first bounce
+        let mut direct = None;
+
+        loop {
+            if bounces >= scene.max_depth {
+                stats::record_path(bounces as usize);
+                return (radiance, direct.unwrap_or(radiance))
+            }
+            if bounces == 2 {
+                direct = Some(radiance);
+            }
+            let Some(rec) = scene.hit(&r) else {
+                stats::record_path(bounces as usize);
+                let radiance = radiance + throughput * scene.background(&r);
+                return (radiance, direct.unwrap_or(radiance))
+            };
+            radiance += throughput * rec.mat.emitted(&rec);
+            let Some(sc) = rec.mat.scatter(&r, &rec, sampler) else {
+                stats::record_path(bounces as usize);
+                return (radiance, direct.unwrap_or(radiance))
+            };
+
+            throughput = throughput * sc.attenuation;
+            bounces += 1;
+            let Some(survival) = scene.russian_roulette(bounces, max_component(throughput), sampler) else {
+                stats::record_path(bounces as usize);
+                return (radiance, direct.unwrap_or(radiance))
+            };
+            throughput = throughput / survival;
+            if bounces < scene.max_depth {
+                stats::increment(Counter::SecondaryRays);
+            }
+            r = sc.ray;
+        }
+    }
+
+    fn ray_color_spectral(&self, r: Ray, scene: &Scene, lambdas: &mut SampledWavelengths, sampler: &mut dyn Sampler) -> (SampledSpectrum, SampledSpectrum) {
+        let mut r = r;
+        let mut radiance = SampledSpectrum::new(0.0);
+        let mut throughput = SampledSpectrum::new(1.0);
+        let mut bounces = 0;
+        let mut direct = None;
+        // The upsampling works on linear sRGB
+        let space = scene.working_space;
+
+        loop {
+            if bounces >= scene.max_depth {
+                stats::record_path(bounces as usize);
+                return (radiance, direct.unwrap_or(radiance))
+            }
+            if bounces == 2 {
+                direct = Some(radiance);
+            }
+            let Some(rec) = scene.hit(&r) else {
+                stats::record_path(bounces as usize);
+                radiance += throughput * SampledSpectrum::from_rgb_in(scene.background(&r), space, lambdas);
+                return (radiance, direct.unwrap_or(radiance))
+            };
+            radiance += throughput * SampledSpectrum::from_rgb_in(rec.mat.emitted(&rec), space, lambdas);
+            if rec.mat.is_dispersive() {
+                lambdas.terminate_secondary();
+            }
+            let Some(sc) = rec.mat.scatter_at_wavelength(&r, &rec, lambdas.hero(), sampler) else {
+                stats::record_path(bounces as usize);
+                return (radiance, direct.unwrap_or(radiance))
+            };
+
+            throughput = throughput * SampledSpectrum::from_rgb_in(sc.attenuation, space, lambdas);
+            bounces += 1;
+            let Some(survival) = scene.russian_roulette(bounces, throughput.max_value(), sampler) else {
+                stats::record_path(bounces as usize);
+                return (radiance, direct.unwrap_or(radiance))
+            };
+            throughput = throughput * (1.0 / survival);
+            if bounces < scene.max_depth {
+                stats::increment(Counter::SecondaryRays);
+            }
+            r = sc.ray;
+        }
+    }
+}
+
+impl Integrator for PathIntegrator {
+    fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+        self.ray_color_with_direct(r, scene, sampler).0
+    }
+
+    fn ray_color_with_direct(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> (Color, Color) {
+        if scene.spectral {
+            let mut lambdas = SampledWavelengths::sample_uniform(sampler.get_1d());
+            let (radiance, direct) = self.ray_color_spectral(r, scene, &mut lambdas, sampler);
+            return (radiance.to_rgb_in(scene.working_space, &lambdas), direct.to_rgb_in(scene.working_space, &lambdas))
+        }
+        self.ray_color_rgb(r, scene, sampler)
+    }
+}
+
+// Ambient occlusion: the fraction of the cosine-weighted hemisphere above the first hit
+// that is open within `max_distance`. Misses are white.
+#[derive(Debug, Clone, Copy)]
+pub struct AmbientOcclusionIntegrator {
+    pub max_distance: f64,
+}
+
+impl AmbientOcclusionIntegrator {
+    pub fn new(max_distance: f64) -> Self {
+        Self { max_distance }
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+        let Some(rec) = scene.hit(&r) else {
+            stats::record_path(0);
+            return Color::new(1.0, 1.0, 1.0)
+        };
+        let direction = Onb::new(rec.normal).transform(sample_cosine_direction(sampler.get_2d()));
+        stats::increment(Counter::ShadowRays);
+        stats::record_path(1);
+        let occluded = scene.world.hit(&Ray::new(rec.p, direction), Interval::new(RAY_EPSILON, self.max_distance)).is_some();
+        if occluded {
+            Color::new(0.0, 0.0, 0.0)
+        } else {
+            Color::new(1.0, 1.0, 1.0)
+        }
+    }
+}
+
+// Cosine-weighted direction around +z
+pub fn sample_cosine_direction(u: (f64, f64)) -> Vec3 {
+    let phi = 2.0 * PI * u.0;
+    let x = phi.cos() * u.1.sqrt();
+    let y = phi.sin() * u.1.sqrt();
+    let z = (1.0 - u.1).sqrt();
+    Vec3::new(x, y, z)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugChannel {
+    // Shading normal mapped from [-1, 1] to [0, 1]
+    Normal,
+    Albedo,
+    // Hit distance, black at the camera and white at `max_distance`
+    Depth { max_distance: f64 },
+}
+
+// Shows one first-hit attribute, useful for checking geometry and materials quickly
+#[derive(Debug, Clone, Copy)]
+pub struct DebugIntegrator {
+    pub channel: DebugChannel,
+}
+
+impl DebugIntegrator {
+    pub fn new(channel: DebugChannel) -> Self {
+        Self { channel }
+    }
+}
+
+impl Integrator for DebugIntegrator {
+    fn ray_color(&self, r: Ray, scene: &Scene, _sampler: &mut dyn Sampler) -> Color {
+        stats::record_path(0);
+        let Some(rec) = scene.hit(&r) else {
+            return Color::new(0.0, 0.0, 0.0)
+        };
+        match self.channel {
+            DebugChannel::Normal => (rec.normal + Color::new(1.0, 1.0, 1.0)) * 0.5,
+            DebugChannel::Albedo => rec.mat.albedo(&rec),
+            DebugChannel::Depth { max_distance } => {
+                let distance = rec.t * r.direction().length();
+                let d = (distance / max_distance).clamp(0.0, 1.0);
+                Color::new(d, d, d)
+            }
+        }
+    }
+}
+
+// Light reaching `rec` straight from the emitters in `scene.lights`, sampled by picking a
+// point on a light and weighted against BSDF sampling with MIS. Emitters missing from the
+// list are never picked here, so `bsdf_sample_weight` leaves them their full weight.
+fn sample_lights(scene: &Scene, rec: &HitRecord, wo: Vec3, sampler: &mut dyn Sampler) -> Color {
+    if scene.lights.is_empty() || rec.mat.is_specular() {
+        return Color::new(0.0, 0.0, 0.0)
+    }
+    let wi = unit_vector(scene.lights.random(rec.p, sampler));
+    let light_pdf = scene.lights.pdf_value(rec.p, wi);
+    let cos_theta = dot(rec.normal, wi);
+    if light_pdf <= 0.0 || cos_theta <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0)
+    }
+
+    stats::increment(Counter::ShadowRays);
+    let Some(light_rec) = scene.hit(&Ray::new(rec.p, wi)) else {
+        return Color::new(0.0, 0.0, 0.0)
+    };
+    let emitted = light_rec.mat.emitted(&light_rec);
+    let bsdf_pdf = rec.mat.pdf(rec, wo, wi);
+    rec.mat.eval(rec, wo, wi) * emitted * (cos_theta * power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+}
+
+// Radiance found by following the material's own sample, MIS weighted against light
+// sampling when the material isn't specular
+fn bsdf_sample_weight(scene: &Scene, rec: &HitRecord, wo: Vec3, wi: Vec3, hit_emitter: bool) -> f64 {
+    if rec.mat.is_specular() || !hit_emitter || scene.lights.is_empty() {
+        return 1.0;
+    }
+    let bsdf_pdf = rec.mat.pdf(rec, wo, wi);
+    let light_pdf = scene.lights.pdf_value(rec.p, wi);
+    power_heuristic(bsdf_pdf, light_pdf)
+}
+
+// Direct illumination only: emitters and sky seen from the first non-specular hit.
+// Specular surfaces are followed until something diffuse is found.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectLightingIntegrator;
+
+impl Integrator for DirectLightingIntegrator {
+    fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+        let mut r = r;
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut radiance = Color::new(0.0, 0.0, 0.0);
+
+        for bounces in 0..scene.max_depth {
+            let Some(rec) = scene.hit(&r) else {
+                stats::record_path(bounces as usize);
+                return radiance + throughput * scene.background(&r)
+            };
+            radiance += throughput * rec.mat.emitted(&rec);
+            let wo = -unit_vector(r.direction());
+
+            let Some(sc) = rec.mat.scatter(&r, &rec, sampler) else {
+                stats::record_path(bounces as usize);
+                return radiance
+            };
+            if rec.mat.is_specular() {
+                throughput = throughput * sc.attenuation;
+                stats::increment(Counter::SecondaryRays);
+                r = sc.ray;
+                continue;
+            }
+
+            radiance += throughput * sample_lights(scene, &rec, wo, sampler);
+
+            let wi = unit_vector(sc.ray.direction());
+            stats::increment(Counter::SecondaryRays);
+            stats::record_path(bounces as usize + 1);
+            return match scene.hit(&sc.ray) {
+                Some(light_rec) => {
+                    let emitted = light_rec.mat.emitted(&light_rec);
+                    let weight = bsdf_sample_weight(scene, &rec, wo, wi, true);
+                    radiance + throughput * sc.attenuation * emitted * weight
+                }
+                None => radiance + throughput * sc.attenuation * scene.background(&sc.ray),
+            };
+        }
+        stats::record_path(scene.max_depth as usize);
+        radiance
+    }
+}
+
+// Below this share of the camera ray's light, rays hitting glass pick one of reflection and
+// refraction at random instead of following both, which keeps them from branching
+// exponentially through nested glass
+const MIN_WHITTED_SPLIT_WEIGHT: f64 = 0.01;
+
+// Classic Whitted ray tracing: shadow rays to the lights plus a sky ambient term on
+// diffuse surfaces, and recursion through mirrors and glass. Glass sends out both the
+// reflected and the refracted ray, weighted by Fresnel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WhittedIntegrator;
+
+impl WhittedIntegrator {
+    // `weight` is how much of the camera ray's light this ray carries at most
+    fn trace(&self, r: Ray, depth: i32, weight: f64, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+        if depth <= 0 {
+            stats::record_path((scene.max_depth - depth) as usize);
+            return Color::new(0.0, 0.0, 0.0)
+        }
+        let Some(rec) = scene.hit(&r) else {
+            stats::record_path((scene.max_depth - depth) as usize);
+            return scene.background(&r)
+        };
+        let emitted = rec.mat.emitted(&rec);
+
+        if rec.mat.is_specular() {
+            if weight >= MIN_WHITTED_SPLIT_WEIGHT
+                && let Some(split) = rec.mat.specular_split(&r, &rec) {
+                let mut radiance = emitted;
+                for sc in split {
+                    let a = sc.attenuation;
+                    let branch_weight = weight * a.x().max(a.y()).max(a.z());
+                    if branch_weight <= 0.0 {
+                        continue;
+                    }
+                    stats::increment(Counter::SecondaryRays);
+                    radiance += a * self.trace(sc.ray, depth - 1, branch_weight, scene, sampler);
+                }
+                return radiance
+            }
+            let Some(sc) = rec.mat.scatter(&r, &rec, sampler) else {
+                stats::record_path((scene.max_depth - depth) as usize);
+                return emitted
+            };
+            stats::increment(Counter::SecondaryRays);
+            let a = sc.attenuation;
+            return emitted + a * self.trace(sc.ray, depth - 1, weight * a.x().max(a.y()).max(a.z()), scene, sampler)
+        }
+
+        stats::record_path((scene.max_depth - depth) as usize);
+        let wo = -unit_vector(r.direction());
+        let mut radiance = emitted;
+        for light in &scene.lights.objects {
+            let wi = unit_vector(light.random(rec.p, sampler));
+            let light_pdf = light.pdf_value(rec.p, wi);
+            let cos_theta = dot(rec.normal, wi);
+            if light_pdf <= 0.0 || cos_theta <= 0.0 {
+                continue;
+            }
+            stats::increment(Counter::ShadowRays);
+            if let Some(light_rec) = scene.hit(&Ray::new(rec.p, wi)) {
+                radiance += rec.mat.eval(&rec, wo, wi) * light_rec.mat.emitted(&light_rec) * (cos_theta / light_pdf);
+            }
+        }
+
+        // Stand-in for indirect light: the sky seen straight along the normal
+        let ambient = scene.background(&Ray::new(rec.p, rec.normal));
+        radiance + rec.mat.albedo(&rec) * ambient
+    }
+}
+
+impl Integrator for WhittedIntegrator {
+    fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+        self.trace(r, scene.max_depth, 1.0, scene, sampler)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+
+    use super::{DirectLightingIntegrator, Integrator, PathIntegrator, Scene, WhittedIntegrator};
+    use crate::camera::Camera;
+    use crate::color::Color;
+    use crate::framebuffer::FrameBuffer;
+    use crate::hittable::Hittable;
+    use crate::hittable_list::HittableList;
+    use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
+    use crate::ray::Ray;
+    use crate::sampler::Sampler;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Point3, Vec3};
+
+    // Diffuse ground and ball lit by a small sphere light, inside a black sphere so no sky
+    // gets in. Returns the world and the light list holding the light.
+    pub(crate) fn diffuse_scene() -> (HittableList, HittableList) {
+        let light = Sphere::new(Point3::new(0.8, 1.2, 0.3), 0.3, Arc::new(DiffuseLight::new(Color::new(6.0, 6.0, 6.0))));
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(Color::new(0.6, 0.5, 0.4))))));
+        world.add(Box::new(Sphere::new(Point3::new(-0.3, 0.5, -0.4), 0.5, Arc::new(Lambertian::new(Color::new(0.3, 0.6, 0.3))))));
+        world.add(Box::new(Sphere::new(Point3::new(0.0, 0.0, 0.0), 20.0, Arc::new(Lambertian::new(Color::new(0.0, 0.0, 0.0))))));
+        world.add(Box::new(light.clone()));
+        let mut lights = HittableList::new();
+        lights.add(Box::new(light));
+        (world, lights)
+    }
+
+    // Looks straight down at `diffuse_scene`, small enough for tests
+    pub(crate) fn test_camera(samples_per_pixel: i32) -> Camera {
+        Camera::new()
+            .with_aspect_ratio(1.0)
+            .with_image_width(24)
+            .with_samples_per_pixel(samples_per_pixel)
+            .with_max_depth(4)
+            .with_vfov(60.0)
+            .with_lookfrom(Point3::new(0.0, 3.0, 0.0))
+            .with_lookat(Point3::new(0.0, 0.0, 0.0))
+            .with_vup(Vec3::new(0.0, 0.0, -1.0))
+            .with_seed(5)
+    }
+
+    // The framebuffer's last resolved image, rendered without writing any files
+    pub(crate) fn render(camera: &mut Camera, world: &dyn Hittable, lights: &HittableList) -> Vec<Color> {
+        let mut image = Vec::new();
+        camera.render_passes(world, lights, &mut |framebuffer: &FrameBuffer| {
+            image = framebuffer.resolve();
+            true
+        });
+        image
+    }
+
+    pub(crate) fn mean(pixels: &[Color]) -> Color {
+        pixels.iter().fold(Color::new(0.0, 0.0, 0.0), |sum, &c| sum + c) / pixels.len() as f64
+    }
+
+    // Every channel within `tolerance` of the reference, relative to it
+    pub(crate) fn assert_close(value: Color, reference: Color, tolerance: f64) {
+        for axis in 0..3 {
+            let (v, r) = (value.e[axis], reference.e[axis]);
+            assert!((v - r).abs() <= tolerance * r.abs(), "{:?} is not within {} of {:?}", value, tolerance, reference);
+        }
+    }
+
+    #[test]
+    fn direct_lighting_on_fuzzy_metal_matches_path_tracing() {
+        // A fuzzy mirror floor under the light, so light sampling and the glossy lobe both
+        // matter all over the frame
+        let light = Sphere::new(Point3::new(0.8, 1.2, 0.3), 0.3, Arc::new(DiffuseLight::new(Color::new(6.0, 6.0, 6.0))));
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Metal::new(Color::new(0.9, 0.8, 0.7), 0.9)))));
+        world.add(Box::new(Sphere::new(Point3::new(0.0, 0.0, 0.0), 20.0, Arc::new(Lambertian::new(Color::new(0.0, 0.0, 0.0))))));
+        world.add(Box::new(light.clone()));
+        let mut lights = HittableList::new();
+        lights.add(Box::new(light));
+
+        // Two bounces is the camera ray plus one scattered ray, all direct lighting sees
+        let direct = render(&mut test_camera(256).with_integrator(DirectLightingIntegrator), &world, &lights);
+        let path = render(&mut test_camera(256).with_max_depth(2).with_integrator(PathIntegrator), &world, &lights);
+        assert_close(mean(&direct), mean(&path), 0.02);
+    }
+
+    // The sky along the normal scaled by the albedo, Whitted's stand-in for indirect light
+    struct Ambient;
+
+    impl Integrator for Ambient {
+        fn ray_color(&self, r: Ray, scene: &Scene, _sampler: &mut dyn Sampler) -> Color {
+            match scene.hit(&r) {
+                Some(rec) => rec.mat.albedo(&rec) * scene.background(&Ray::new(rec.p, rec.normal)),
+                None => scene.background(&r),
+            }
+        }
+    }
+
+    #[test]
+    fn whitted_is_direct_lighting_plus_ambient() {
+        let (world, lights) = diffuse_scene();
+        let whitted = render(&mut test_camera(64).with_integrator(WhittedIntegrator), &world, &lights);
+        let ambient = render(&mut test_camera(64).with_integrator(Ambient), &world, &lights);
+        let direct = render(&mut test_camera(64).with_integrator(DirectLightingIntegrator), &world, &lights);
+        assert_close(mean(&whitted) - mean(&ambient), mean(&direct), 0.005);
+    }
+
+    // Whitted rays that never split at glass, picking reflection or refraction at random
+    struct UnsplitWhitted;
+
+    impl Integrator for UnsplitWhitted {
+        fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+            WhittedIntegrator.trace(r, scene.max_depth, 0.0, scene, sampler)
+        }
+    }
+
+    #[test]
+    fn whitted_glass_splits_match_random_choices() {
+        let (mut world, lights) = diffuse_scene();
+        world.add(Box::new(Sphere::new(Point3::new(0.0, 1.4, 0.0), 0.8, Arc::new(Dielectric::new(1.5)))));
+        let split = render(&mut test_camera(16).with_max_depth(6).with_integrator(WhittedIntegrator), &world, &lights);
+        let unsplit = render(&mut test_camera(256).with_max_depth(6).with_integrator(UnsplitWhitted), &world, &lights);
+        assert_close(mean(&split), mean(&unsplit), 0.02);
+    }
+}
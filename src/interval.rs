@@ -4,6 +4,12 @@ pub struct Interval {
     pub max: f64,
 }
 
+impl Default for Interval {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
 impl Interval {
     pub const EMPTY: Interval = Interval {
         min: f64::INFINITY,
@@ -19,8 +25,9 @@ impl Interval {
         Interval { min, max }
     }
 
-    pub fn default() -> Self {
-        Self::EMPTY
+    // Smallest interval holding both
+    pub fn enclosing(a: Interval, b: Interval) -> Self {
+        Interval { min: a.min.min(b.min), max: a.max.max(b.max) }
     }
 
     pub fn size(&self) -> f64 {
@@ -35,6 +42,11 @@ impl Interval {
         self.min < x && x < self.max
     }
 
+    pub fn expand(&self, delta: f64) -> Interval {
+        let padding = delta / 2.0;
+        Interval { min: self.min - padding, max: self.max + padding }
+    }
+
     pub fn clamp(&self, x: f64) -> f64 {
         if x < self.min {
             return self.min;
@@ -0,0 +1,269 @@
+use crate::aperture::Aperture;
+use crate::vec3::{dot, unit_vector, Point3, Vec3};
+
+// One surface of a lens description, in millimeters, listed from the scene side towards
+// the film like a lens patent table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensElement {
+    // Radius of the spherical surface, positive when its center lies towards the film. Zero
+    // marks the aperture stop.
+    pub curvature_radius: f64,
+    // Distance to the next surface, or to the film for the last one
+    pub thickness: f64,
+    // Index of refraction between this surface and the next, 0 or 1 for air
+    pub ior: f64,
+    pub aperture_diameter: f64,
+}
+
+impl LensElement {
+    pub fn new(curvature_radius: f64, thickness: f64, ior: f64, aperture_diameter: f64) -> Self {
+        Self { curvature_radius, thickness, ior, aperture_diameter }
+    }
+
+    fn is_stop(&self) -> bool {
+        self.curvature_radius == 0.0
+    }
+
+    fn medium_ior(&self) -> f64 {
+        if self.ior == 0.0 { 1.0 } else { self.ior }
+    }
+}
+
+// Multi-element lens traced surface by surface, after Kolb et al.'s realistic camera.
+// Lens space has the film at z = 0 and the scene towards -z, all lengths in millimeters.
+#[derive(Debug, Clone)]
+pub struct LensSystem {
+    pub elements: Vec<LensElement>,
+    pub sensor_diagonal: f64,
+    // Scene units per millimeter
+    pub scale: f64,
+}
+
+impl LensSystem {
+    // Full frame sensor, scene units of one meter
+    pub fn new(elements: Vec<LensElement>) -> Self {
+        Self { elements, sensor_diagonal: 43.27, scale: 0.001 }
+    }
+
+    pub fn with_sensor_diagonal(mut self, sensor_diagonal: f64) -> Self {
+        self.sensor_diagonal = sensor_diagonal;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    // The 50mm f/2 double Gauss design from US patent 2,673,491
+    pub fn double_gauss_50mm() -> Self {
+        Self::new(vec![
+            LensElement::new(29.475, 3.76, 1.67, 25.2),
+            LensElement::new(84.83, 0.12, 1.0, 25.2),
+            LensElement::new(19.275, 4.025, 1.67, 23.0),
+            LensElement::new(40.77, 3.275, 1.699, 23.0),
+            LensElement::new(12.75, 5.705, 1.0, 18.0),
+            LensElement::new(0.0, 4.5, 0.0, 17.1),
+            LensElement::new(-14.495, 1.18, 1.603, 17.0),
+            LensElement::new(40.77, 6.065, 1.658, 20.0),
+            LensElement::new(-20.385, 0.19, 1.0, 20.0),
+            LensElement::new(437.065, 3.22, 1.717, 20.0),
+            LensElement::new(-39.73, 37.0, 1.0, 20.0),
+        ])
+    }
+
+    pub fn front_z(&self) -> f64 {
+        -self.elements.iter().map(|e| e.thickness).sum::<f64>()
+    }
+
+    pub fn rear_z(&self) -> f64 {
+        -self.elements.last().map_or(0.0, |e| e.thickness)
+    }
+
+    pub fn rear_radius(&self) -> f64 {
+        self.elements.last().map_or(0.0, |e| e.aperture_diameter / 2.0)
+    }
+
+    // Follows a ray leaving the film through every surface. None if a lens barrel or the
+    // stop blocks it or it reflects internally. The stop takes the shape of `aperture`.
+    pub fn trace_from_film(&self, origin: Point3, direction: Vec3, aperture: &Aperture) -> Option<(Point3, Vec3)> {
+        let (mut o, mut d) = (origin, unit_vector(direction));
+        let mut element_z = 0.0;
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            element_z -= element.thickness;
+            let outside = if i > 0 { self.elements[i - 1].medium_ior() } else { 1.0 };
+            (o, d) = cross_surface(element, element_z, o, d, element.medium_ior() / outside, aperture)?;
+        }
+        Some((o, d))
+    }
+
+    // Follows a ray from the scene through every surface towards the film
+    pub fn trace_from_scene(&self, origin: Point3, direction: Vec3, aperture: &Aperture) -> Option<(Point3, Vec3)> {
+        let (mut o, mut d) = (origin, unit_vector(direction));
+        let mut element_z = self.front_z();
+        for (i, element) in self.elements.iter().enumerate() {
+            let outside = if i > 0 { self.elements[i - 1].medium_ior() } else { 1.0 };
+            (o, d) = cross_surface(element, element_z, o, d, outside / element.medium_ior(), aperture)?;
+            element_z += element.thickness;
+        }
+        Some((o, d))
+    }
+
+    // Moves the film so that objects `focus_distance` millimeters in front of it are sharp,
+    // using the thick lens the system behaves like near the axis
+    pub fn focused(&self, focus_distance: f64) -> Option<Self> {
+        let (pz_scene, pz_film, fz_film) = self.cardinal_points()?;
+        let f = fz_film - pz_film;
+        // The film moving back by delta shifts the lens the same amount away from the object,
+        // so solve 1/(a - delta) + 1/(delta - b) = 1/f with the object and image distances
+        // measured for the film left in place
+        let a = pz_scene + focus_distance;
+        let b = pz_film;
+        let discriminant = (a - b) * (a - b - 4.0 * f);
+        if discriminant < 0.0 {
+            return None;
+        }
+        let delta = 0.5 * (a + b - discriminant.sqrt());
+
+        let mut focused = self.clone();
+        focused.elements.last_mut()?.thickness += delta;
+        Some(focused)
+    }
+
+    // Principal planes on both sides and the film side focal point, found by tracing rays
+    // parallel to the axis at a small height
+    fn cardinal_points(&self) -> Option<(f64, f64, f64)> {
+        let height = 0.001 * self.sensor_diagonal;
+        let aperture = Aperture::Circular;
+
+        let from_scene = Point3::new(height, 0.0, self.front_z() - 1.0);
+        let (o, d) = self.trace_from_scene(from_scene, Vec3::new(0.0, 0.0, 1.0), &aperture)?;
+        let (pz_film, fz_film) = cardinal_point(height, o, d)?;
+
+        let from_film = Point3::new(height, 0.0, self.rear_z() + 1.0);
+        let (o, d) = self.trace_from_film(from_film, Vec3::new(0.0, 0.0, -1.0), &aperture)?;
+        let (pz_scene, _) = cardinal_point(height, o, d)?;
+        Some((pz_scene, pz_film, fz_film))
+    }
+}
+
+// Intersects one surface at `z` and refracts through it with relative index `eta`
+fn cross_surface(element: &LensElement, z: f64, o: Point3, d: Vec3, eta: f64, aperture: &Aperture) -> Option<(Point3, Vec3)> {
+    let radius = element.aperture_diameter / 2.0;
+    if element.is_stop() {
+        let t = (z - o.z()) / d.z();
+        if t < 0.0 {
+            return None;
+        }
+        let p = o + d * t;
+        return aperture.contains(p.x() / radius, p.y() / radius).then_some((p, d));
+    }
+
+    let (t, normal) = intersect_spherical(element.curvature_radius, z + element.curvature_radius, o, d)?;
+    let p = o + d * t;
+    if p.x() * p.x() + p.y() * p.y() > radius * radius {
+        return None;
+    }
+    Some((p, refract(d, normal, eta)?))
+}
+
+// Where a ray that entered parallel at `height` crosses the axis and where its bend happens
+fn cardinal_point(height: f64, o: Point3, d: Vec3) -> Option<(f64, f64)> {
+    if d.x() == 0.0 {
+        return None;
+    }
+    let t_focus = -o.x() / d.x();
+    let t_principal = (height - o.x()) / d.x();
+    Some(((o + d * t_principal).z(), (o + d * t_focus).z()))
+}
+
+// Nearest useful hit with a sphere of `radius` centered on the axis at `center_z`, with
+// the normal facing the incoming ray
+fn intersect_spherical(radius: f64, center_z: f64, o: Point3, d: Vec3) -> Option<(f64, Vec3)> {
+    let oc = o - Point3::new(0.0, 0.0, center_z);
+    let a = d.length_squared();
+    let half_b = dot(oc, d);
+    let c = oc.length_squared() - radius * radius;
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let sqrt_d = discriminant.sqrt();
+    let (t0, t1) = ((-half_b - sqrt_d) / a, (-half_b + sqrt_d) / a);
+    // Convex towards the ray means the first hit, otherwise the second
+    let closer = (d.z() > 0.0) != (radius < 0.0);
+    let t = if closer { t0 } else { t1 };
+    if t < 0.0 {
+        return None;
+    }
+    let mut normal = unit_vector(oc + d * t);
+    if dot(normal, d) > 0.0 {
+        normal = -normal;
+    }
+    Some((t, normal))
+}
+
+// Snell's law for a unit direction against a normal facing it, None on total internal reflection
+fn refract(d: Vec3, normal: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = -dot(d, normal);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(d * eta + normal * (eta * cos_i - cos_t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where a ray coming out of the lens towards the film lands on it
+    fn on_film((o, d): (Point3, Vec3)) -> Point3 {
+        o + d * (-o.z() / d.z())
+    }
+
+    // Largest distance from the axis on the film of rays from an axis point `distance` in
+    // front of the film through the front element
+    fn spot_radius(lens: &LensSystem, distance: f64) -> f64 {
+        let object = Point3::new(0.0, 0.0, -distance);
+        let front = lens.front_z();
+        let mut radius: f64 = 0.0;
+        for k in 1..=8 {
+            let target = Point3::new(0.5 * k as f64, 0.0, front);
+            let exit = lens.trace_from_scene(object, target - object, &Aperture::Circular).unwrap();
+            radius = radius.max(on_film(exit).x().abs());
+        }
+        radius
+    }
+
+    #[test]
+    fn rays_retrace_their_path_backwards() {
+        let lens = LensSystem::double_gauss_50mm();
+        let film = Point3::new(1.0, -1.0, 0.0);
+        for (x, y) in [(0.0, 0.0), (2.0, 1.0), (-1.5, 3.0)] {
+            let rear = Point3::new(x, y, lens.rear_z());
+            let (o, d) = lens.trace_from_film(film, rear - film, &Aperture::Circular).unwrap();
+            assert!(d.z() < 0.0);
+            let back = lens.trace_from_scene(o + d * 10.0, -d, &Aperture::Circular).unwrap();
+            assert!((on_film(back) - film).length() < 1e-9, "{:?}", on_film(back));
+        }
+        // Rays outside the stop's shape are blocked
+        let blades = Aperture::Polygon { blades: 3, rotation: 0.0 };
+        let rear = Point3::new(0.0, -8.0, lens.rear_z());
+        assert!(lens.trace_from_film(Point3::new(0.0, 0.0, 0.0), rear, &Aperture::Circular).is_some());
+        assert!(lens.trace_from_film(Point3::new(0.0, 0.0, 0.0), rear, &blades).is_none());
+    }
+
+    #[test]
+    fn focusing_brings_the_focus_distance_to_a_point() {
+        let lens = LensSystem::double_gauss_50mm();
+        // The film moves back for closer objects
+        assert!(lens.focused(500.0).unwrap().rear_z() < lens.focused(2000.0).unwrap().rear_z());
+        for distance in [500.0, 2000.0] {
+            let focused = lens.focused(distance).unwrap();
+            assert!(spot_radius(&focused, distance) < 0.01, "{}", spot_radius(&focused, distance));
+            assert!(spot_radius(&lens, distance) > 3.0 * spot_radius(&focused, distance));
+        }
+    }
+}
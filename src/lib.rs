@@ -0,0 +1,36 @@
+pub mod vec3;
+pub mod color;
+pub mod tonemap;
+pub mod ray;
+pub mod hittable;
+pub mod hittable_list;
+pub mod aabb;
+pub mod bvh;
+pub mod transform;
+pub mod sphere;
+pub mod interval;
+pub mod camera;
+pub mod projection;
+pub mod aperture;
+pub mod lens;
+pub mod stereo;
+pub mod material;
+pub mod texture;
+pub mod spectrum;
+pub mod framebuffer;
+pub mod denoise;
+pub mod aov;
+pub mod exr;
+pub mod cryptomatte;
+pub mod interrupt;
+pub mod sampler;
+pub mod filter;
+pub mod tile;
+pub mod stats;
+pub mod onb;
+pub mod integrator;
+pub mod bdpt;
+pub mod photon;
+pub mod mlt;
+pub mod animation;
+pub mod preview;
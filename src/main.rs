@@ -1,23 +1,15 @@
 use rand::Rng;
 
-use crate::camera::Camera;
-use crate::color::Color;
-use crate::hittable_list::HittableList;
-use crate::sphere::Sphere;
-use crate::vec3::{random_vector, Point3, Vec3};
-use crate::material::{Dielectric, Lambertian, Material, Metal};
+use raytracing::bvh::Bvh;
+use raytracing::camera::Camera;
+use raytracing::color::Color;
+use raytracing::hittable_list::HittableList;
+use raytracing::sphere::Sphere;
+use raytracing::vec3::{random_vector, Point3, Vec3};
+use raytracing::material::{Dielectric, Lambertian, Material, Metal};
+use raytracing::preview::{open_preview, run_preview};
 use std::sync::Arc;
 
-mod vec3;
-mod color;
-mod ray;
-mod hittable;
-mod hittable_list;
-mod sphere;
-mod interval;
-mod camera;
-mod material;
-
 
 fn main() {
     // STAR PLATINUM THE WORLD! BWOOOOOSH
@@ -62,6 +54,9 @@ fn main() {
     world.add(Box::new(Sphere::new(Point3::new(-4.0,1.0,0.0), 1.0, material_2)));
     world.add(Box::new(Sphere::new(Point3::new(4.0,1.0,0.0), 1.0, material_3)));
 
+    // Hundreds of small spheres, so rays go through a BVH instead of testing every one
+    let world = Bvh::new(world);
+
 
     let mut camera: Camera = Camera::new()
         .with_aspect_ratio(16.0/9.0)
@@ -75,5 +70,15 @@ fn main() {
         .with_defocus_angle(0.6)
         .with_focus_dist(10.0);
 
+    // Look around interactively instead of rendering to stdout
+    if std::env::args().any(|arg| arg == "--preview") {
+        let previewed = open_preview("Raytracing in Rust")
+            .and_then(|mut backend| run_preview(&mut camera, &world, &HittableList::new(), backend.as_mut()));
+        if let Err(err) = previewed {
+            eprintln!("Preview failed: {}", err);
+        }
+        return;
+    }
+
     camera.render(&world);
 }
\ No newline at end of file
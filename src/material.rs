@@ -1,5 +1,8 @@
-use crate::{color::Color, hittable::HitRecord, ray::Ray, vec3::{dot, random_unit_vector, reflect, refract, unit_vector, Vec3}};
-use rand::{Rng, RngCore};
+use std::f64::consts::PI;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{color::{Color, WorkingColor}, hittable::HitRecord, ray::Ray, sampler::Sampler, texture::{SolidColor, Texture}, vec3::{dot, reflect, refract, sample_unit_sphere, unit_vector, Vec3}};
 
 pub struct Scatter {
     pub attenuation: Color,
@@ -7,77 +10,284 @@ pub struct Scatter {
 }
 
 pub trait Material: Send + Sync {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<Scatter>;
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter>;
+
+    // Spectral variant of `scatter`, where `lambda` is the hero wavelength in nanometers.
+    // Only materials whose behavior depends on wavelength need to override this.
+    fn scatter_at_wavelength(&self, r_in: &Ray, rec: &HitRecord, _lambda: f64, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        self.scatter(r_in, rec, sampler)
+    }
+
+    // True if scattering depends on wavelength, which terminates the secondary wavelengths of a spectral path
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    // Radiance the surface emits towards the viewer
+    fn emitted(&self, _rec: &HitRecord) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    // BSDF value for light arriving along `wi` and leaving along `wo`. Both directions are
+    // unit length and point away from the surface.
+    fn eval(&self, _rec: &HitRecord, _wo: Vec3, _wi: Vec3) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    // Solid angle density with which `scatter` picks `wi` when the viewer is along `wo`
+    fn pdf(&self, _rec: &HitRecord, _wo: Vec3, _wi: Vec3) -> f64 {
+        0.0
+    }
+
+    // Specular materials only scatter into a few discrete directions, so `eval` and `pdf`
+    // are meaningless for them and light sampling skips them
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    // Every direction a specular surface splits a ray into, each attenuated by the share of
+    // light going that way, for integrators that follow them all. None if `scatter` has to
+    // pick one at random.
+    fn specular_split(&self, _r_in: &Ray, _rec: &HitRecord) -> Option<Vec<Scatter>> {
+        None
+    }
+
+    // Base reflectance, used by the debug integrator
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+}
+
+// Material IDs for one scene, from 1 up in the order its objects first use them, so the
+// same scene numbers its materials the same way every run. Holding the materials keeps
+// their addresses, which identify them, from being reused while the IDs are around.
+#[derive(Clone, Default)]
+pub struct MaterialIds {
+    materials: Vec<(Arc<dyn Material + Send + Sync>, Option<String>)>,
+    ids: HashMap<usize, u32>,
+}
+
+fn material_key(material: &Arc<dyn Material + Send + Sync>) -> usize {
+    Arc::as_ptr(material) as *const () as usize
+}
+
+impl MaterialIds {
+    pub fn add(&mut self, material: &Arc<dyn Material + Send + Sync>) -> u32 {
+        let next = self.materials.len() as u32 + 1;
+        let id = *self.ids.entry(material_key(material)).or_insert(next);
+        if id == next {
+            self.materials.push((material.clone(), None));
+        }
+        id
+    }
+
+    // Gives `material` a name for ID mattes and returns its ID
+    pub fn name(&mut self, material: &Arc<dyn Material + Send + Sync>, name: impl Into<String>) -> u32 {
+        let id = self.add(material);
+        self.materials[id as usize - 1].1 = Some(name.into());
+        id
+    }
+
+    // Adds the materials of `other` in its order, with their names
+    pub fn extend(&mut self, other: &MaterialIds) {
+        for (material, name) in &other.materials {
+            match name {
+                Some(name) => self.name(material, name.clone()),
+                None => self.add(material),
+            };
+        }
+    }
+
+    // 0 for materials the scene doesn't use
+    pub fn id(&self, material: &Arc<dyn Material + Send + Sync>) -> u32 {
+        self.ids.get(&material_key(material)).copied().unwrap_or(0)
+    }
+
+    pub fn name_of(&self, id: u32) -> Option<&str> {
+        self.materials.get((id as usize).checked_sub(1)?)?.1.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
 }
 
 pub struct Lambertian {
-    albedo: Color,
+    tex: Arc<dyn Texture>,
 }
 
 impl Lambertian {
-    pub fn new(albedo: Color) -> Self { Self { albedo } }
+    pub fn new(albedo: Color) -> Self { Self::from_texture(Arc::new(SolidColor::new(albedo))) }
+
+    pub fn from_texture(tex: Arc<dyn Texture>) -> Self { Self { tex } }
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<Scatter> {
-        let mut scatter_direction = rec.normal + random_unit_vector(rng);
+    fn scatter(&self, _r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        let mut scatter_direction = rec.normal + sample_unit_sphere(sampler.get_2d());
 
         if scatter_direction.near_zero() {
             scatter_direction = rec.normal;
         }
 
-        Some (Scatter { attenuation: self.albedo, ray: Ray::new(rec.p, scatter_direction) } )
+        Some (Scatter { attenuation: self.tex.value(rec.u, rec.v, rec.p, rec.working_space), ray: Ray::new(rec.p, scatter_direction) } )
+    }
+
+    fn eval(&self, rec: &HitRecord, _wo: Vec3, wi: Vec3) -> Color {
+        if dot(rec.normal, wi) <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0)
+        }
+        self.tex.value(rec.u, rec.v, rec.p, rec.working_space) / PI
+    }
+
+    fn pdf(&self, rec: &HitRecord, _wo: Vec3, wi: Vec3) -> f64 {
+        // normal + uniform sphere direction is cosine distributed
+        dot(rec.normal, wi).max(0.0) / PI
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.tex.value(rec.u, rec.v, rec.p, rec.working_space)
     }
 }
 
 pub struct Metal {
-    albedo: Color,
+    albedo: WorkingColor,
     fuzz: f64
 }
 
 impl Metal {
-    pub fn new(albedo: Color, fuzz: f64) -> Self { Self { albedo, fuzz } }
+    pub fn new(albedo: Color, fuzz: f64) -> Self { Self { albedo: WorkingColor::from_linear_srgb(albedo), fuzz } }
 }
 
 impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<Scatter> {
-        let reflected = unit_vector(reflect(r_in.direction(), rec.normal)) + (random_unit_vector(rng) * self.fuzz);
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        let reflected = unit_vector(reflect(r_in.direction(), rec.normal)) + (sample_unit_sphere(sampler.get_2d()) * self.fuzz);
         if dot(reflected, rec.normal) <= 0.0 {
             return None;
         }
         Some( Scatter {
-            attenuation: self.albedo,
+            attenuation: self.albedo.get(rec.working_space),
             ray: Ray::new(rec.p, reflected)
         })
     }
+
+    fn eval(&self, rec: &HitRecord, wo: Vec3, wi: Vec3) -> Color {
+        let cos_theta = dot(rec.normal, wi);
+        if self.is_specular() || cos_theta <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0)
+        }
+        // `scatter` weights every direction it picks by the albedo alone
+        self.albedo.get(rec.working_space) * (self.pdf(rec, wo, wi) / cos_theta)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wo: Vec3, wi: Vec3) -> f64 {
+        if self.is_specular() || dot(rec.normal, wi) <= 0.0 {
+            return 0.0
+        }
+        // `scatter` picks a uniform point on the sphere of radius fuzz around the mirror
+        // direction. Each place the ray along `wi` crosses that sphere at distance t adds
+        // t^2 / (area * |cos|) to the solid angle density.
+        let reflected = reflect(-wo, rec.normal);
+        let b = dot(wi, reflected);
+        let discriminant = b * b - 1.0 + self.fuzz * self.fuzz;
+        if discriminant <= 0.0 {
+            return 0.0
+        }
+        let root = discriminant.sqrt();
+        let density: f64 = [b - root, b + root].iter().filter(|&&t| t > 0.0).map(|t| t * t).sum();
+        density / (4.0 * PI * self.fuzz * root)
+    }
+
+    // A perfect mirror is a delta lobe, a fuzzy one spreads over a cone
+    fn is_specular(&self) -> bool {
+        self.fuzz == 0.0
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.albedo.get(rec.working_space)
+    }
+}
+
+// Index of refraction as a function of wavelength
+#[derive(Debug, Clone, Copy)]
+pub enum Ior {
+    Constant(f64),
+    // n = a + b / lambda^2, lambda in micrometers
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + sum(b_i * lambda^2 / (lambda^2 - c_i)), lambda in micrometers
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Ior {
+    // Wavelength used when rendering in RGB (sodium d-line)
+    pub const RGB_WAVELENGTH: f64 = 587.6;
+
+    // Sellmeier coefficients for Schott N-BK7 crown glass
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+
+    pub fn at(&self, lambda_nm: f64) -> f64 {
+        let lambda_um = lambda_nm / 1000.0;
+        let l2 = lambda_um * lambda_um;
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy { a, b } => a + b / l2,
+            Ior::Sellmeier { b, c } => {
+                let sum: f64 = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        matches!(self, Ior::Constant(_))
+    }
 }
 
 pub struct Dielectric {
-    refraction_index: f64,
+    ior: Ior,
 }
 
 impl Dielectric {
-    pub fn new(refraction_index: f64) -> Self { Self {refraction_index} }
-}
+    pub fn new(refraction_index: f64) -> Self { Self { ior: Ior::Constant(refraction_index) } }
 
-impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<Scatter> {
+    pub fn cauchy(a: f64, b: f64) -> Self { Self { ior: Ior::Cauchy { a, b } } }
+
+    pub fn sellmeier(b: [f64; 3], c: [f64; 3]) -> Self { Self { ior: Ior::Sellmeier { b, c } } }
+
+    pub fn with_ior(ior: Ior) -> Self { Self { ior } }
+
+    // The reflected direction, the refracted one unless there is total internal reflection,
+    // and the fraction of light that gets reflected
+    fn split_with_index(&self, refraction_index: f64, r_in: &Ray, rec: &HitRecord) -> (Vec3, Option<Vec3>, f64) {
         let ri: f64 = if rec.front_face {
-            1.0/self.refraction_index
+            1.0/refraction_index
         } else {
-            self.refraction_index
+            refraction_index
         };
         let unit_direction = unit_vector(r_in.direction());
 
         let cos_theta = dot(-unit_direction, rec.normal).min(1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
-        let cannot_refract = ri * sin_theta > 1.0;
+        let reflected = reflect(unit_direction, rec.normal);
+        if ri * sin_theta > 1.0 {
+            return (reflected, None, 1.0);
+        }
+        (reflected, Some(refract(unit_direction, rec.normal, ri)), reflectance(cos_theta, ri))
+    }
 
-        let direction: Vec3 = if (cannot_refract || reflectance(cos_theta, ri) > rng.random()) {
-            reflect(unit_direction, rec.normal)
-        } else {
-            refract(unit_direction, rec.normal, ri)
+    fn scatter_with_index(&self, refraction_index: f64, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        let (reflected, refracted, fresnel) = self.split_with_index(refraction_index, r_in, rec);
+        let direction: Vec3 = match refracted {
+            Some(refracted) if fresnel <= sampler.get_1d() => refracted,
+            _ => reflected,
         };
         Some( Scatter {
             attenuation: Color::new(1.0,1.0,1.0),
@@ -86,8 +296,120 @@ impl Material for Dielectric {
     }
 }
 
+impl Material for Dielectric {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        self.scatter_with_index(self.ior.at(Ior::RGB_WAVELENGTH), r_in, rec, sampler)
+    }
+
+    fn scatter_at_wavelength(&self, r_in: &Ray, rec: &HitRecord, lambda: f64, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        self.scatter_with_index(self.ior.at(lambda), r_in, rec, sampler)
+    }
+
+    fn specular_split(&self, r_in: &Ray, rec: &HitRecord) -> Option<Vec<Scatter>> {
+        let (reflected, refracted, fresnel) = self.split_with_index(self.ior.at(Ior::RGB_WAVELENGTH), r_in, rec);
+        let mut split = vec![Scatter { attenuation: Color::new(fresnel, fresnel, fresnel), ray: Ray::new(rec.p, reflected) }];
+        if let Some(refracted) = refracted {
+            let transmitted = 1.0 - fresnel;
+            split.push(Scatter { attenuation: Color::new(transmitted, transmitted, transmitted), ray: Ray::new(rec.p, refracted) });
+        }
+        Some(split)
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn is_dispersive(&self) -> bool {
+        !self.ior.is_constant()
+    }
+}
+
 fn reflectance(cosine: f64, refraction_index: f64) -> f64 {
     // Using schlicks approximation
     let r0 = ((1.0 - refraction_index) / (1.0 + refraction_index)).powi(2);
     r0 + (1.0 - r0) * (1.0-cosine).powi(5)
 }
+
+// Emits light and doesn't scatter any
+pub struct DiffuseLight {
+    emit: WorkingColor,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Color) -> Self { Self { emit: WorkingColor::from_linear_srgb(emit) } }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _r_in: &Ray, _rec: &HitRecord, _sampler: &mut dyn Sampler) -> Option<Scatter> {
+        None
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        if !rec.front_face {
+            return Color::new(0.0, 0.0, 0.0)
+        }
+        self.emit.get(rec.working_space)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.emit.get(rec.working_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::ColorSpace;
+    use crate::sampler::IndependentSampler;
+
+    fn record(mat: Arc<dyn Material + Send + Sync>) -> HitRecord {
+        HitRecord {
+            t: 1.0,
+            p: Vec3::new(0.0, 0.0, 0.0),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            u: 0.0,
+            v: 0.0,
+            front_face: true,
+            object_id: 0,
+            working_space: ColorSpace::default(),
+            mat,
+        }
+    }
+
+    #[test]
+    fn fuzzy_metal_pdf_matches_scatter() {
+        let mut sampler = IndependentSampler::new(3);
+        sampler.start_pixel_sample(0, 0, 0);
+        // Grazing view so some of the fuzz falls below the surface, both inside and
+        // outside the sphere of directions
+        let wo = unit_vector(Vec3::new(1.0, 0.0, 0.3));
+        for fuzz in [0.3, 1.5] {
+            let metal = Arc::new(Metal::new(Color::new(0.8, 0.6, 0.4), fuzz));
+            let rec = record(metal.clone());
+            assert!(!metal.is_specular());
+
+            // Averaging 1 / pdf over the directions `scatter` picks measures the solid angle
+            // they can land in, which uniform directions measure too
+            let n = 200_000;
+            let r_in = Ray::new(wo, -wo);
+            let scattered = (0..n)
+                .filter_map(|_| metal.scatter(&r_in, &rec, &mut sampler))
+                .map(|scatter| 1.0 / metal.pdf(&rec, wo, unit_vector(scatter.ray.direction())))
+                .sum::<f64>() / n as f64;
+            let uniform = (0..n).filter(|_| metal.pdf(&rec, wo, sample_unit_sphere(sampler.get_2d())) > 0.0).count() as f64 * 4.0 * PI / n as f64;
+            assert!((scattered - uniform).abs() < 0.02 * uniform, "fuzz {}: scatter covers {} sr, pdf covers {} sr", fuzz, scattered, uniform);
+
+            let wi = unit_vector(Vec3::new(-1.0, 0.1, 0.5));
+            let cos_theta = dot(rec.normal, wi);
+            let weight = metal.eval(&rec, wo, wi) * cos_theta / metal.pdf(&rec, wo, wi);
+            assert!((weight - Color::new(0.8, 0.6, 0.4)).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn only_delta_lobes_are_specular() {
+        assert!(Metal::new(Color::new(1.0, 1.0, 1.0), 0.0).is_specular());
+        assert!(Dielectric::new(1.5).is_specular());
+        assert!(!Lambertian::new(Color::new(1.0, 1.0, 1.0)).is_specular());
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex, RwLock};
+
+use rayon::prelude::*;
+
+use crate::color::{luminance, Color};
+use crate::framebuffer::SplatSink;
+use crate::integrator::{Integrator, Scene};
+use crate::ray::Ray;
+use crate::sampler::{hash, IndependentSampler, Sampler};
+use crate::stats::{self, Counter};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PrimarySample {
+    value: f64,
+    // Iteration that last changed `value`
+    last_modification: u64,
+    value_backup: f64,
+    modification_backup: u64,
+}
+
+// Primary sample space point that mutates instead of being drawn fresh (Kelemen et al.).
+// Every dimension a path asks for is one coordinate; coordinates are brought up to date
+// lazily, so paths may use as many dimensions as they like.
+pub struct MltSampler {
+    rng: IndependentSampler,
+    sigma: f64,
+    large_step_probability: f64,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    index: usize,
+}
+
+impl MltSampler {
+    // The first path traced with a new sampler is a large step, so samplers built with the
+    // same seed and stream start out at the same point
+    pub fn new(seed: u64, stream: u64, sigma: f64, large_step_probability: f64) -> Self {
+        let mut sampler = Self {
+            rng: IndependentSampler::new(seed),
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0,
+        };
+        sampler.reseed(stream);
+        sampler
+    }
+
+    // Switches the random numbers used for future mutations to another stream
+    pub fn reseed(&mut self, stream: u64) {
+        self.rng.start_pixel_sample((stream >> 32) as i32, stream as i32, 0);
+    }
+
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.get_1d() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.last_modification == self.iteration {
+                sample.value = sample.value_backup;
+                sample.last_modification = sample.modification_backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn ensure_ready(&mut self, index: usize) {
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+        // Catch up on a large step that happened while this coordinate was unused
+        if self.samples[index].last_modification < self.last_large_step {
+            self.samples[index].value = self.rng.get_1d();
+            self.samples[index].last_modification = self.last_large_step;
+        }
+
+        let sample = &mut self.samples[index];
+        sample.value_backup = sample.value;
+        sample.modification_backup = sample.last_modification;
+        if self.large_step {
+            sample.value = self.rng.get_1d();
+        } else {
+            // Every skipped small step would have added its own Gaussian offset
+            let small_steps = self.iteration - sample.last_modification;
+            let (u1, u2) = (1.0 - self.rng.get_1d(), self.rng.get_1d());
+            let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+            sample.value += normal * self.sigma * (small_steps as f64).sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modification = self.iteration;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn start_pixel_sample(&mut self, _i: i32, _j: i32, _sample_index: u32) {
+        self.index = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let index = self.index;
+        self.ensure_ready(index);
+        self.index += 1;
+        self.samples[index].value
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// Collects the splats of one path so they can be weighted before reaching the film
+#[derive(Default)]
+struct SplatRecorder(Mutex<Vec<(f64, f64, Color)>>);
+
+impl SplatSink for SplatRecorder {
+    fn add_splat(&self, x: f64, y: f64, color: Color) {
+        self.0.lock().unwrap().push((x, y, color));
+    }
+}
+
+struct Chain {
+    sampler: MltSampler,
+    rng: IndependentSampler,
+    // Image contributions of the current state and their summed luminance
+    current: Vec<(f64, f64, Color)>,
+    current_f: f64,
+    recorder: SplatRecorder,
+}
+
+#[derive(Default)]
+struct Bootstrap {
+    // Average path luminance over the image, what the Markov chains are scaled by
+    b: f64,
+    // Running sums of the bootstrap path luminances, for picking chain starting points
+    cdf: Vec<f64>,
+}
+
+// Primary sample space Metropolis light transport (Kelemen et al.) on top of another
+// integrator. Paths are found by mutating the random numbers the inner integrator
+// consumes, so once a chain finds light through a narrow gap it keeps exploring around it.
+// Chains start from paths resampled out of a bootstrap set, which also provides the
+// normalization that makes the result match the inner integrator on average.
+//
+// Camera samples only schedule the work: each one runs `mutations_per_pixel` divided by
+// the camera's samples per pixel mutations of a chain and splats the results. Every tile
+// has its own chain, carried from pass to pass and only advanced while that tile renders,
+// so the same seed gives the same image however the tiles are spread over threads.
+pub struct MltIntegrator {
+    inner: Arc<dyn Integrator>,
+    pub mutations_per_pixel: u32,
+    pub bootstrap_samples: usize,
+    pub large_step_probability: f64,
+    // Standard deviation of a small step in primary sample space
+    pub sigma: f64,
+    pub seed: u64,
+    bootstrap: RwLock<Bootstrap>,
+    // By tile index
+    chains: Mutex<HashMap<usize, Chain>>,
+}
+
+impl MltIntegrator {
+    pub fn new<I: Integrator + 'static>(inner: I) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            mutations_per_pixel: 100,
+            bootstrap_samples: 100_000,
+            large_step_probability: 0.3,
+            sigma: 0.01,
+            seed: 0,
+            bootstrap: RwLock::new(Bootstrap::default()),
+            chains: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_mutations_per_pixel(mut self, mutations_per_pixel: u32) -> Self {
+        self.mutations_per_pixel = mutations_per_pixel;
+        self
+    }
+
+    pub fn with_bootstrap_samples(mut self, bootstrap_samples: usize) -> Self {
+        self.bootstrap_samples = bootstrap_samples;
+        self
+    }
+
+    pub fn with_large_step_probability(mut self, large_step_probability: f64) -> Self {
+        self.large_step_probability = large_step_probability;
+        self
+    }
+
+    pub fn with_sigma(mut self, sigma: f64) -> Self {
+        self.sigma = sigma;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    fn new_sampler(&self, stream: u64) -> MltSampler {
+        MltSampler::new(self.seed, stream, self.sigma, self.large_step_probability)
+    }
+
+    // Traces the path the sampler's current point describes. Returns everything it adds
+    // to the image and the summed luminance, the function the chains are distributed by.
+    fn evaluate(&self, scene: &Scene, sampler: &mut MltSampler, recorder: &SplatRecorder) -> (Vec<(f64, f64, Color)>, f64) {
+        let (width, height) = scene.camera.image_size();
+        let u = sampler.get_2d();
+        let raster = (u.0 * width as f64, u.1 * height as f64);
+        let Some(r) = scene.camera.generate_ray(raster, sampler) else {
+            return (Vec::new(), 0.0)
+        };
+        stats::increment(Counter::PrimaryRays);
+
+        let inner_scene = Scene { film: recorder, ..*scene };
+        let color = self.inner.ray_color(r, &inner_scene, sampler);
+        let mut contributions = std::mem::take(&mut *recorder.0.lock().unwrap());
+        contributions.push((raster.0, raster.1, color));
+        let f = contributions.iter().map(|(_, _, c)| luminance(*c).max(0.0)).sum();
+        (contributions, f)
+    }
+
+    fn run_bootstrap(&self, scene: &Scene) {
+        let weights: Vec<f64> = (0..self.bootstrap_samples)
+            .into_par_iter()
+            .map(|index| {
+                let mut sampler = self.new_sampler(index as u64);
+                self.evaluate(scene, &mut sampler, &SplatRecorder::default()).1
+            })
+            .collect();
+
+        let mut cdf = Vec::with_capacity(weights.len());
+        let mut sum = 0.0;
+        for weight in weights {
+            sum += weight;
+            cdf.push(sum);
+        }
+        let mut bootstrap = self.bootstrap.write().unwrap();
+        bootstrap.b = sum / self.bootstrap_samples.max(1) as f64;
+        bootstrap.cdf = cdf;
+        self.chains.lock().unwrap().clear();
+    }
+
+    // Starts the chain of the scene's tile at a bootstrap path picked in proportion to its
+    // luminance
+    fn new_chain(&self, scene: &Scene) -> Chain {
+        let chain_index = scene.tile as u64;
+        let mut rng = IndependentSampler::new(hash(&[self.seed, chain_index]));
+        rng.start_pixel_sample(0, 0, 0);
+
+        let start = {
+            let bootstrap = self.bootstrap.read().unwrap();
+            let total = bootstrap.cdf.last().copied().unwrap_or(0.0);
+            let target = rng.get_1d() * total;
+            bootstrap.cdf.partition_point(|&c| c <= target).min(bootstrap.cdf.len().saturating_sub(1))
+        };
+
+        let recorder = SplatRecorder::default();
+        let mut sampler = self.new_sampler(start as u64);
+        let (current, current_f) = self.evaluate(scene, &mut sampler, &recorder);
+        // Chains that start at the same path must not take the same steps from there
+        sampler.reseed(hash(&[start as u64, chain_index, 0x6d6c74]));
+        Chain { sampler, rng, current, current_f, recorder }
+    }
+
+    // One Metropolis step. Both the proposal and the current state are splatted with their
+    // expected weights, which keeps rejected proposals useful.
+    fn mutate(&self, chain: &mut Chain, scene: &Scene, scale: f64) {
+        chain.sampler.start_iteration();
+        let (proposed, proposed_f) = self.evaluate(scene, &mut chain.sampler, &chain.recorder);
+        let accept = if chain.current_f > 0.0 { (proposed_f / chain.current_f).min(1.0) } else { 1.0 };
+
+        if accept > 0.0 && proposed_f > 0.0 {
+            for &(x, y, color) in &proposed {
+                scene.film.add_splat(x, y, color * (accept * scale / proposed_f));
+            }
+        }
+        if accept < 1.0 && chain.current_f > 0.0 {
+            for &(x, y, color) in &chain.current {
+                scene.film.add_splat(x, y, color * ((1.0 - accept) * scale / chain.current_f));
+            }
+        }
+
+        if chain.rng.get_1d() < accept {
+            chain.current = proposed;
+            chain.current_f = proposed_f;
+            chain.sampler.accept();
+        } else {
+            chain.sampler.reject();
+        }
+    }
+}
+
+impl Integrator for MltIntegrator {
+    // Bootstraps at the start of every new render, and on the first pass run at all, which
+    // for a render resumed from a checkpoint comes after pass 1
+    fn begin_pass(&self, scene: &Scene, pass: u32) {
+        self.inner.begin_pass(scene, pass);
+        if pass == 1 || self.bootstrap.read().unwrap().cdf.is_empty() {
+            self.run_bootstrap(scene);
+        }
+    }
+
+    fn ray_color(&self, _r: Ray, scene: &Scene, _sampler: &mut dyn Sampler) -> Color {
+        let black = Color::new(0.0, 0.0, 0.0);
+        let b = self.bootstrap.read().unwrap().b;
+        if b <= 0.0 {
+            return black;
+        }
+
+        let mutations = self.mutations_per_pixel.div_ceil(scene.camera.samples_per_pixel.max(1) as u32).max(1);
+        let scale = b / mutations as f64;
+        let chain = self.chains.lock().unwrap().remove(&scene.tile);
+        let mut chain = chain.unwrap_or_else(|| self.new_chain(scene));
+        for _ in 0..mutations {
+            self.mutate(&mut chain, scene, scale);
+        }
+        self.chains.lock().unwrap().insert(scene.tile, chain);
+        black
+    }
+}
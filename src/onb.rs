@@ -0,0 +1,23 @@
+use crate::vec3::{cross, unit_vector, Vec3};
+
+// Orthonormal basis with `w` along a given direction, for sampling around normals and cones
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    pub fn new(n: Vec3) -> Self {
+        let w = unit_vector(n);
+        let a = if w.x().abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+        let v = unit_vector(cross(w, a));
+        let u = cross(w, v);
+        Self { u, v, w }
+    }
+
+    // Transform from basis coordinates to world space
+    pub fn transform(&self, a: Vec3) -> Vec3 {
+        (self.u * a.x()) + (self.v * a.y()) + (self.w * a.z())
+    }
+}
@@ -0,0 +1,326 @@
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+use std::sync::RwLock;
+
+use rayon::prelude::*;
+
+use crate::color::Color;
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::{sample_cosine_direction, Integrator, Scene};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::{IndependentSampler, Sampler};
+use crate::stats;
+use crate::vec3::{dot, unit_vector, Point3, Vec3};
+
+// Photons are traced in chunks of this many, one chunk per rayon task
+const PHOTON_CHUNK: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Photon {
+    pub p: Point3,
+    // Direction the photon arrived from, pointing away from the surface
+    pub wi: Vec3,
+    // Surface normal on the side the photon arrived from
+    pub normal: Vec3,
+    pub power: Color,
+    // Split axis when this photon is an interior kd-tree node
+    axis: u8,
+}
+
+// Photons in a left-balanced kd-tree stored implicitly: the median of every range is its
+// node, the halves either side are the subtrees.
+#[derive(Default)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+}
+
+impl PhotonMap {
+    pub fn build(mut photons: Vec<Photon>) -> Self {
+        build_node(&mut photons);
+        Self { photons }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // Calls `f` for every photon within `radius` of `p`
+    pub fn for_each_within(&self, p: Point3, radius: f64, mut f: impl FnMut(&Photon)) {
+        self.query(&self.photons, p, radius * radius, &mut f);
+    }
+
+    fn query(&self, photons: &[Photon], p: Point3, radius_squared: f64, f: &mut impl FnMut(&Photon)) {
+        if photons.is_empty() {
+            return;
+        }
+        let mid = photons.len() / 2;
+        let node = &photons[mid];
+        if (node.p - p).length_squared() <= radius_squared {
+            f(node);
+        }
+
+        let axis = node.axis as usize;
+        let d = p.e[axis] - node.p.e[axis];
+        let (near, far) = if d < 0.0 {
+            (&photons[..mid], &photons[mid + 1..])
+        } else {
+            (&photons[mid + 1..], &photons[..mid])
+        };
+        self.query(near, p, radius_squared, f);
+        if d * d <= radius_squared {
+            self.query(far, p, radius_squared, f);
+        }
+    }
+}
+
+fn build_node(photons: &mut [Photon]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let mut min = photons[0].p;
+    let mut max = photons[0].p;
+    for photon in photons.iter() {
+        for axis in 0..3 {
+            min.e[axis] = min.e[axis].min(photon.p.e[axis]);
+            max.e[axis] = max.e[axis].max(photon.p.e[axis]);
+        }
+    }
+    let extent = max - min;
+    let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+        0
+    } else if extent.y() > extent.z() {
+        1
+    } else {
+        2
+    };
+
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.p.e[axis].partial_cmp(&b.p.e[axis]).unwrap_or(Ordering::Equal));
+    photons[mid].axis = axis as u8;
+    let (left, right) = photons.split_at_mut(mid);
+    build_node(left);
+    build_node(&mut right[1..]);
+}
+
+struct PassState {
+    map: PhotonMap,
+    radius: f64,
+}
+
+// Stochastic progressive photon mapping (Knaus and Zwicker's probabilistic formulation).
+// Every pass shoots a fresh set of photons from the emitters in `Scene::lights` and
+// estimates the light they carry at the first diffuse surface each camera path reaches.
+// The gather radius shrinks from pass to pass, so the bias fades as passes accumulate.
+// Light from the sky and from emitters outside the light list is path traced as usual.
+pub struct PhotonMapIntegrator {
+    pub photons_per_pass: usize,
+    pub initial_radius: f64,
+    // Fraction of the photons kept from one pass to the next, between 0 and 1
+    pub alpha: f64,
+    // Camera samples per pixel taken with one photon map
+    pub samples_per_pass: u32,
+    state: RwLock<PassState>,
+}
+
+impl PhotonMapIntegrator {
+    pub fn new(photons_per_pass: usize, initial_radius: f64) -> Self {
+        Self {
+            photons_per_pass,
+            initial_radius,
+            alpha: 2.0 / 3.0,
+            samples_per_pass: 1,
+            state: RwLock::new(PassState { map: PhotonMap::default(), radius: initial_radius }),
+        }
+    }
+
+    pub fn with_alpha(mut self, alpha: f64) -> Self {
+        self.alpha = alpha;
+        self
+    }
+
+    pub fn with_samples_per_pass(mut self, samples_per_pass: u32) -> Self {
+        self.samples_per_pass = samples_per_pass;
+        self
+    }
+
+    // Gather radius for a pass, shrinking so that r_{i+1}^2 = r_i^2 (i + alpha) / (i + 1)
+    pub fn radius(&self, pass: u32) -> f64 {
+        let mut radius_squared = self.initial_radius * self.initial_radius;
+        for i in 1..pass.max(1) {
+            radius_squared *= (i as f64 + self.alpha) / (i as f64 + 1.0);
+        }
+        radius_squared.sqrt()
+    }
+
+    fn trace_photons(&self, scene: &Scene, pass: u32) -> Vec<Photon> {
+        let chunks = self.photons_per_pass.div_ceil(PHOTON_CHUNK);
+        (0..chunks)
+            .into_par_iter()
+            .flat_map_iter(|chunk| {
+                let mut sampler = IndependentSampler::new(scene.camera.seed);
+                let mut photons = Vec::new();
+                let end = ((chunk + 1) * PHOTON_CHUNK).min(self.photons_per_pass);
+                for index in chunk * PHOTON_CHUNK..end {
+                    sampler.start_pixel_sample(index as i32, -(pass as i32), 0);
+                    trace_photon(scene, &mut sampler, self.photons_per_pass, &mut photons);
+                }
+                photons
+            })
+            .collect()
+    }
+
+    // Radiance reflected towards `wo` estimated from the photons around `rec`
+    fn estimate(&self, rec: &HitRecord, wo: Vec3) -> Color {
+        let state = self.state.read().unwrap();
+        let mut flux = Color::new(0.0, 0.0, 0.0);
+        state.map.for_each_within(rec.p, state.radius, |photon| {
+            // Photons on a differently oriented surface nearby don't belong to this one
+            if dot(photon.normal, rec.normal) < 0.5 {
+                return;
+            }
+            flux += rec.mat.eval(rec, wo, photon.wi) * photon.power;
+        });
+        flux / (PI * state.radius * state.radius)
+    }
+}
+
+// Traces one photon from the lights, storing it at every diffuse surface it lands on
+fn trace_photon(scene: &Scene, sampler: &mut dyn Sampler, photon_count: usize, photons: &mut Vec<Photon>) {
+    let Some((rec, pdf_pos)) = scene.sample_light(sampler) else {
+        return;
+    };
+    let direction = Onb::new(rec.normal).transform(sample_cosine_direction(sampler.get_2d()));
+    let cos_theta = dot(rec.normal, direction);
+    if pdf_pos <= 0.0 || cos_theta <= 0.0 {
+        return;
+    }
+    // Cosine-weighted emission, so the cosine cancels against the direction density
+    let emitted = rec.mat.emitted(&rec) * (PI / (pdf_pos * photon_count as f64));
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+    let mut r = Ray::new(rec.p, direction);
+
+    for bounces in 0..scene.max_depth {
+        let Some(rec) = scene.hit(&r) else {
+            return;
+        };
+        let wi = -unit_vector(r.direction());
+        if !rec.mat.is_specular() {
+            photons.push(Photon { p: rec.p, wi, normal: rec.normal, power: emitted * throughput, axis: 0 });
+        }
+        let Some(sc) = rec.mat.scatter(&r, &rec, sampler) else {
+            return;
+        };
+        throughput = throughput * sc.attenuation;
+        let max_throughput = throughput.x().max(throughput.y()).max(throughput.z());
+        let Some(survival) = scene.russian_roulette(bounces + 1, max_throughput, sampler) else {
+            return;
+        };
+        throughput = throughput / survival;
+        r = sc.ray;
+    }
+}
+
+impl Integrator for PhotonMapIntegrator {
+    fn begin_pass(&self, scene: &Scene, pass: u32) {
+        let photons = self.trace_photons(scene, pass);
+        let mut state = self.state.write().unwrap();
+        state.map = PhotonMap::build(photons);
+        state.radius = self.radius(pass);
+    }
+
+    fn max_samples_per_pass(&self) -> Option<u32> {
+        Some(self.samples_per_pass.max(1))
+    }
+
+    fn ray_color(&self, r: Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Color {
+        let mut r = r;
+        let mut radiance = Color::new(0.0, 0.0, 0.0);
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut gathered = false;
+        let mut bounces = 0;
+
+        while bounces < scene.max_depth {
+            let Some(rec) = scene.hit(&r) else {
+                stats::record_path(bounces as usize);
+                return radiance + throughput * scene.background(&r)
+            };
+            // Once photons were gathered they account for everything the lights send
+            if !gathered || scene.lights.surface_pdf(rec.p) == 0.0 {
+                radiance += throughput * rec.mat.emitted(&rec);
+            }
+            let wo = -unit_vector(r.direction());
+            if !gathered && !rec.mat.is_specular() {
+                radiance += throughput * self.estimate(&rec, wo);
+                gathered = true;
+            }
+
+            let Some(sc) = rec.mat.scatter(&r, &rec, sampler) else {
+                break;
+            };
+            throughput = throughput * sc.attenuation;
+            bounces += 1;
+            let max_throughput = throughput.x().max(throughput.y()).max(throughput.z());
+            let Some(survival) = scene.russian_roulette(bounces, max_throughput, sampler) else {
+                break;
+            };
+            throughput = throughput / survival;
+            stats::increment(stats::Counter::SecondaryRays);
+            r = sc.ray;
+        }
+        stats::record_path(bounces as usize);
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::tests::{assert_close, diffuse_scene, mean, render, test_camera};
+    use crate::integrator::PathIntegrator;
+    use crate::sampler::hash;
+
+    fn point(seed: u64) -> Point3 {
+        let unit = |k: u64| (hash(&[seed, k]) >> 11) as f64 / (1u64 << 53) as f64;
+        Point3::new(unit(0), unit(1), unit(2))
+    }
+
+    #[test]
+    fn kd_tree_finds_the_same_photons_as_a_linear_scan() {
+        let photons: Vec<Photon> = (0..1000)
+            .map(|k| Photon { p: point(k), wi: Vec3::new(0.0, 1.0, 0.0), normal: Vec3::new(0.0, 1.0, 0.0), power: Color::new(k as f64, 0.0, 0.0), axis: 0 })
+            .collect();
+        let map = PhotonMap::build(photons.clone());
+        assert_eq!(map.len(), 1000);
+        for query in 0..20 {
+            let (p, radius) = (point(5000 + query), 0.05 + 0.01 * query as f64);
+            let mut found = Vec::new();
+            map.for_each_within(p, radius, |photon| found.push(photon.power.x() as u64));
+            found.sort();
+            let expected: Vec<u64> = photons.iter().filter(|photon| (photon.p - p).length() <= radius).map(|photon| photon.power.x() as u64).collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn radius_shrinks_by_the_progressive_schedule() {
+        let integrator = PhotonMapIntegrator::new(1000, 0.5).with_alpha(0.5);
+        assert_eq!(integrator.radius(1), 0.5);
+        let r2 = integrator.radius(2);
+        assert!((r2 * r2 - 0.25 * 1.5 / 2.0).abs() < 1e-12);
+        assert!(integrator.radius(100) < integrator.radius(10));
+    }
+
+    // The estimate is biased by the gather radius, so it only has to get close
+    #[test]
+    fn photon_mapping_converges_to_path_tracing() {
+        let (world, lights) = diffuse_scene();
+        let photons = render(&mut test_camera(16).with_progressive(true).with_integrator(PhotonMapIntegrator::new(5_000, 0.1)), &world, &lights);
+        let path = render(&mut test_camera(256).with_integrator(PathIntegrator), &world, &lights);
+        assert_close(mean(&photons), mean(&path), 0.03);
+    }
+}
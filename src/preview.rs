@@ -0,0 +1,693 @@
+use std::collections::VecDeque;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::camera::Camera;
+use crate::color::to_rgb8;
+use crate::framebuffer::FrameBuffer;
+use crate::hittable::Hittable;
+use crate::hittable_list::HittableList;
+use crate::interrupt;
+use crate::tonemap::DisplayTransform;
+use crate::vec3::{cross, dot, unit_vector, Vec3};
+
+// Degrees a key press orbits the camera by
+const KEY_ORBIT: f64 = 5.0;
+// Distance to the look-at point is scaled by this per zoom-in step
+const ZOOM_STEP: f64 = 0.9;
+// How often a finished preview checks for input
+const IDLE_POLL: Duration = Duration::from_millis(16);
+// Orbiting stops this far short of straight above or below the look-at point
+const MAX_ELEVATION: f64 = 89.0;
+
+// Input that changes the view or ends the preview
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreviewEvent {
+    // Degrees to swing the camera around the look-at point, yaw about `vup` (positive moves
+    // the camera to its right) and pitch towards `vup`
+    Orbit { yaw: f64, pitch: f64 },
+    // Scales the distance to the look-at point, below 1 moves closer
+    Zoom(f64),
+    Quit,
+}
+
+// Somewhere to show the image as it refines and to read the user's input from
+pub trait PreviewBackend {
+    // `pixels` are 0RGB, row by row from the top
+    fn present(&mut self, width: usize, height: usize, pixels: &[u32]) -> io::Result<()>;
+
+    // Input since the last call, without waiting
+    fn poll_events(&mut self) -> Vec<PreviewEvent>;
+
+    // Blocks until there's input, for when the image has all its samples. Gives up with
+    // nothing on Ctrl-C.
+    fn wait_events(&mut self) -> Vec<PreviewEvent> {
+        loop {
+            let events = self.poll_events();
+            if !events.is_empty() || interrupt::requested() {
+                return events;
+            }
+            thread::sleep(IDLE_POLL);
+        }
+    }
+}
+
+// Renders `world` into `backend` one sample per pixel at a time, up to the camera's
+// `samples_per_pixel`. Any camera move starts the accumulation over from the new view.
+// Runs until the backend sends Quit or Ctrl-C is pressed, and writes no files.
+pub fn run_preview(camera: &mut Camera, world: &dyn Hittable, lights: &HittableList, backend: &mut dyn PreviewBackend) -> io::Result<()> {
+    let display = camera.display;
+    loop {
+        let mut events = Vec::new();
+        let mut failed = None;
+        camera.render_passes(world, lights, &mut |framebuffer| {
+            if let Err(err) = backend.present(framebuffer.width, framebuffer.height, &preview_pixels(framebuffer, &display)) {
+                failed = Some(err);
+                return false;
+            }
+            events = backend.poll_events();
+            events.is_empty()
+        });
+        if let Some(err) = failed {
+            return Err(err);
+        }
+        if events.is_empty() && !interrupt::requested() {
+            events = backend.wait_events();
+        }
+        if interrupt::requested() {
+            return Ok(());
+        }
+
+        for event in events {
+            match event {
+                PreviewEvent::Orbit { yaw, pitch } => orbit(camera, yaw, pitch),
+                PreviewEvent::Zoom(factor) => zoom(camera, factor),
+                PreviewEvent::Quit => return Ok(()),
+            }
+        }
+    }
+}
+
+// The framebuffer's current estimate, display encoded and packed as 0RGB
+pub fn preview_pixels(framebuffer: &FrameBuffer, display: &DisplayTransform) -> Vec<u32> {
+    framebuffer
+        .resolve()
+        .into_iter()
+        .map(|c| {
+            let [r, g, b] = to_rgb8(display.apply(c));
+            (r as u32) << 16 | (g as u32) << 8 | b as u32
+        })
+        .collect()
+}
+
+// Swings `lookfrom` around `lookat` at the same distance
+pub fn orbit(camera: &mut Camera, yaw: f64, pitch: f64) {
+    let up = unit_vector(camera.vup);
+    let offset = camera.lookfrom - camera.lookat;
+    let distance = offset.length();
+    if distance <= 0.0 {
+        return;
+    }
+    let direction = offset / distance;
+
+    // Split the direction into a heading around `vup` and an elevation towards it
+    let elevation = dot(direction, up).clamp(-1.0, 1.0).asin().to_degrees();
+    let mut heading = direction - up * dot(direction, up);
+    if heading.length() < 1e-9 {
+        heading = cross(up, Vec3::new(1.0, 0.0, 0.0));
+        if heading.length() < 1e-9 {
+            heading = cross(up, Vec3::new(0.0, 0.0, 1.0));
+        }
+    }
+    let heading = unit_vector(heading);
+
+    let (sin_yaw, cos_yaw) = yaw.to_radians().sin_cos();
+    let heading = heading * cos_yaw + cross(up, heading) * sin_yaw;
+    let (sin_elevation, cos_elevation) = (elevation + pitch).clamp(-MAX_ELEVATION, MAX_ELEVATION).to_radians().sin_cos();
+    camera.lookfrom = camera.lookat + (heading * cos_elevation + up * sin_elevation) * distance;
+}
+
+// Moves `lookfrom` along its line to `lookat`
+pub fn zoom(camera: &mut Camera, factor: f64) {
+    if factor > 0.0 {
+        camera.lookfrom = camera.lookat + (camera.lookfrom - camera.lookat) * factor;
+    }
+}
+
+// Keeps the last frame and plays back scripted input instead of showing anything, for
+// running the preview without a display
+#[derive(Debug, Clone, Default)]
+pub struct HeadlessPreview {
+    // Each entry is sent once that many more frames have been presented, or as soon as the
+    // image has all its samples. Quits when the script runs out.
+    script: VecDeque<(usize, Vec<PreviewEvent>)>,
+    frames_since_events: usize,
+    pub frames_presented: usize,
+    // Width, height and pixels
+    pub last_frame: Option<(usize, usize, Vec<u32>)>,
+}
+
+impl HeadlessPreview {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_events_after(mut self, frames: usize, events: Vec<PreviewEvent>) -> Self {
+        self.script.push_back((frames, events));
+        self
+    }
+
+    fn next_events(&mut self) -> Vec<PreviewEvent> {
+        self.frames_since_events = 0;
+        match self.script.pop_front() {
+            Some((_, events)) => events,
+            None => vec![PreviewEvent::Quit],
+        }
+    }
+}
+
+impl PreviewBackend for HeadlessPreview {
+    fn present(&mut self, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+        self.frames_presented += 1;
+        self.frames_since_events += 1;
+        self.last_frame = Some((width, height, pixels.to_vec()));
+        Ok(())
+    }
+
+    fn poll_events(&mut self) -> Vec<PreviewEvent> {
+        match self.script.front() {
+            Some(&(frames, _)) if self.frames_since_events >= frames => self.next_events(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn wait_events(&mut self) -> Vec<PreviewEvent> {
+        self.next_events()
+    }
+}
+
+// How a terminal can be sent pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalGraphics {
+    // kitty's graphics protocol, full color
+    Kitty,
+    // DEC sixels, which xterm, foot, mlterm and others understand, limited to a 6x6x6 color cube
+    Sixel,
+}
+
+impl TerminalGraphics {
+    // kitty and terminals that copied its protocol say so in the environment, anything else
+    // gets sixels
+    pub fn detect() -> Self {
+        let term = std::env::var("TERM").unwrap_or_default();
+        let program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+        if std::env::var_os("KITTY_WINDOW_ID").is_some() || term.contains("kitty") || term.contains("ghostty") || program == "WezTerm" {
+            return TerminalGraphics::Kitty;
+        }
+        TerminalGraphics::Sixel
+    }
+
+    pub fn encode(&self, width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+        match self {
+            TerminalGraphics::Kitty => encode_kitty(width, height, pixels),
+            TerminalGraphics::Sixel => encode_sixel(width, height, pixels),
+        }
+    }
+}
+
+// Draws the preview in the controlling terminal and reads keys from it: arrows or WASD
+// orbit, + and - zoom, q or Esc quits
+#[cfg(unix)]
+pub struct TerminalPreview {
+    tty: std::fs::File,
+    graphics: TerminalGraphics,
+    saved: libc::termios,
+}
+
+#[cfg(unix)]
+impl TerminalPreview {
+    // Puts the terminal into raw mode until dropped. Ctrl-C still interrupts.
+    pub fn open(title: &str, graphics: TerminalGraphics) -> io::Result<Self> {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+
+        let mut tty = std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let fd = tty.as_raw_fd();
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        // Reads return at once with whatever has been typed
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Title, hidden cursor, cleared screen
+        write!(tty, "\x1b]2;{}\x07\x1b[?25l\x1b[2J", title)?;
+        tty.flush()?;
+        Ok(Self { tty, graphics, saved })
+    }
+}
+
+#[cfg(unix)]
+impl PreviewBackend for TerminalPreview {
+    fn present(&mut self, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+        use std::io::Write;
+
+        let mut out = b"\x1b[H".to_vec();
+        out.extend(self.graphics.encode(width, height, pixels));
+        self.tty.write_all(&out)?;
+        self.tty.flush()
+    }
+
+    fn poll_events(&mut self) -> Vec<PreviewEvent> {
+        use std::io::Read;
+
+        let mut input = Vec::new();
+        let mut buffer = [0u8; 64];
+        while let Ok(n) = self.tty.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            input.extend_from_slice(&buffer[..n]);
+        }
+        parse_keys(&input)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for TerminalPreview {
+    fn drop(&mut self) {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+
+        if self.graphics == TerminalGraphics::Kitty {
+            let _ = self.tty.write_all(b"\x1b_Ga=d,d=I,i=1,q=2\x1b\\");
+        }
+        let _ = self.tty.write_all(b"\x1b[?25h\r\n");
+        let _ = self.tty.flush();
+        unsafe {
+            libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+// Keys typed into a raw mode terminal, as preview input
+pub fn parse_keys(input: &[u8]) -> Vec<PreviewEvent> {
+    let orbit = |yaw, pitch| Some(PreviewEvent::Orbit { yaw, pitch });
+    let mut events = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        let event = match input[i] {
+            // Arrow keys, in normal or application cursor mode
+            0x1b if i + 2 < input.len() && matches!(input[i + 1], b'[' | b'O') => {
+                i += 2;
+                match input[i] {
+                    b'A' => orbit(0.0, KEY_ORBIT),
+                    b'B' => orbit(0.0, -KEY_ORBIT),
+                    b'C' => orbit(KEY_ORBIT, 0.0),
+                    b'D' => orbit(-KEY_ORBIT, 0.0),
+                    _ => None,
+                }
+            }
+            b'w' | b'W' => orbit(0.0, KEY_ORBIT),
+            b's' | b'S' => orbit(0.0, -KEY_ORBIT),
+            b'd' | b'D' => orbit(KEY_ORBIT, 0.0),
+            b'a' | b'A' => orbit(-KEY_ORBIT, 0.0),
+            b'+' | b'=' => Some(PreviewEvent::Zoom(ZOOM_STEP)),
+            b'-' | b'_' => Some(PreviewEvent::Zoom(1.0 / ZOOM_STEP)),
+            // q, Esc on its own and Ctrl-C
+            b'q' | b'Q' | 0x1b | 0x03 => Some(PreviewEvent::Quit),
+            _ => None,
+        };
+        events.extend(event);
+        i += 1;
+    }
+    events
+}
+
+// Transmits the frame as 24-bit RGB in base64 chunks. Reusing the image and placement IDs
+// makes every frame replace the last one in place.
+pub fn encode_kitty(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    let rgb: Vec<u8> = pixels.iter().flat_map(|&p| [(p >> 16) as u8, (p >> 8) as u8, p as u8]).collect();
+    let payload = base64(&rgb);
+    let chunks: Vec<&[u8]> = payload.chunks(4096).collect();
+    let mut out = Vec::with_capacity(payload.len() + chunks.len() * 16 + 64);
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = (index + 1 < chunks.len()) as u8;
+        if index == 0 {
+            out.extend(format!("\x1b_Ga=T,f=24,s={},v={},i=1,p=1,q=2,C=1,m={};", width, height, more).bytes());
+        } else {
+            out.extend(format!("\x1b_Gm={};", more).bytes());
+        }
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1b\\");
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> Vec<u8> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+    out
+}
+
+// Six rows of pixels at a time, each color of the 6x6x6 cube drawn over the band in turn
+// with repeated columns run-length encoded
+pub fn encode_sixel(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    let level = |v: u32| ((v & 0xff) * 5 + 127) / 255;
+    let index = |p: u32| (level(p >> 16) * 36 + level(p >> 8) * 6 + level(p)) as usize;
+
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height).into_bytes();
+    for color in 0..216 {
+        let (r, g, b) = (color / 36, color / 6 % 6, color % 6);
+        out.extend(format!("#{};2;{};{};{}", color, r * 20, g * 20, b * 20).bytes());
+    }
+
+    let mut bands: Vec<Option<Vec<u8>>> = vec![None; 216];
+    for top in (0..height).step_by(6) {
+        for row in 0..6.min(height - top) {
+            for x in 0..width {
+                let bits = bands[index(pixels[(top + row) * width + x])].get_or_insert_with(|| vec![0; width]);
+                bits[x] |= 1 << row;
+            }
+        }
+        let mut first = true;
+        for (color, bits) in bands.iter_mut().enumerate() {
+            let Some(bits) = bits.take() else {
+                continue;
+            };
+            if !first {
+                // Back to the start of the band for the next color
+                out.push(b'$');
+            }
+            first = false;
+            out.extend(format!("#{}", color).bytes());
+            let mut x = 0;
+            while x < width {
+                let run = bits[x..].iter().take_while(|&&b| b == bits[x]).count();
+                let sixel = 63 + bits[x];
+                if run > 3 {
+                    out.extend(format!("!{}", run).bytes());
+                    out.push(sixel);
+                } else {
+                    out.extend(std::iter::repeat_n(sixel, run));
+                }
+                x += run;
+            }
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1b\\");
+    out
+}
+
+// Shows the preview in a desktop window: arrows or WASD orbit, dragging with the left
+// button orbits too, + and - or the scroll wheel zoom, q or Esc quits
+#[cfg(feature = "window")]
+pub struct WindowPreview {
+    title: String,
+    // Opened on the first frame, and again whenever the image size changes
+    window: Option<minifb::Window>,
+    last_frame: Vec<u32>,
+    size: (usize, usize),
+    drag_from: Option<(f32, f32)>,
+}
+
+// Degrees per pixel of mouse drag
+#[cfg(feature = "window")]
+const DRAG_ORBIT: f64 = 0.3;
+
+#[cfg(feature = "window")]
+impl WindowPreview {
+    pub fn new(title: impl Into<String>) -> Self {
+        Self { title: title.into(), window: None, last_frame: Vec::new(), size: (0, 0), drag_from: None }
+    }
+}
+
+#[cfg(feature = "window")]
+impl PreviewBackend for WindowPreview {
+    fn present(&mut self, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+        if self.window.is_none() || self.size != (width, height) {
+            let mut window = minifb::Window::new(&self.title, width, height, minifb::WindowOptions::default()).map_err(|err| io::Error::other(err.to_string()))?;
+            window.set_target_fps(60);
+            self.window = Some(window);
+            self.size = (width, height);
+        }
+        self.last_frame = pixels.to_vec();
+        let window = self.window.as_mut().unwrap();
+        window.update_with_buffer(pixels, width, height).map_err(|err| io::Error::other(err.to_string()))
+    }
+
+    fn poll_events(&mut self) -> Vec<PreviewEvent> {
+        use minifb::{Key, KeyRepeat, MouseButton, MouseMode};
+
+        let Some(window) = &self.window else {
+            return Vec::new();
+        };
+        if !window.is_open() {
+            return vec![PreviewEvent::Quit];
+        }
+        let orbit = |yaw, pitch| Some(PreviewEvent::Orbit { yaw, pitch });
+        let mut events: Vec<PreviewEvent> = window
+            .get_keys_pressed(KeyRepeat::Yes)
+            .into_iter()
+            .filter_map(|key| match key {
+                Key::Up | Key::W => orbit(0.0, KEY_ORBIT),
+                Key::Down | Key::S => orbit(0.0, -KEY_ORBIT),
+                Key::Right | Key::D => orbit(KEY_ORBIT, 0.0),
+                Key::Left | Key::A => orbit(-KEY_ORBIT, 0.0),
+                Key::Equal | Key::NumPadPlus => Some(PreviewEvent::Zoom(ZOOM_STEP)),
+                Key::Minus | Key::NumPadMinus => Some(PreviewEvent::Zoom(1.0 / ZOOM_STEP)),
+                Key::Q | Key::Escape => Some(PreviewEvent::Quit),
+                _ => None,
+            })
+            .collect();
+
+        // The scene follows the mouse, so the camera moves against it
+        let position = window.get_mouse_pos(MouseMode::Pass);
+        if window.get_mouse_down(MouseButton::Left) {
+            if let (Some((x0, y0)), Some((x1, y1))) = (self.drag_from, position)
+                && (x0, y0) != (x1, y1) {
+                events.extend(orbit(-(x1 - x0) as f64 * DRAG_ORBIT, (y1 - y0) as f64 * DRAG_ORBIT));
+            }
+            self.drag_from = position;
+        } else {
+            self.drag_from = None;
+        }
+        if let Some((_, scroll)) = window.get_scroll_wheel()
+            && scroll != 0.0 {
+            events.push(PreviewEvent::Zoom(ZOOM_STEP.powf(scroll.signum() as f64)));
+        }
+        events
+    }
+
+    fn wait_events(&mut self) -> Vec<PreviewEvent> {
+        let (width, height) = self.size;
+        loop {
+            // Events only come in while the window is being updated
+            let Some(window) = self.window.as_mut() else {
+                return vec![PreviewEvent::Quit];
+            };
+            if window.update_with_buffer(&self.last_frame, width, height).is_err() {
+                return vec![PreviewEvent::Quit];
+            }
+            let events = self.poll_events();
+            if !events.is_empty() || interrupt::requested() {
+                return events;
+            }
+        }
+    }
+}
+
+// The best preview available: a window when built with the `window` feature and an X
+// display is around, otherwise graphics in the controlling terminal
+pub fn open_preview(title: &str) -> io::Result<Box<dyn PreviewBackend>> {
+    #[cfg(feature = "window")]
+    if std::env::var_os("DISPLAY").is_some() {
+        return Ok(Box::new(WindowPreview::new(title)));
+    }
+    #[cfg(unix)]
+    {
+        Ok(Box::new(TerminalPreview::open(title, TerminalGraphics::detect())?))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = title;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "no window or terminal to preview in"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+    use crate::vec3::Point3;
+    use std::sync::Arc;
+
+    fn scene() -> (Camera, HittableList) {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0, Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5))))));
+        let camera = Camera::new()
+            .with_aspect_ratio(1.0)
+            .with_image_width(8)
+            .with_samples_per_pixel(8)
+            .with_max_depth(2)
+            .with_lookfrom(Point3::new(0.0, 0.0, 4.0))
+            .with_lookat(Point3::new(0.0, 0.0, 0.0))
+            .with_vup(Vec3::new(0.0, 1.0, 0.0))
+            .with_progressive(true);
+        (camera, world)
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn headless_preview_quits_once_refined() {
+        let (mut camera, world) = scene();
+        let mut backend = HeadlessPreview::new();
+        run_preview(&mut camera, &world, &HittableList::new(), &mut backend).unwrap();
+        assert_eq!(backend.frames_presented, 8);
+        let (width, height, pixels) = backend.last_frame.unwrap();
+        assert_eq!((width, height, pixels.len()), (8, 8, 64));
+        assert!(pixels.iter().any(|&p| p != 0));
+        assert_near(camera.lookfrom, Point3::new(0.0, 0.0, 4.0));
+    }
+
+    #[test]
+    fn headless_preview_orbits_and_zooms() {
+        let (mut camera, world) = scene();
+        let mut backend = HeadlessPreview::new()
+            .with_events_after(3, vec![PreviewEvent::Orbit { yaw: 90.0, pitch: 0.0 }])
+            .with_events_after(2, vec![PreviewEvent::Zoom(0.5), PreviewEvent::Orbit { yaw: 0.0, pitch: 200.0 }]);
+        run_preview(&mut camera, &world, &HittableList::new(), &mut backend).unwrap();
+        // Each move starts over, and the last view is refined fully before quitting
+        assert_eq!(backend.frames_presented, 3 + 2 + 8);
+        // Swung to the camera's right, halved the distance, then stopped short of straight up
+        let (sin, cos) = MAX_ELEVATION.to_radians().sin_cos();
+        assert_near(camera.lookfrom, Point3::new(2.0 * cos, 2.0 * sin, 0.0));
+    }
+
+    #[test]
+    fn headless_preview_quits_mid_render() {
+        let (mut camera, world) = scene();
+        let mut backend = HeadlessPreview::new().with_events_after(2, vec![PreviewEvent::Zoom(0.5), PreviewEvent::Quit, PreviewEvent::Zoom(0.5)]);
+        run_preview(&mut camera, &world, &HittableList::new(), &mut backend).unwrap();
+        assert_eq!(backend.frames_presented, 2);
+        // Events after Quit are dropped
+        assert_near(camera.lookfrom, Point3::new(0.0, 0.0, 2.0));
+    }
+
+    #[test]
+    fn parses_keys_and_escape_sequences() {
+        let orbit = |yaw, pitch| PreviewEvent::Orbit { yaw, pitch };
+        assert_eq!(
+            parse_keys(b"\x1b[A\x1b[B\x1bOC\x1bODwsdA+=-_x"),
+            vec![
+                orbit(0.0, KEY_ORBIT),
+                orbit(0.0, -KEY_ORBIT),
+                orbit(KEY_ORBIT, 0.0),
+                orbit(-KEY_ORBIT, 0.0),
+                orbit(0.0, KEY_ORBIT),
+                orbit(0.0, -KEY_ORBIT),
+                orbit(KEY_ORBIT, 0.0),
+                orbit(-KEY_ORBIT, 0.0),
+                PreviewEvent::Zoom(ZOOM_STEP),
+                PreviewEvent::Zoom(ZOOM_STEP),
+                PreviewEvent::Zoom(1.0 / ZOOM_STEP),
+                PreviewEvent::Zoom(1.0 / ZOOM_STEP),
+            ]
+        );
+        // Unknown sequences are skipped whole, a lone Esc or one cut short quits
+        assert_eq!(parse_keys(b"\x1b[Hq"), vec![PreviewEvent::Quit]);
+        assert_eq!(parse_keys(b"\x1b"), vec![PreviewEvent::Quit]);
+        assert_eq!(parse_keys(b"\x1b["), vec![PreviewEvent::Quit]);
+        assert_eq!(parse_keys(b"\x03Q"), vec![PreviewEvent::Quit, PreviewEvent::Quit]);
+        assert!(parse_keys(b"").is_empty());
+    }
+
+    #[test]
+    fn base64_matches_rfc_4648_vectors() {
+        let vectors: [(&[u8], &[u8]); 7] = [
+            (b"", b""),
+            (b"f", b"Zg=="),
+            (b"fo", b"Zm8="),
+            (b"foo", b"Zm9v"),
+            (b"foob", b"Zm9vYg=="),
+            (b"fooba", b"Zm9vYmE="),
+            (b"foobar", b"Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64(data), encoded);
+        }
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), b"//4A");
+    }
+
+    #[test]
+    fn kitty_frames_fit_in_one_escape() {
+        let out = encode_kitty(2, 1, &[0x102030, 0xffffff]);
+        let mut expected = b"\x1b_Ga=T,f=24,s=2,v=1,i=1,p=1,q=2,C=1,m=0;".to_vec();
+        expected.extend(base64(&[0x10, 0x20, 0x30, 0xff, 0xff, 0xff]));
+        expected.extend(b"\x1b\\");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn kitty_frames_split_into_chunks() {
+        // 1100 pixels are 4400 base64 characters, one full chunk and a partial one
+        let pixels: Vec<u32> = (0..1100).map(|i| i * 0x010203).collect();
+        let out = encode_kitty(55, 20, &pixels);
+        let header = b"\x1b_Ga=T,f=24,s=55,v=20,i=1,p=1,q=2,C=1,m=1;";
+        assert!(out.starts_with(header));
+        let rest = &out[header.len()..];
+        assert_eq!(&rest[4096..4096 + 2], b"\x1b\\");
+        assert!(rest[4098..].starts_with(b"\x1b_Gm=0;"));
+        assert!(rest.ends_with(b"\x1b\\"));
+
+        let payload: Vec<u8> = [&rest[..4096], &rest[4098 + 7..rest.len() - 2]].concat();
+        let rgb: Vec<u8> = pixels.iter().flat_map(|&p| [(p >> 16) as u8, (p >> 8) as u8, p as u8]).collect();
+        assert_eq!(payload, base64(&rgb));
+    }
+
+    // Everything after the header and the 216 color palette
+    fn sixel_body(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+        let out = encode_sixel(width, height, pixels);
+        let header = format!("\x1bPq\"1;1;{};{}", width, height).into_bytes();
+        assert!(out.starts_with(&header));
+        let palette = b"#215;2;100;100;100";
+        let end = out.windows(palette.len()).position(|w| w == palette).unwrap() + palette.len();
+        assert!(out[header.len()..].starts_with(b"#0;2;0;0;0#1;2;0;0;20"));
+        out[end..].to_vec()
+    }
+
+    #[test]
+    fn sixel_bands_are_six_rows_tall() {
+        // A full band then a band one row tall, with the runs length encoded
+        assert_eq!(sixel_body(8, 7, &[0xffffff; 56]), b"#215!8~-#215!8@-\x1b\\");
+    }
+
+    #[test]
+    fn sixel_colors_share_a_band() {
+        assert_eq!(sixel_body(2, 1, &[0x000000, 0xffffff]), b"#0@?$#215?@-\x1b\\");
+        // Short runs are written out
+        assert_eq!(sixel_body(3, 2, &[0xff0000; 6]), b"#180BBB-\x1b\\");
+    }
+}
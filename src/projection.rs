@@ -0,0 +1,123 @@
+use std::f64::consts::PI;
+
+use crate::vec3::Vec3;
+
+// How the camera maps the image onto directions. Every projection is oriented by the
+// camera's lookfrom, lookat and vup.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Projection {
+    // Thin lens perspective with `vfov`, `defocus_angle` and `focus_dist`
+    #[default]
+    Perspective,
+    // Parallel rays from a `view_width` wide rectangle, depth of field still applies
+    Orthographic { view_width: f64 },
+    // Equidistant fisheye, `fov` degrees across the image width. Pixels further out than
+    // 180 degrees from the view direction stay black.
+    Fisheye { fov: f64 },
+    // Full 360 x 180 degree panorama, meant for 2:1 images
+    Equirectangular,
+    // 360 degrees around `vup`, perspective vertically with `vfov` degrees
+    Cylindrical { vfov: f64 },
+}
+
+impl Projection {
+    // Planar projections are built from the camera's viewport, the others from `direction`
+    pub fn is_planar(&self) -> bool {
+        matches!(self, Projection::Perspective | Projection::Orthographic { .. })
+    }
+
+    // Direction through the image point (s, t), both in [0, 1) from the top left corner, in
+    // camera space: x right, y up, z along the view direction. Only for non-planar
+    // projections; None where the image has no direction.
+    pub fn direction(&self, s: f64, t: f64, aspect_ratio: f64) -> Option<Vec3> {
+        match *self {
+            Projection::Perspective | Projection::Orthographic { .. } => None,
+            Projection::Fisheye { fov } => {
+                let x = 2.0 * s - 1.0;
+                let y = (1.0 - 2.0 * t) / aspect_ratio;
+                let theta = (x * x + y * y).sqrt() * fov.to_radians() / 2.0;
+                if theta > PI {
+                    return None;
+                }
+                let phi = y.atan2(x);
+                Some(Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()))
+            }
+            Projection::Equirectangular => {
+                let phi = (s - 0.5) * 2.0 * PI;
+                let lat = (0.5 - t) * PI;
+                Some(Vec3::new(lat.cos() * phi.sin(), lat.sin(), lat.cos() * phi.cos()))
+            }
+            Projection::Cylindrical { vfov } => {
+                let phi = (s - 0.5) * 2.0 * PI;
+                let height = (1.0 - 2.0 * t) * (vfov.to_radians() / 2.0).tan();
+                Some(Vec3::new(phi.sin(), height, phi.cos()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::{dot, unit_vector};
+
+    // Image point each projection maps `d` to, worked out independently of `direction`
+    fn image_point(projection: Projection, d: Vec3, aspect_ratio: f64) -> (f64, f64) {
+        match projection {
+            Projection::Fisheye { fov } => {
+                let radius = d.z().clamp(-1.0, 1.0).acos() / (fov.to_radians() / 2.0);
+                let phi = d.y().atan2(d.x());
+                ((radius * phi.cos() + 1.0) / 2.0, (1.0 - radius * phi.sin() * aspect_ratio) / 2.0)
+            }
+            Projection::Equirectangular => {
+                let phi = d.x().atan2(d.z());
+                (phi / (2.0 * PI) + 0.5, 0.5 - d.y().asin() / PI)
+            }
+            Projection::Cylindrical { vfov } => {
+                let phi = d.x().atan2(d.z());
+                let height = d.y() / d.x().hypot(d.z());
+                (phi / (2.0 * PI) + 0.5, (1.0 - height / (vfov.to_radians() / 2.0).tan()) / 2.0)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn directions_map_back_to_their_image_points() {
+        let projections = [Projection::Fisheye { fov: 180.0 }, Projection::Fisheye { fov: 300.0 }, Projection::Equirectangular, Projection::Cylindrical { vfov: 90.0 }];
+        for projection in projections {
+            for aspect_ratio in [1.0, 2.0] {
+                for k in 0..100 {
+                    let (s, t) = (0.05 + 0.09 * (k % 10) as f64, 0.05 + 0.09 * (k / 10) as f64);
+                    let Some(d) = projection.direction(s, t, aspect_ratio) else {
+                        continue;
+                    };
+                    let (s2, t2) = image_point(projection, unit_vector(d), aspect_ratio);
+                    assert!((s - s2).abs() < 1e-9 && (t - t2).abs() < 1e-9, "{:?}: ({}, {}) came back as ({}, {})", projection, s, t, s2, t2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn image_centers_look_along_the_view_direction() {
+        for projection in [Projection::Fisheye { fov: 180.0 }, Projection::Equirectangular, Projection::Cylindrical { vfov: 60.0 }] {
+            let d = projection.direction(0.5, 0.5, 2.0).unwrap();
+            assert!((unit_vector(d) - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-12, "{:?}", projection);
+        }
+        assert_eq!(Projection::Perspective.direction(0.5, 0.5, 1.0), None);
+    }
+
+    #[test]
+    fn fisheye_angle_grows_linearly_with_radius() {
+        let fisheye = Projection::Fisheye { fov: 180.0 };
+        // The image edge is 90 degrees out and half way there is 45
+        let edge = fisheye.direction(1.0, 0.5, 1.0).unwrap();
+        let half = fisheye.direction(0.75, 0.5, 1.0).unwrap();
+        assert!(dot(edge, Vec3::new(0.0, 0.0, 1.0)).abs() < 1e-12);
+        assert!((dot(half, Vec3::new(0.0, 0.0, 1.0)) - (PI / 4.0).cos()).abs() < 1e-12);
+        // Corners of a square image are past 90 degrees but still on the sphere
+        assert!(fisheye.direction(0.0, 0.0, 1.0).unwrap().z() < 0.0);
+        assert_eq!(Projection::Fisheye { fov: 360.0 }.direction(0.0, 0.0, 1.0), None);
+    }
+}
@@ -0,0 +1,542 @@
+use std::sync::OnceLock;
+
+// Source of the random numbers a path consumes. Every value is a pure function of the
+// pixel, the sample index within that pixel and how many dimensions were drawn before it,
+// so renders are reproducible no matter how the work is split across threads.
+pub trait Sampler: Send {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, sample_index: u32);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64);
+
+    // Position inside the pixel footprint, the first dimension of every camera sample
+    fn get_pixel_2d(&mut self) -> (f64, f64) {
+        self.get_2d()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub fn create(self, samples_per_pixel: u32, seed: u64) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel, seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler::new(seed)),
+        }
+    }
+}
+
+// 2^-32, turns a u32 into a float in [0, 1)
+const U32_TO_UNIT: f64 = 1.0 / 4294967296.0;
+
+// Largest f64 below 1, so scrambled sequences never return exactly 1.0
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// Finalizer from SplitMix64: a cheap, well-distributed 64-bit hash
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, v| mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15)))
+}
+
+fn pixel_key(i: i32, j: i32) -> u64 {
+    ((i as u32 as u64) << 32) | j as u32 as u64
+}
+
+fn unit_from_bits(bits: u64) -> f64 {
+    (bits >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+// Uniform random numbers; this is the original renderer's behavior, made reproducible
+pub struct IndependentSampler {
+    seed: u64,
+    state: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, state: 0 }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        mix_bits(self.state)
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, sample_index: u32) {
+        self.state = hash(&[pixel_key(i, j), sample_index as u64, self.seed]);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        unit_from_bits(self.next_u64())
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// Random permutation of [0, n) evaluated one element at a time (Kensler 2013)
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % n
+}
+
+// Jittered strata: each dimension (pair) of a pixel gets its own random assignment of
+// sample indices to strata, so dimensions don't correlate with each other.
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    x_strata: u32,
+    y_strata: u32,
+    seed: u64,
+    pixel: u64,
+    sample_index: u32,
+    dimension: u64,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> Self {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        let x_strata = (samples_per_pixel as f64).sqrt().round().max(1.0) as u32;
+        let y_strata = samples_per_pixel.div_ceil(x_strata);
+        Self { samples_per_pixel, x_strata, y_strata, seed, pixel: 0, sample_index: 0, dimension: 0 }
+    }
+
+    fn next_hash(&mut self) -> u64 {
+        let h = hash(&[self.pixel, self.dimension, self.seed, (self.sample_index / self.samples_per_pixel) as u64]);
+        self.dimension += 1;
+        h
+    }
+
+    fn jitter(&self, h: u64) -> f64 {
+        unit_from_bits(mix_bits(h ^ self.sample_index as u64))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, sample_index: u32) {
+        self.pixel = pixel_key(i, j);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let h = self.next_hash();
+        let n = self.samples_per_pixel;
+        let stratum = permutation_element(self.sample_index % n, n, h as u32);
+        ((stratum as f64 + self.jitter(h)) / n as f64).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let h = self.next_hash();
+        let n = self.x_strata * self.y_strata;
+        let stratum = permutation_element(self.sample_index % n, n, h as u32);
+        let (x, y) = (stratum % self.x_strata, stratum / self.x_strata);
+        let dx = self.jitter(h);
+        let dy = self.jitter(mix_bits(h));
+        (
+            ((x as f64 + dx) / self.x_strata as f64).min(ONE_MINUS_EPSILON),
+            ((y as f64 + dy) / self.y_strata as f64).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+// Radical inverse with every digit shifted by a seeded amount (random digit scrambling)
+fn scrambled_radical_inverse(base: u32, mut index: u64, seed: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut result = 0.0;
+    let mut digit_index = 0;
+    // Keep going past the last nonzero digit, otherwise the shifts would be truncated
+    while inv_base_m > 1e-14 {
+        let digit = (index % base as u64) as u32;
+        let shift = (mix_bits(seed ^ digit_index) % base as u64) as u32;
+        inv_base_m *= inv_base;
+        result += ((digit + shift) % base) as f64 * inv_base_m;
+        index /= base as u64;
+        digit_index += 1;
+    }
+    result.min(ONE_MINUS_EPSILON)
+}
+
+// Halton sequence in the first 64 prime bases, scrambled per pixel. Higher dimensions
+// reuse the bases with a different scramble.
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    sample_index: u32,
+    dimension: u64,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, pixel: 0, sample_index: 0, dimension: 0 }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, sample_index: u32) {
+        self.pixel = pixel_key(i, j);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let base = PRIMES[self.dimension as usize % PRIMES.len()];
+        let seed = hash(&[self.pixel, self.dimension, self.seed]);
+        self.dimension += 1;
+        scrambled_radical_inverse(base, self.sample_index as u64, seed)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// Hash-based Owen scrambling (Laine & Karras 2011, improved constants by Burley 2020)
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// The first two Sobol dimensions; the first is the van der Corput sequence
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+fn sobol_1(mut index: u32) -> u32 {
+    let mut v: u32 = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Owen-scrambled Sobol padded from 1D/2D pieces (Burley 2020): every dimension (pair) uses
+// its own shuffled sample order and scramble, seeded by `seed`.
+fn padded_sobol_1d(sample_index: u32, seed: u64) -> f64 {
+    let index = nested_uniform_scramble(sample_index, mix_bits(seed) as u32);
+    let x = nested_uniform_scramble(sobol_0(index), (seed >> 32) as u32);
+    (x as f64 * U32_TO_UNIT).min(ONE_MINUS_EPSILON)
+}
+
+fn padded_sobol_2d(sample_index: u32, seed: u64) -> (f64, f64) {
+    let index = nested_uniform_scramble(sample_index, mix_bits(seed) as u32);
+    let x = nested_uniform_scramble(sobol_0(index), (seed >> 32) as u32);
+    let y = nested_uniform_scramble(sobol_1(index), mix_bits(seed ^ 0x5851f42d4c957f2d) as u32);
+    (
+        (x as f64 * U32_TO_UNIT).min(ONE_MINUS_EPSILON),
+        (y as f64 * U32_TO_UNIT).min(ONE_MINUS_EPSILON),
+    )
+}
+
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    sample_index: u32,
+    dimension: u64,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, pixel: 0, sample_index: 0, dimension: 0 }
+    }
+
+    fn dimension_seed(&mut self) -> u64 {
+        let h = hash(&[self.pixel, self.dimension, self.seed]);
+        self.dimension += 1;
+        h
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, sample_index: u32) {
+        self.pixel = pixel_key(i, j);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let seed = self.dimension_seed();
+        padded_sobol_1d(self.sample_index, seed)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let seed = self.dimension_seed();
+        padded_sobol_2d(self.sample_index, seed)
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+// Ranked blue-noise mask built by repeatedly filling the largest void (the second half of
+// Ulichney's void-and-cluster method). Values are in [0, 1).
+fn blue_noise_mask() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        let sigma = 1.9;
+        let mut kernel = vec![0.0; n];
+        for dy in 0..BLUE_NOISE_SIZE {
+            for dx in 0..BLUE_NOISE_SIZE {
+                let tx = dx.min(BLUE_NOISE_SIZE - dx) as f64;
+                let ty = dy.min(BLUE_NOISE_SIZE - dy) as f64;
+                kernel[dy * BLUE_NOISE_SIZE + dx] = (-(tx * tx + ty * ty) / (2.0 * sigma * sigma)).exp();
+            }
+        }
+
+        // Tiny deterministic jitter breaks ties so the result isn't a regular lattice
+        let mut energy: Vec<f64> = (0..n).map(|k| unit_from_bits(mix_bits(k as u64)) * 1e-6).collect();
+        let mut placed = vec![false; n];
+        let mut mask = vec![0.0; n];
+        for rank in 0..n {
+            let mut best = usize::MAX;
+            for k in 0..n {
+                if !placed[k] && (best == usize::MAX || energy[k] < energy[best]) {
+                    best = k;
+                }
+            }
+            placed[best] = true;
+            mask[best] = (rank as f64 + 0.5) / n as f64;
+
+            let (bx, by) = (best % BLUE_NOISE_SIZE, best / BLUE_NOISE_SIZE);
+            for y in 0..BLUE_NOISE_SIZE {
+                let dy = (y + BLUE_NOISE_SIZE - by) % BLUE_NOISE_SIZE;
+                for x in 0..BLUE_NOISE_SIZE {
+                    let dx = (x + BLUE_NOISE_SIZE - bx) % BLUE_NOISE_SIZE;
+                    energy[y * BLUE_NOISE_SIZE + x] += kernel[dy * BLUE_NOISE_SIZE + dx];
+                }
+            }
+        }
+        mask
+    })
+}
+
+// The same Owen-scrambled Sobol sequence in every pixel, decorrelated by toroidally shifting
+// it with a blue-noise mask (Heitz & Belcour style dithering). The remaining error is
+// distributed as high-frequency noise, which looks much cleaner at low sample counts.
+pub struct BlueNoiseSampler {
+    seed: u64,
+    i: i32,
+    j: i32,
+    sample_index: u32,
+    dimension: u64,
+}
+
+impl BlueNoiseSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, i: 0, j: 0, sample_index: 0, dimension: 0 }
+    }
+
+    fn dimension_seed(&mut self) -> u64 {
+        let h = hash(&[self.dimension, self.seed]);
+        self.dimension += 1;
+        h
+    }
+
+    fn dither(&self, value: f64, seed: u64) -> f64 {
+        let offset = mix_bits(seed ^ 0xb5ad4eceda1ce2a9);
+        let x = (self.i as i64 + (offset % BLUE_NOISE_SIZE as u64) as i64).rem_euclid(BLUE_NOISE_SIZE as i64) as usize;
+        let y = (self.j as i64 + ((offset >> 32) % BLUE_NOISE_SIZE as u64) as i64).rem_euclid(BLUE_NOISE_SIZE as i64) as usize;
+        let shifted = value + blue_noise_mask()[y * BLUE_NOISE_SIZE + x];
+        (shifted - shifted.floor()).min(ONE_MINUS_EPSILON)
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, sample_index: u32) {
+        self.i = i;
+        self.j = j;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let seed = self.dimension_seed();
+        self.dither(padded_sobol_1d(self.sample_index, seed), seed)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let seed = self.dimension_seed();
+        let (x, y) = padded_sobol_2d(self.sample_index, seed);
+        (self.dither(x, seed), self.dither(y, mix_bits(seed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = [SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol, SamplerKind::BlueNoise];
+
+    // The stratum in 0..n each sample's value falls into
+    fn strata(values: impl Iterator<Item = f64>, n: usize) -> Vec<usize> {
+        let mut strata: Vec<usize> = values.map(|v| (v * n as f64) as usize).collect();
+        strata.sort();
+        strata
+    }
+
+    #[test]
+    fn samples_stay_in_the_unit_interval() {
+        for kind in KINDS {
+            let mut sampler = kind.create(16, 7);
+            for sample_index in 0..64 {
+                sampler.start_pixel_sample(-3, 5, sample_index);
+                for _ in 0..8 {
+                    let v = sampler.get_1d();
+                    let (x, y) = sampler.get_2d();
+                    for value in [v, x, y] {
+                        assert!((0.0..1.0).contains(&value), "{:?} gave {}", kind, value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn samples_only_depend_on_pixel_index_and_dimension() {
+        for kind in KINDS {
+            let mut first = kind.create(16, 7);
+            let mut second = kind.create(16, 7);
+            second.start_pixel_sample(9, 9, 3);
+            second.get_2d();
+            first.start_pixel_sample(2, 4, 5);
+            second.start_pixel_sample(2, 4, 5);
+            let a = (first.get_1d(), first.get_2d(), first.get_1d());
+            let b = (second.get_1d(), second.get_2d(), second.get_1d());
+            assert_eq!(a, b, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn stratified_samples_cover_every_stratum_once() {
+        let mut sampler = StratifiedSampler::new(16, 1);
+        let mut values = Vec::new();
+        for sample_index in 0..16 {
+            sampler.start_pixel_sample(3, 1, sample_index);
+            values.push((sampler.get_1d(), sampler.get_2d()));
+        }
+        assert_eq!(strata(values.iter().map(|v| v.0), 16), (0..16).collect::<Vec<_>>());
+        let cells = strata(values.iter().map(|v| ((v.1.1 * 4.0).floor() * 4.0 + (v.1.0 * 4.0).floor()) / 16.0), 16);
+        assert_eq!(cells, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sobol_samples_form_nets() {
+        let mut sampler = SobolSampler::new(1);
+        let mut values = Vec::new();
+        for sample_index in 0..16 {
+            sampler.start_pixel_sample(3, 1, sample_index);
+            values.push((sampler.get_1d(), sampler.get_2d()));
+        }
+        assert_eq!(strata(values.iter().map(|v| v.0), 16), (0..16).collect::<Vec<_>>());
+        // Every elementary interval of area 1/16 holds exactly one point
+        for (columns, rows) in [(16.0, 1.0), (8.0, 2.0), (4.0, 4.0), (2.0, 8.0), (1.0, 16.0)] {
+            let cells = strata(values.iter().map(|v| ((v.1.1 * rows).floor() * columns + (v.1.0 * columns).floor()) / 16.0), 16);
+            assert_eq!(cells, (0..16).collect::<Vec<_>>(), "{}x{} intervals", columns, rows);
+        }
+    }
+
+    #[test]
+    fn halton_dimensions_are_stratified_in_their_base() {
+        let mut sampler = HaltonSampler::new(1);
+        let mut values = Vec::new();
+        for sample_index in 0..27 {
+            sampler.start_pixel_sample(3, 1, sample_index);
+            values.push(sampler.get_2d());
+        }
+        assert_eq!(strata(values.iter().take(16).map(|v| v.0), 16), (0..16).collect::<Vec<_>>());
+        assert_eq!(strata(values.iter().map(|v| v.1), 27), (0..27).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn blue_noise_decorrelates_neighbouring_pixels() {
+        let mut sampler = BlueNoiseSampler::new(1);
+        let mut values = Vec::new();
+        for i in 0..8 {
+            sampler.start_pixel_sample(i, 0, 0);
+            values.push(sampler.get_1d());
+        }
+        values.sort_by(f64::total_cmp);
+        values.dedup();
+        assert_eq!(values.len(), 8);
+        // Within a pixel it is still a shifted Sobol sequence, so the gaps between
+        // successive values of the first 16 samples are all close to 1/16
+        let mut pixel: Vec<f64> = (0..16)
+            .map(|sample_index| {
+                sampler.start_pixel_sample(5, 2, sample_index);
+                sampler.get_1d()
+            })
+            .collect();
+        pixel.sort_by(f64::total_cmp);
+        for pair in pixel.windows(2) {
+            assert!(pair[1] - pair[0] < 2.0 / 16.0);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::ops::{AddAssign, Mul};
+use std::sync::OnceLock;
+
+use crate::color::{convert, mat3_inverse, mat3_mul, Color, ColorSpace, Mat3};
+use crate::vec3::Vec3;
+
+// Visible range we sample wavelengths over, in nanometers
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+// Number of wavelengths carried by each path (hero + 3 rotated companions)
+pub const N_SPECTRUM_SAMPLES: usize = 4;
+
+// Integral of the CIE y-bar matching function over the visible range
+const CIE_Y_INTEGRAL: f64 = 106.856895;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SampledSpectrum {
+    pub values: [f64; N_SPECTRUM_SAMPLES],
+}
+
+impl SampledSpectrum {
+    pub fn new(value: f64) -> Self {
+        Self { values: [value; N_SPECTRUM_SAMPLES] }
+    }
+
+    // Upsample an RGB color and evaluate it at every wavelength in `lambdas`
+    pub fn from_rgb(rgb: Color, lambdas: &SampledWavelengths) -> Self {
+        let mut values = [0.0; N_SPECTRUM_SAMPLES];
+        for (value, lambda) in values.iter_mut().zip(lambdas.lambda) {
+            *value = rgb_to_spectrum(rgb, lambda);
+        }
+        Self { values }
+    }
+
+    // `from_rgb` for a color in a working space other than linear sRGB
+    pub fn from_rgb_in(rgb: Color, space: ColorSpace, lambdas: &SampledWavelengths) -> Self {
+        Self::from_rgb(convert(rgb, space, ColorSpace::LinearSrgb), lambdas)
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.values.iter().all(|v| *v == 0.0)
+    }
+
+    pub fn max_value(&self) -> f64 {
+        self.values.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+    }
+
+    // Monte Carlo estimate of this spectrum's CIE XYZ coordinates
+    pub fn to_xyz(&self, lambdas: &SampledWavelengths) -> Vec3 {
+        let mut xyz = Vec3::new(0.0, 0.0, 0.0);
+        for i in 0..N_SPECTRUM_SAMPLES {
+            if lambdas.pdf[i] == 0.0 {
+                continue;
+            }
+            let lambda = lambdas.lambda[i];
+            let weight = self.values[i] / lambdas.pdf[i];
+            xyz += Vec3::new(cie_x(lambda), cie_y(lambda), cie_z(lambda)) * weight;
+        }
+        xyz / (N_SPECTRUM_SAMPLES as f64 * CIE_Y_INTEGRAL)
+    }
+
+    // Convert to linear sRGB, corrected so that upsampled RGB inputs round-trip
+    pub fn to_rgb(&self, lambdas: &SampledWavelengths) -> Color {
+        let rgb = xyz_to_linear_srgb(self.to_xyz(lambdas));
+        mat3_mul(roundtrip_correction(), rgb)
+    }
+
+    // `to_rgb`, converted on to a working space
+    pub fn to_rgb_in(&self, space: ColorSpace, lambdas: &SampledWavelengths) -> Color {
+        convert(self.to_rgb(lambdas), ColorSpace::LinearSrgb, space)
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        let mut values = self.values;
+        for (v, r) in values.iter_mut().zip(rhs.values) {
+            *v *= r;
+        }
+        Self { values }
+    }
+}
+
+impl Mul<f64> for SampledSpectrum {
+    type Output = Self;
+    fn mul(self, t: f64) -> Self::Output {
+        Self { values: self.values.map(|v| v * t) }
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, other: Self) {
+        for (v, o) in self.values.iter_mut().zip(other.values) {
+            *v += o;
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SampledWavelengths {
+    pub lambda: [f64; N_SPECTRUM_SAMPLES],
+    pub pdf: [f64; N_SPECTRUM_SAMPLES],
+}
+
+impl SampledWavelengths {
+    // Hero wavelength sampling: the hero is uniform over the visible range and the
+    // companions are spaced evenly after it, wrapping around at the upper end.
+    pub fn sample_uniform(u: f64) -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let delta = range / N_SPECTRUM_SAMPLES as f64;
+        let mut lambda = [0.0; N_SPECTRUM_SAMPLES];
+        lambda[0] = LAMBDA_MIN + u * range;
+        for i in 1..N_SPECTRUM_SAMPLES {
+            lambda[i] = lambda[i - 1] + delta;
+            if lambda[i] > LAMBDA_MAX {
+                lambda[i] -= range;
+            }
+        }
+        Self { lambda, pdf: [1.0 / range; N_SPECTRUM_SAMPLES] }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|p| *p == 0.0)
+    }
+
+    // Drop the companion wavelengths once the path hits something wavelength dependent,
+    // e.g. a dispersive dielectric. The hero alone stays an unbiased estimator.
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for pdf in self.pdf[1..].iter_mut() {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= N_SPECTRUM_SAMPLES as f64;
+    }
+}
+
+// Smooth partition of unity over the visible range, so that (1,1,1) upsamples to a
+// constant spectrum of 1 and every albedo in [0,1] stays a valid reflectance.
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn spectrum_basis(lambda: f64) -> Vec3 {
+    let blue = 1.0 - smoothstep(470.0, 510.0, lambda);
+    let red = smoothstep(570.0, 610.0, lambda);
+    Vec3::new(red, 1.0 - red - blue, blue)
+}
+
+pub fn rgb_to_spectrum(rgb: Color, lambda: f64) -> f64 {
+    let basis = spectrum_basis(lambda);
+    rgb.x() * basis.x() + rgb.y() * basis.y() + rgb.z() * basis.z()
+}
+
+// Multi-lobe analytic fit of the CIE 1931 matching functions (Wyman, Sloan & Shirley 2013)
+fn lobe(x: f64, mu: f64, sigma_low: f64, sigma_high: f64) -> f64 {
+    let sigma = if x < mu { sigma_low } else { sigma_high };
+    let t = (x - mu) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+pub fn cie_x(lambda: f64) -> f64 {
+    1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(lambda, 501.1, 20.4, 26.2)
+}
+
+pub fn cie_y(lambda: f64) -> f64 {
+    0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1)
+}
+
+pub fn cie_z(lambda: f64) -> f64 {
+    1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8)
+}
+
+pub fn xyz_to_linear_srgb(xyz: Vec3) -> Color {
+    Color::new(
+        3.2404542 * xyz.x() - 1.5371385 * xyz.y() - 0.4985314 * xyz.z(),
+        -0.9692660 * xyz.x() + 1.8760108 * xyz.y() + 0.0415560 * xyz.z(),
+        0.0556434 * xyz.x() - 0.2040259 * xyz.y() + 1.0572252 * xyz.z(),
+    )
+}
+
+// Upsampling followed by XYZ -> sRGB is linear in the input RGB, so we integrate the
+// round trip once and invert it. This makes white stay white and keeps single-bounce
+// colors matching the RGB renderer.
+fn roundtrip_correction() -> &'static Mat3 {
+    static CORRECTION: OnceLock<Mat3> = OnceLock::new();
+    CORRECTION.get_or_init(|| {
+        let mut columns = [Vec3::new(0.0, 0.0, 0.0); 3];
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            let basis = spectrum_basis(lambda);
+            let cmf = Vec3::new(cie_x(lambda), cie_y(lambda), cie_z(lambda));
+            columns[0] += cmf * basis.x();
+            columns[1] += cmf * basis.y();
+            columns[2] += cmf * basis.z();
+            lambda += 1.0;
+        }
+        let mut roundtrip = [[0.0; 3]; 3];
+        for (c, column) in columns.iter().enumerate() {
+            let rgb = xyz_to_linear_srgb(*column / CIE_Y_INTEGRAL);
+            roundtrip[0][c] = rgb.x();
+            roundtrip[1][c] = rgb.y();
+            roundtrip[2][c] = rgb.z();
+        }
+        mat3_inverse(&roundtrip)
+    })
+}
@@ -1,7 +1,14 @@
+use crate::aabb::Aabb;
+use crate::color::ColorSpace;
 use crate::interval::Interval;
-use crate::material::Material;
-use crate::vec3::{Vec3, dot};
+use crate::material::{Material, MaterialIds};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec3::{Vec3, dot, sample_unit_sphere};
+use std::f64::consts::PI;
 use crate::hittable::{Hittable, HitRecord};
+use crate::stats::{self, Counter};
 use crate::vec3::Point3;
 use std::sync::Arc;
 
@@ -9,21 +16,26 @@ use std::sync::Arc;
 pub struct Sphere {
     center: Point3,
     radius: f64,
-    material: Arc<dyn Material + Sync + Send>
+    material: Arc<dyn Material + Sync + Send>,
 }
 
 impl Sphere {
     pub fn new(center: Point3, radius: f64, material: Arc<dyn Material + Sync + Send>) -> Self{
         Self { 
-            center: center,
-            radius: radius,
-            material: material,
+            center,
+            radius,
+            material,
         }
     }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, r: &crate::ray::Ray, ray_t: Interval) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        stats::increment(Counter::PrimitiveTests);
         let oc: Vec3 = self.center - r.origin();
         let a: f64 = r.direction().length_squared();
         let h: f64 = dot(r.direction(), oc);
@@ -47,15 +59,90 @@ impl Hittable for Sphere {
         let p = r.at(t);
         let outward_normal: Vec3 = (p - self.center) / self.radius;
 
+        let (u, v) = sphere_uv(outward_normal);
+
         let mut rec: HitRecord = HitRecord {
             p,
             normal: Vec3::new(0.0, 0.0, 0.0), // temp get overwritten by set_face_normal below
+            u,
+            v,
             front_face: false, // temp
+            object_id: 0,
+            working_space: ColorSpace::default(),
             t,
             mat: self.material.clone()
         };
         rec.set_face_normal(r, outward_normal);
 
-        return Some(rec);
+        Some(rec)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::from_points(self.center - radius, self.center + radius))
+    }
+
+    // Uniform over the cone of directions the sphere subtends from `origin`
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        if self.hit(&Ray::new(origin, direction), Interval::new(0.001, f64::INFINITY)).is_none() {
+            return 0.0;
+        }
+        let dist_squared = (self.center - origin).length_squared();
+        let cos_theta_max = (1.0 - self.radius * self.radius / dist_squared).max(0.0).sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+    }
+
+    fn random(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let direction = self.center - origin;
+        let dist_squared = direction.length_squared();
+        let uvw = Onb::new(direction);
+        uvw.transform(random_to_sphere(self.radius, dist_squared, sampler.get_2d()))
     }
-}
\ No newline at end of file
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let normal = sample_unit_sphere(sampler.get_2d());
+        let (u, v) = sphere_uv(normal);
+        let rec = HitRecord {
+            t: 0.0,
+            p: self.center + normal * self.radius,
+            normal,
+            u,
+            v,
+            front_face: true,
+            object_id: 0,
+            working_space: ColorSpace::default(),
+            mat: self.material.clone(),
+        };
+        Some((rec, 1.0 / self.area()))
+    }
+
+    fn surface_pdf(&self, p: Point3) -> f64 {
+        // Allow for the error a ray intersection leaves in the hit point
+        if ((p - self.center).length() - self.radius).abs() > 1e-6 * self.radius.max(1.0) {
+            return 0.0;
+        }
+        1.0 / self.area()
+    }
+
+    fn scene_materials(&self, materials: &mut MaterialIds) {
+        materials.add(&self.material);
+    }
+}
+
+// Direction in the cone towards a sphere of `radius` at squared distance `dist_squared`, around +z
+fn random_to_sphere(radius: f64, dist_squared: f64, u: (f64, f64)) -> Vec3 {
+    let cos_theta_max = (1.0 - radius * radius / dist_squared).max(0.0).sqrt();
+    let z = 1.0 + u.1 * (cos_theta_max - 1.0);
+    let phi = 2.0 * PI * u.0;
+    let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+    Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z)
+}
+
+// Texture coordinates of a point on the unit sphere: u runs once around the y axis
+// starting from -x, v from the bottom pole to the top one
+fn sphere_uv(p: Vec3) -> (f64, f64) {
+    let theta = (-p.y()).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z()).atan2(p.x()) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::tile::TileTiming;
+
+// Things we count while tracing. Counting goes into thread-local storage so the hot
+// paths never touch shared memory; workers hand their totals over after each pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    PrimaryRays,
+    SecondaryRays,
+    ShadowRays,
+    BvhNodeTests,
+    PrimitiveTests,
+    RussianRouletteTerminations,
+    Paths,
+}
+
+const COUNTER_COUNT: usize = 7;
+
+#[derive(Debug, Clone, Default)]
+pub struct Counters {
+    values: [u64; COUNTER_COUNT],
+    // path_depths[d] is the number of paths that ended after d bounces
+    path_depths: Vec<u64>,
+}
+
+impl Counters {
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.values[counter as usize]
+    }
+
+    pub fn merge(&mut self, other: &Counters) {
+        for (value, o) in self.values.iter_mut().zip(other.values) {
+            *value += o;
+        }
+        if self.path_depths.len() < other.path_depths.len() {
+            self.path_depths.resize(other.path_depths.len(), 0);
+        }
+        for (count, o) in self.path_depths.iter_mut().zip(&other.path_depths) {
+            *count += o;
+        }
+    }
+}
+
+thread_local! {
+    static COUNTERS: RefCell<Counters> = RefCell::new(Counters::default());
+}
+
+pub fn increment(counter: Counter) {
+    add(counter, 1);
+}
+
+pub fn add(counter: Counter, n: u64) {
+    COUNTERS.with(|c| c.borrow_mut().values[counter as usize] += n);
+}
+
+// Called once per camera path when it terminates
+pub fn record_path(bounces: usize) {
+    COUNTERS.with(|c| {
+        let mut c = c.borrow_mut();
+        c.values[Counter::Paths as usize] += 1;
+        if c.path_depths.len() <= bounces {
+            c.path_depths.resize(bounces + 1, 0);
+        }
+        c.path_depths[bounces] += 1;
+    });
+}
+
+// Returns this thread's counts and resets them
+pub fn take_thread_counters() -> Counters {
+    COUNTERS.with(|c| std::mem::take(&mut *c.borrow_mut()))
+}
+
+// End-of-render report, returned from `Camera::render`
+#[derive(Debug, Clone, Default)]
+pub struct RenderStats {
+    pub wall_time: Duration,
+    pub passes: u32,
+    pub image_width: usize,
+    pub image_height: usize,
+    pub counters: Counters,
+    pub tile_timings: Vec<TileTiming>,
+}
+
+impl RenderStats {
+    pub fn primary_rays(&self) -> u64 {
+        self.counters.get(Counter::PrimaryRays)
+    }
+
+    pub fn secondary_rays(&self) -> u64 {
+        self.counters.get(Counter::SecondaryRays)
+    }
+
+    pub fn shadow_rays(&self) -> u64 {
+        self.counters.get(Counter::ShadowRays)
+    }
+
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays() + self.secondary_rays() + self.shadow_rays()
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        self.total_rays() as f64 / self.wall_time.as_secs_f64().max(1e-9)
+    }
+
+    // Average number of bounces a camera path made before it ended
+    pub fn average_path_length(&self) -> f64 {
+        let paths = self.counters.get(Counter::Paths);
+        if paths == 0 {
+            return 0.0;
+        }
+        let bounces: u64 = self.counters.path_depths.iter().enumerate().map(|(d, n)| d as u64 * n).sum();
+        bounces as f64 / paths as f64
+    }
+
+    pub fn bvh_node_tests_per_ray(&self) -> f64 {
+        self.per_ray(self.counters.get(Counter::BvhNodeTests))
+    }
+
+    pub fn primitive_tests_per_ray(&self) -> f64 {
+        self.per_ray(self.counters.get(Counter::PrimitiveTests))
+    }
+
+    pub fn russian_roulette_terminations(&self) -> u64 {
+        self.counters.get(Counter::RussianRouletteTerminations)
+    }
+
+    pub fn path_depth_histogram(&self) -> &[u64] {
+        &self.counters.path_depths
+    }
+
+    fn per_ray(&self, n: u64) -> f64 {
+        let rays = self.total_rays();
+        if rays == 0 {
+            return 0.0;
+        }
+        n as f64 / rays as f64
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Render time:          {:.2} s ({} passes)", self.wall_time.as_secs_f64(), self.passes);
+        let _ = writeln!(out, "Rays:                 {} ({:.2} M/s)", self.total_rays(), self.rays_per_second() / 1e6);
+        let _ = writeln!(out, "  primary:            {}", self.primary_rays());
+        let _ = writeln!(out, "  secondary:          {}", self.secondary_rays());
+        let _ = writeln!(out, "  shadow:             {}", self.shadow_rays());
+        let _ = writeln!(out, "Average path length:  {:.2} bounces", self.average_path_length());
+        let _ = writeln!(out, "BVH node tests/ray:   {:.2}", self.bvh_node_tests_per_ray());
+        let _ = writeln!(out, "Primitive tests/ray:  {:.2}", self.primitive_tests_per_ray());
+        let _ = writeln!(out, "Russian roulette:     {} terminations", self.russian_roulette_terminations());
+
+        let paths = self.counters.get(Counter::Paths).max(1);
+        let _ = writeln!(out, "Path depth histogram:");
+        for (depth, count) in self.path_depth_histogram().iter().enumerate() {
+            let fraction = *count as f64 / paths as f64;
+            let bar = "#".repeat((fraction * 50.0).round() as usize);
+            let _ = writeln!(out, "  {:>3}: {:>12} {:>6.2}% {}", depth, count, fraction * 100.0, bar);
+        }
+
+        if let Some(slowest) = self.tile_timings.iter().max_by_key(|t| t.time) {
+            let fastest = self.tile_timings.iter().min_by_key(|t| t.time).unwrap();
+            let total: Duration = self.tile_timings.iter().map(|t| t.time).sum();
+            let _ = writeln!(
+                out,
+                "Tiles:                {}, mean {:.1} ms, fastest {:.1} ms, slowest {:.1} ms at ({}, {})",
+                self.tile_timings.len(),
+                total.as_secs_f64() * 1000.0 / self.tile_timings.len() as f64,
+                fastest.time.as_secs_f64() * 1000.0,
+                slowest.time.as_secs_f64() * 1000.0,
+                slowest.tile.x0,
+                slowest.tile.y0,
+            );
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{{");
+        let _ = writeln!(out, "  \"wall_time_seconds\": {},", self.wall_time.as_secs_f64());
+        let _ = writeln!(out, "  \"passes\": {},", self.passes);
+        let _ = writeln!(out, "  \"image_width\": {},", self.image_width);
+        let _ = writeln!(out, "  \"image_height\": {},", self.image_height);
+        let _ = writeln!(out, "  \"rays\": {},", self.total_rays());
+        let _ = writeln!(out, "  \"rays_per_second\": {},", self.rays_per_second());
+        let _ = writeln!(out, "  \"primary_rays\": {},", self.primary_rays());
+        let _ = writeln!(out, "  \"secondary_rays\": {},", self.secondary_rays());
+        let _ = writeln!(out, "  \"shadow_rays\": {},", self.shadow_rays());
+        let _ = writeln!(out, "  \"average_path_length\": {},", self.average_path_length());
+        let _ = writeln!(out, "  \"bvh_node_tests_per_ray\": {},", self.bvh_node_tests_per_ray());
+        let _ = writeln!(out, "  \"primitive_tests_per_ray\": {},", self.primitive_tests_per_ray());
+        let _ = writeln!(out, "  \"russian_roulette_terminations\": {},", self.russian_roulette_terminations());
+        let histogram: Vec<String> = self.path_depth_histogram().iter().map(|n| n.to_string()).collect();
+        let _ = writeln!(out, "  \"path_depth_histogram\": [{}],", histogram.join(", "));
+        let tiles: Vec<String> = self
+            .tile_timings
+            .iter()
+            .map(|t| format!("{{\"x\": {}, \"y\": {}, \"seconds\": {}}}", t.tile.x0, t.tile.y0, t.time.as_secs_f64()))
+            .collect();
+        let _ = writeln!(out, "  \"tiles\": [{}]", tiles.join(", "));
+        let _ = writeln!(out, "}}");
+        out
+    }
+}
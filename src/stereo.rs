@@ -0,0 +1,103 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+impl Eye {
+    // Which way along the camera's right vector the eye sits
+    pub fn sign(&self) -> f64 {
+        match self {
+            Eye::Left => -1.0,
+            Eye::Right => 1.0,
+        }
+    }
+}
+
+// How the two eye images share the output image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StereoLayout {
+    // Left eye on the left, output twice as wide
+    #[default]
+    SideBySide,
+    // Left eye on top, output twice as tall
+    OverUnder,
+}
+
+// Two-eye rig around the camera position. Planar projections use parallel eyes with their
+// images shifted so objects at `convergence_distance` line up in both; the equirectangular
+// and other panoramic projections swing the eyes around the center with the view direction
+// instead, giving omni-directional stereo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stereo {
+    // In scene units
+    pub interpupillary_distance: f64,
+    // Infinity keeps the eyes parallel
+    pub convergence_distance: f64,
+    pub layout: StereoLayout,
+}
+
+impl Stereo {
+    pub fn new(interpupillary_distance: f64) -> Self {
+        Self { interpupillary_distance, convergence_distance: f64::INFINITY, layout: StereoLayout::SideBySide }
+    }
+
+    pub fn with_convergence(mut self, convergence_distance: f64) -> Self {
+        self.convergence_distance = convergence_distance;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: StereoLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    // Output image size for eye images of `eye_size`
+    pub fn image_size(&self, eye_size: (usize, usize)) -> (usize, usize) {
+        match self.layout {
+            StereoLayout::SideBySide => (eye_size.0 * 2, eye_size.1),
+            StereoLayout::OverUnder => (eye_size.0, eye_size.1 * 2),
+        }
+    }
+
+    // The eye an output pixel belongs to and its position within that eye's image
+    pub fn eye_pixel(&self, i: i32, j: i32, eye_size: (usize, usize)) -> (Eye, i32, i32) {
+        let (width, height) = (eye_size.0 as i32, eye_size.1 as i32);
+        match self.layout {
+            StereoLayout::SideBySide if i >= width => (Eye::Right, i - width, j),
+            StereoLayout::OverUnder if j >= height => (Eye::Right, i, j - height),
+            _ => (Eye::Left, i, j),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_eye_pixel_appears_once() {
+        let eye_size = (5, 3);
+        for layout in [StereoLayout::SideBySide, StereoLayout::OverUnder] {
+            let stereo = Stereo::new(0.065).with_layout(layout);
+            let (width, height) = stereo.image_size(eye_size);
+            assert_eq!(width * height, 2 * 5 * 3);
+            let mut seen = Vec::new();
+            for j in 0..height as i32 {
+                for i in 0..width as i32 {
+                    let (eye, x, y) = stereo.eye_pixel(i, j, eye_size);
+                    assert!((0..5).contains(&x) && (0..3).contains(&y));
+                    seen.push((eye == Eye::Right, x, y));
+                }
+            }
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen.len(), 2 * 5 * 3);
+        }
+        // The left eye comes first either way
+        assert_eq!(Stereo::new(0.065).eye_pixel(4, 2, eye_size), (Eye::Left, 4, 2));
+        assert_eq!(Stereo::new(0.065).eye_pixel(5, 2, eye_size), (Eye::Right, 0, 2));
+        let over_under = Stereo::new(0.065).with_layout(StereoLayout::OverUnder);
+        assert_eq!(over_under.eye_pixel(4, 3, eye_size), (Eye::Right, 4, 0));
+    }
+}
@@ -0,0 +1,114 @@
+use std::io;
+use std::path::Path;
+
+use crate::color::{convert, Color, ColorSpace, Transfer, WorkingColor};
+use crate::framebuffer::read_ppm;
+use crate::vec3::Point3;
+
+// Color that varies over a surface. Values come back in `working_space`, the space the
+// render shades in.
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f64, v: f64, p: Point3, working_space: ColorSpace) -> Color;
+}
+
+pub struct SolidColor {
+    color: WorkingColor,
+}
+
+impl SolidColor {
+    // A linear sRGB color, like every plain `Color` in a scene
+    pub fn new(color: Color) -> Self {
+        Self::in_space(color, ColorSpace::LinearSrgb)
+    }
+
+    pub fn in_space(color: Color, space: ColorSpace) -> Self {
+        Self { color: WorkingColor::new(color, space) }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f64, _v: f64, _p: Point3, working_space: ColorSpace) -> Color {
+        self.color.get(working_space)
+    }
+}
+
+// Image wrapped over the surface's (u, v), nearest texel. Texels are kept linear in the
+// space the image was tagged with.
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    // Row by row from the top
+    texels: Vec<Color>,
+    space: ColorSpace,
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, texels: Vec<Color>, space: ColorSpace) -> Self {
+        assert!(width > 0 && height > 0, "image texture has no texels");
+        assert_eq!(texels.len(), width * height, "image texture size doesn't match its texels");
+        Self { width, height, texels, space }
+    }
+
+    // A PPM whose values are encoded with `transfer` in `space`. Ordinary 8-bit images are
+    // sRGB primaries with the sRGB curve.
+    pub fn load(path: &Path, space: ColorSpace, transfer: Transfer) -> io::Result<Self> {
+        let (width, height, pixels) = read_ppm(path)?;
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: empty image", path.display())));
+        }
+        let texels = pixels.into_iter().map(|c| transfer.decode_color(c)).collect();
+        Ok(Self::new(width, height, texels, space))
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: Point3, working_space: ColorSpace) -> Color {
+        // v = 0 is the bottom of the image
+        let i = ((u.clamp(0.0, 1.0) * self.width as f64) as usize).min(self.width - 1);
+        let j = (((1.0 - v.clamp(0.0, 1.0)) * self.height as f64) as usize).min(self.height - 1);
+        convert(self.texels[j * self.width + i], self.space, working_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checker() -> ImageTexture {
+        let (black, white) = (Color::new(0.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0));
+        ImageTexture::new(2, 2, vec![white, black, black, white], ColorSpace::LinearSrgb)
+    }
+
+    #[test]
+    fn image_lookups_stay_inside_the_image() {
+        let texture = checker();
+        let p = Point3::new(0.0, 0.0, 0.0);
+        // Top left texel is v = 1, and out of range coordinates clamp to the edge texels
+        assert_eq!(texture.value(0.25, 0.75, p, ColorSpace::LinearSrgb).x(), 1.0);
+        assert_eq!(texture.value(0.75, 0.75, p, ColorSpace::LinearSrgb).x(), 0.0);
+        assert_eq!(texture.value(1.0, 0.0, p, ColorSpace::LinearSrgb).x(), 1.0);
+        assert_eq!(texture.value(-3.0, 5.0, p, ColorSpace::LinearSrgb).x(), 1.0);
+        assert_eq!(texture.value(2.0, 5.0, p, ColorSpace::LinearSrgb).x(), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't match")]
+    fn image_texture_rejects_mismatched_texels() {
+        ImageTexture::new(2, 3, vec![Color::new(1.0, 1.0, 1.0); 4], ColorSpace::LinearSrgb);
+    }
+
+    #[test]
+    #[should_panic(expected = "no texels")]
+    fn image_texture_rejects_empty_images() {
+        ImageTexture::new(0, 4, Vec::new(), ColorSpace::LinearSrgb);
+    }
+
+    #[test]
+    fn loading_an_empty_image_is_an_error() {
+        let path = std::env::temp_dir().join(format!("raytracing-empty-{}.ppm", std::process::id()));
+        std::fs::write(&path, "P3\n0 0\n255\n").unwrap();
+        let loaded = ImageTexture::load(&path, ColorSpace::LinearSrgb, Transfer::Srgb);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,123 @@
+use std::f64::consts::PI;
+use std::time::Duration;
+
+// Half-open pixel rectangle [x0, x1) x [y0, y1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    pub fn pixel_count(&self) -> usize {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+}
+
+// Part of the frame to render. Rays keep the full frame's framing, only the pixels outside
+// the window are skipped and left out of the written image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropWindow {
+    // Half-open pixel rectangle [x0, x1) x [y0, y1)
+    Pixels { x0: usize, y0: usize, x1: usize, y1: usize },
+    // Fractions of the image width and height from the top left corner, widened to whole
+    // pixels
+    Normalized { x0: f64, y0: f64, x1: f64, y1: f64 },
+}
+
+impl CropWindow {
+    // The window's pixels in a `width` x `height` image, clamped to it and never empty
+    pub fn to_tile(&self, width: usize, height: usize) -> Tile {
+        let (x0, y0, x1, y1) = match *self {
+            CropWindow::Pixels { x0, y0, x1, y1 } => (x0, y0, x1, y1),
+            CropWindow::Normalized { x0, y0, x1, y1 } => {
+                let floor = |t: f64, size: usize| (t.clamp(0.0, 1.0) * size as f64).floor() as usize;
+                let ceil = |t: f64, size: usize| (t.clamp(0.0, 1.0) * size as f64).ceil() as usize;
+                (floor(x0, width), floor(y0, height), ceil(x1, width), ceil(y1, height))
+            }
+        };
+        let x0 = x0.min(width.saturating_sub(1));
+        let y0 = y0.min(height.saturating_sub(1));
+        Tile { x0, y0, x1: x1.clamp(x0 + 1, width.max(1)), y1: y1.clamp(y0 + 1, height.max(1)) }
+    }
+}
+
+// Order in which tiles are handed out to the render threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileOrder {
+    Scanline,
+    Hilbert,
+    // Outward from the center of the image, so the subject resolves first
+    Spiral,
+}
+
+// Wall time spent on one tile, summed over every pass
+#[derive(Debug, Clone, Copy)]
+pub struct TileTiming {
+    pub tile: Tile,
+    pub time: Duration,
+}
+
+pub fn generate_tiles(width: usize, height: usize, tile_size: usize, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let tiles_x = width.div_ceil(tile_size);
+    let tiles_y = height.div_ceil(tile_size);
+
+    let mut coords: Vec<(usize, usize)> = (0..tiles_y)
+        .flat_map(|ty| (0..tiles_x).map(move |tx| (tx, ty)))
+        .collect();
+
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Hilbert => {
+            let n = tiles_x.max(tiles_y).next_power_of_two();
+            coords.sort_by_key(|&(tx, ty)| hilbert_index(n, tx, ty));
+        }
+        TileOrder::Spiral => {
+            let cx = (tiles_x as f64 - 1.0) / 2.0;
+            let cy = (tiles_y as f64 - 1.0) / 2.0;
+            let key = |&(tx, ty): &(usize, usize)| {
+                let dx = tx as f64 - cx;
+                let dy = ty as f64 - cy;
+                // Ring first, then clockwise angle within the ring
+                let ring = dx.abs().max(dy.abs()).round();
+                let angle = dy.atan2(dx).rem_euclid(2.0 * PI);
+                (ring, angle)
+            };
+            coords.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        }
+    }
+
+    coords
+        .into_iter()
+        .map(|(tx, ty)| Tile {
+            x0: tx * tile_size,
+            y0: ty * tile_size,
+            x1: ((tx + 1) * tile_size).min(width),
+            y1: ((ty + 1) * tile_size).min(height),
+        })
+        .collect()
+}
+
+// Distance of (x, y) along the Hilbert curve filling an n x n grid, n a power of two
+fn hilbert_index(n: usize, mut x: usize, mut y: usize) -> usize {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = usize::from(x & s > 0);
+        let ry = usize::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve stays continuous
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
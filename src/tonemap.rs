@@ -0,0 +1,289 @@
+use crate::color::{bradford_adaptation, convert, luminance, mat3_mul, mat3_product, xy_to_xyz, Color, ColorSpace, Mat3, Transfer};
+
+// Curves that squeeze scene radiance into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ToneMap {
+    // Leave values alone, anything above 1 clips to white
+    #[default]
+    Clamp,
+    // L / (1 + L) on luminance, keeping hue and saturation
+    Reinhard,
+    // Reinhard with luminance `white` and above mapping to 1
+    ExtendedReinhard { white: f64 },
+    // John Hable's filmic curve from Uncharted 2
+    Hable,
+    // Stephen Hill's fit of the ACES reference and sRGB output transforms
+    Aces,
+    // Troy Sobotka's AgX base look, which desaturates towards white instead of skewing hue
+    AgX,
+}
+
+impl ToneMap {
+    pub fn apply(&self, c: Color) -> Color {
+        match *self {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+            ToneMap::ExtendedReinhard { white } => {
+                scale_luminance(c, |l| l * (1.0 + l / (white * white)) / (1.0 + l))
+            }
+            ToneMap::Hable => {
+                let white_scale = 1.0 / hable_partial(HABLE_WHITE);
+                map_channels(c * HABLE_EXPOSURE_BIAS, |x| hable_partial(x) * white_scale)
+            }
+            ToneMap::Aces => aces(c),
+            ToneMap::AgX => agx(c),
+        }
+    }
+}
+
+fn map_channels(c: Color, f: impl Fn(f64) -> f64) -> Color {
+    Color::new(f(c.x()), f(c.y()), f(c.z()))
+}
+
+fn scale_luminance(c: Color, curve: impl Fn(f64) -> f64) -> Color {
+    let l = luminance(c);
+    if l <= 0.0 {
+        return c;
+    }
+    c * (curve(l) / l)
+}
+
+const HABLE_EXPOSURE_BIAS: f64 = 2.0;
+const HABLE_WHITE: f64 = 11.2;
+
+fn hable_partial(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+// sRGB -> ACES AP1 with the reference transform's D65 -> D60 adaptation and glow folded in
+const ACES_INPUT: Mat3 = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+
+const ACES_OUTPUT: Mat3 = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+fn aces(c: Color) -> Color {
+    let v = mat3_mul(&ACES_INPUT, c);
+    let v = map_channels(v, |x| {
+        let a = x * (x + 0.0245786) - 0.000090537;
+        let b = x * (0.983729 * x + 0.4329510) + 0.238081;
+        a / b
+    });
+    map_channels(mat3_mul(&ACES_OUTPUT, v), |x| x.clamp(0.0, 1.0))
+}
+
+// Pulls the primaries in towards white so bright saturated colors bleach instead of clipping
+const AGX_INSET: Mat3 = [
+    [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+    [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+    [0.0423756549057051, 0.0784336, 0.879142973793104],
+];
+
+const AGX_OUTSET: Mat3 = [
+    [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+    [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+    [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+];
+
+// Stops below and above middle grey that the log encoding covers
+const AGX_MIN_EV: f64 = -12.47393;
+const AGX_MAX_EV: f64 = 4.026069;
+
+fn agx(c: Color) -> Color {
+    let v = mat3_mul(&AGX_INSET, c);
+    let v = map_channels(v, |x| {
+        let encoded = (x.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV) - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV);
+        agx_contrast(encoded)
+    });
+    // The curve's output is display encoded, undo the 2.2 gamma so it goes out like everything else
+    map_channels(mat3_mul(&AGX_OUTSET, v), |x| x.clamp(0.0, 1.0).powf(2.2))
+}
+
+// Polynomial fit of the AgX sigmoid
+fn agx_contrast(x: f64) -> f64 {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+}
+
+// Temperature that white balance treats as neutral, sRGB's D65 white sits next to it
+const NEUTRAL_TEMPERATURE: f64 = 6504.0;
+
+// Makes light of the given color temperature come out neutral, as a camera's white balance
+// does. `tint` moves that light off the Planckian locus by a distance in CIE 1960 uv, with
+// positive values taking out a green cast and negative ones a magenta cast.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WhiteBalance {
+    pub temperature: f64,
+    pub tint: f64,
+    // Linear sRGB to linear sRGB, taking the light's white to the neutral one
+    matrix: Mat3,
+}
+
+impl WhiteBalance {
+    pub fn new(temperature: f64, tint: f64) -> Self {
+        let source = xy_to_xyz(white_point(temperature, tint));
+        let target = xy_to_xyz(white_point(NEUTRAL_TEMPERATURE, 0.0));
+        let srgb = ColorSpace::LinearSrgb;
+        let adapted = mat3_product(&bradford_adaptation(source, target), &srgb.to_xyz());
+        Self { temperature, tint, matrix: mat3_product(&srgb.from_xyz(), &adapted) }
+    }
+
+    pub fn apply(&self, c: Color) -> Color {
+        mat3_mul(&self.matrix, c)
+    }
+}
+
+// Chromaticity of a blackbody at `temperature` kelvin (Kim et al. cubic fit, good for
+// 1667 K to 25000 K), shifted perpendicular to the locus by `tint`
+fn white_point(temperature: f64, tint: f64) -> (f64, f64) {
+    let (u, v) = xy_to_uv(planckian_xy(temperature));
+    if tint == 0.0 {
+        return uv_to_xy((u, v));
+    }
+    let (u2, v2) = xy_to_uv(planckian_xy(temperature * 1.01));
+    let (du, dv) = (u2 - u, v2 - v);
+    let length = (du * du + dv * dv).sqrt();
+    // The locus runs towards -u with rising temperature, so this normal points up into green
+    let (nu, nv) = (dv / length, -du / length);
+    uv_to_xy((u + nu * tint, v + nv * tint))
+}
+
+fn planckian_xy(temperature: f64) -> (f64, f64) {
+    let t = temperature.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t < 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t < 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t < 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+    (x, y)
+}
+
+fn xy_to_uv((x, y): (f64, f64)) -> (f64, f64) {
+    let d = -2.0 * x + 12.0 * y + 3.0;
+    (4.0 * x / d, 6.0 * y / d)
+}
+
+fn uv_to_xy((u, v): (f64, f64)) -> (f64, f64) {
+    let d = 2.0 * u - 8.0 * v + 4.0;
+    (3.0 * u / d, 2.0 * v / d)
+}
+
+// Everything that happens between the linear framebuffer and the display encoding:
+// exposure, then white balance and the tone curve in linear sRGB, then conversion to the
+// display's primaries and its transfer curve. The framebuffer itself is left as is.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DisplayTransform {
+    // Space the framebuffer's values are in
+    pub working_space: ColorSpace,
+    // In stops, each one doubles the brightness
+    pub exposure: f64,
+    pub white_balance: Option<WhiteBalance>,
+    pub tone_map: ToneMap,
+    pub display_space: ColorSpace,
+    pub transfer: Transfer,
+}
+
+impl DisplayTransform {
+    // From the working space to encoded display values, not yet clamped
+    pub fn apply(&self, c: Color) -> Color {
+        let mut c = convert(c * self.exposure.exp2(), self.working_space, ColorSpace::LinearSrgb);
+        if let Some(white_balance) = &self.white_balance {
+            c = white_balance.apply(c);
+        }
+        let c = convert(self.tone_map.apply(c), ColorSpace::LinearSrgb, self.display_space);
+        self.transfer.encode_color(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [ToneMap; 6] = [
+        ToneMap::Clamp,
+        ToneMap::Reinhard,
+        ToneMap::ExtendedReinhard { white: 4.0 },
+        ToneMap::Hable,
+        ToneMap::Aces,
+        ToneMap::AgX,
+    ];
+
+    fn grey(l: f64) -> Color {
+        Color::new(l, l, l)
+    }
+
+    #[test]
+    fn curves_keep_black_and_rise_monotonically() {
+        for curve in CURVES {
+            assert!(luminance(curve.apply(grey(0.0))).abs() < 1e-3, "{:?}", curve);
+            let mut previous = 0.0;
+            for k in 1..=400 {
+                let l = luminance(curve.apply(grey(k as f64 * 0.05)));
+                assert!(l >= previous - 1e-12, "{:?} falls at {}", curve, k as f64 * 0.05);
+                previous = l;
+            }
+            // Everything but plain clamping compresses the highlights
+            if curve != ToneMap::Clamp {
+                assert!(luminance(curve.apply(grey(2.0))) < 1.0, "{:?}", curve);
+            }
+        }
+    }
+
+    #[test]
+    fn curves_reach_white_where_they_say() {
+        let extended = ToneMap::ExtendedReinhard { white: 4.0 }.apply(grey(4.0));
+        assert!((luminance(extended) - 1.0).abs() < 1e-12);
+        let hable = ToneMap::Hable.apply(grey(HABLE_WHITE / HABLE_EXPOSURE_BIAS));
+        assert!((luminance(hable) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn reinhard_keeps_the_hue() {
+        let c = Color::new(3.0, 1.5, 0.5);
+        let mapped = ToneMap::Reinhard.apply(c);
+        assert!((mapped.x() / mapped.y() - 2.0).abs() < 1e-12);
+        assert!((mapped.y() / mapped.z() - 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn white_balance_neutralizes_its_light() {
+        let neutral = WhiteBalance::new(NEUTRAL_TEMPERATURE, 0.0).apply(Color::new(0.2, 0.5, 0.7));
+        assert!((neutral - Color::new(0.2, 0.5, 0.7)).length() < 1e-9);
+
+        // A blackbody's own color comes out as the neutral white, which sits right next to D65
+        let srgb = ColorSpace::LinearSrgb;
+        let light = mat3_mul(&srgb.from_xyz(), xy_to_xyz(white_point(3000.0, 0.0)));
+        assert!(light.x() > 1.5 * light.z());
+        let balanced = WhiteBalance::new(3000.0, 0.0).apply(light);
+        let neutral_white = mat3_mul(&srgb.from_xyz(), xy_to_xyz(white_point(NEUTRAL_TEMPERATURE, 0.0)));
+        assert!((balanced - neutral_white).length() < 1e-9, "{:?}", balanced);
+        assert!((neutral_white - grey(1.0)).length() < 0.1);
+
+        // Positive tint balances for a greenish light, so grey turns magenta
+        let tinted = WhiteBalance::new(NEUTRAL_TEMPERATURE, 0.01).apply(grey(0.5));
+        assert!(tinted.y() < tinted.x() && tinted.y() < tinted.z(), "{:?}", tinted);
+    }
+
+    #[test]
+    fn exposure_is_in_stops() {
+        let transform = DisplayTransform { exposure: 1.0, transfer: Transfer::Linear, ..Default::default() };
+        assert!((transform.apply(Color::new(0.1, 0.2, 0.3)) - Color::new(0.2, 0.4, 0.6)).length() < 1e-12);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
+use crate::hittable::{HitRecord, Hittable};
+use crate::interval::Interval;
+use crate::material::MaterialIds;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec3::{dot, unit_vector, Point3, Vec3};
+
+// Places an object in the world: uniform scale, then rotation, then translation. Rays are
+// taken into object space instead of moving the object, so one object can be placed many
+// times, and since the scale is uniform angles and solid angles stay the same.
+pub struct Transform {
+    object: Arc<dyn Hittable>,
+    translation: Vec3,
+    // Object to world rotation, rows of the matrix
+    rotation: [Vec3; 3],
+    scale: f64,
+    bbox: Option<Aabb>,
+}
+
+impl Transform {
+    // `rotation` holds angles in degrees about x, then y, then z
+    pub fn new(object: Arc<dyn Hittable>, translation: Vec3, rotation: Vec3, scale: f64) -> Self {
+        let rotation = rotation_matrix(rotation);
+        let mut transform = Self { object, translation, rotation, scale, bbox: None };
+        transform.bbox = transform.object.bounding_box().map(|bbox| {
+            let corners = bbox.corners().map(|corner| transform.to_world(corner));
+            corners[1..].iter().fold(Aabb::from_points(corners[0], corners[0]), |bbox, &corner| {
+                Aabb::enclosing(&bbox, &Aabb::from_points(corner, corner))
+            })
+        });
+        transform
+    }
+
+    fn rotate(&self, v: Vec3) -> Vec3 {
+        Vec3::new(dot(self.rotation[0], v), dot(self.rotation[1], v), dot(self.rotation[2], v))
+    }
+
+    // The transposed matrix undoes the rotation
+    fn unrotate(&self, v: Vec3) -> Vec3 {
+        self.rotation[0] * v.x() + self.rotation[1] * v.y() + self.rotation[2] * v.z()
+    }
+
+    fn to_world(&self, p: Point3) -> Point3 {
+        self.rotate(p * self.scale) + self.translation
+    }
+
+    fn to_object(&self, p: Point3) -> Point3 {
+        self.unrotate(p - self.translation) / self.scale
+    }
+
+    fn record_to_world(&self, mut rec: HitRecord) -> HitRecord {
+        rec.p = self.to_world(rec.p);
+        rec.normal = unit_vector(self.rotate(rec.normal));
+        rec
+    }
+}
+
+fn rotation_matrix(degrees: Vec3) -> [Vec3; 3] {
+    let (sx, cx) = degrees.x().to_radians().sin_cos();
+    let (sy, cy) = degrees.y().to_radians().sin_cos();
+    let (sz, cz) = degrees.z().to_radians().sin_cos();
+    // Rz * Ry * Rx
+    [
+        Vec3::new(cz * cy, cz * sy * sx - sz * cx, cz * sy * cx + sz * sx),
+        Vec3::new(sz * cy, sz * sy * sx + cz * cx, sz * sy * cx - cz * sx),
+        Vec3::new(-sy, cy * sx, cy * cx),
+    ]
+}
+
+impl Hittable for Transform {
+    // The object space ray keeps the same parameterization, so t carries over unchanged
+    fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let local = Ray::new(self.to_object(r.origin()), self.unrotate(r.direction()) / self.scale);
+        let rec = self.object.hit(&local, ray_t)?;
+        Some(self.record_to_world(rec))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bbox
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        self.object.pdf_value(self.to_object(origin), self.unrotate(direction))
+    }
+
+    fn random(&self, origin: Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.rotate(self.object.random(self.to_object(origin), sampler))
+    }
+
+    // Areas grow with the square of the scale
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let (rec, pdf) = self.object.sample_surface(sampler)?;
+        Some((self.record_to_world(rec), pdf / (self.scale * self.scale)))
+    }
+
+    fn surface_pdf(&self, p: Point3) -> f64 {
+        self.object.surface_pdf(self.to_object(p)) / (self.scale * self.scale)
+    }
+
+    fn object_names(&self, names: &mut HashMap<u32, String>) {
+        self.object.object_names(names)
+    }
+
+    fn scene_materials(&self, materials: &mut MaterialIds) {
+        self.object.scene_materials(materials)
+    }
+}
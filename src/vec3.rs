@@ -1,8 +1,6 @@
 use std::ops::{Add, Sub, Mul, Div, Neg, AddAssign};
 use rand::Rng;
-use rayon::vec;
 
-use crate::vec3;
 
 #[derive(Copy, Clone, Debug, PartialEq, Default)]
 pub struct Vec3 {
@@ -138,11 +136,33 @@ pub fn random_in_unit_disk(rng: &mut dyn rand::RngCore) -> Vec3 {
     }
 }
 
+// Concentric map from [0,1)^2 to the unit disk (Shirley & Chiu), keeps stratification intact
+pub fn sample_unit_disk(u: (f64, f64)) -> Vec3 {
+    let (ox, oy) = (2.0 * u.0 - 1.0, 2.0 * u.1 - 1.0);
+    if ox == 0.0 && oy == 0.0 {
+        return Vec3::new(0.0, 0.0, 0.0)
+    }
+    let (r, theta) = if ox.abs() > oy.abs() {
+        (ox, std::f64::consts::FRAC_PI_4 * (oy / ox))
+    } else {
+        (oy, std::f64::consts::FRAC_PI_2 - std::f64::consts::FRAC_PI_4 * (ox / oy))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+// Uniform direction on the unit sphere from a 2D sample
+pub fn sample_unit_sphere(u: (f64, f64)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
 pub fn random_unit_vector(rng: &mut dyn rand::RngCore) -> Vec3 {
     loop {
         let p: Vec3 = random_with_range(-1.0, 1.0, rng);
         let lensq: f64 = p.length_squared();
-        if (lensq <= 1.0 && lensq > 1e-160) {
+        if lensq <= 1.0 && lensq > 1e-160  {
             return p / lensq.sqrt()
         }
     }
@@ -150,10 +170,10 @@ pub fn random_unit_vector(rng: &mut dyn rand::RngCore) -> Vec3 {
 
 pub fn random_on_hemisphere(normal: Vec3, rng: &mut dyn rand::RngCore) -> Vec3 {
     let on_unit_sphere = random_unit_vector(rng);
-    if (dot(on_unit_sphere, normal) > 0.0) {
-        return on_unit_sphere
+    if dot(on_unit_sphere, normal) > 0.0  {
+        on_unit_sphere
     } else {
-        return -on_unit_sphere
+        -on_unit_sphere
     }
 }
 